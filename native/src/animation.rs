@@ -0,0 +1,448 @@
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, Frame, ImageDecoder, ImageFormat};
+use std::io::Cursor;
+use crate::encoders::encode_gif_frames;
+use crate::error::ConvertError;
+use crate::metadata::{embed_png, embed_webp, Metadata};
+use crate::options::{ConvertOptions, DecodeLimits};
+use crate::pages::count_tiff_pages;
+#[cfg(feature = "webp-lossy")]
+use crate::encoders::webp_config;
+
+// ----------------------------------------------------------------
+// Animated GIF / APNG / WebP
+// ----------------------------------------------------------------
+//
+// The `image` decoders hand out every frame already composited onto the full canvas,
+// so disposal and blending are baked into the pixels. Re-encoding therefore writes
+// full frames and only has to carry the delays and the loop count across.
+//
+// The containers count loops differently: GIF's NETSCAPE block stores the repeats after
+// the first play (and a missing block means "play once"), while APNG and WebP store the
+// total number of plays with 0 meaning forever. `LoopCount` holds total plays and is
+// converted at the edges.
+
+/// How often an animation plays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopCount {
+    Infinite,
+    /// Total number of plays, at least 1.
+    Finite(u16),
+}
+
+#[derive(Clone)]
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    pub loop_count: LoopCount,
+}
+
+/// Formats we can write animations to with the current feature set.
+pub fn supports_animation(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Gif | ImageFormat::Png)
+        || (format == ImageFormat::WebP && cfg!(feature = "webp-lossy"))
+}
+
+/// Decodes every frame of an animated input. Returns `None` for formats that cannot be
+/// animated and for files holding a single frame (counted from the headers, so nothing is
+/// decoded), so callers take the still image path. Fails with `LimitExceeded` before
+/// decoding if all frames together would break `limits`.
+pub fn decode_animation(
+    input_data: &[u8],
+    format: ImageFormat,
+    limits: &DecodeLimits,
+) -> Result<Option<Animation>, ConvertError> {
+    if !matches!(format, ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP) {
+        return Ok(None);
+    }
+    let frame_count = count_frames(input_data, format);
+    if frame_count < 2 {
+        return Ok(None);
+    }
+
+    let (frames, loop_count) = match format {
+        ImageFormat::Gif => {
+            let mut decoder =
+                GifDecoder::new(Cursor::new(input_data)).map_err(ConvertError::from_decode)?;
+            check_limits(&mut decoder, frame_count, limits)?;
+            let frames = decoder.into_frames().collect_frames();
+            (frames, gif_loop_count(input_data))
+        }
+        ImageFormat::Png => {
+            let mut decoder =
+                PngDecoder::new(Cursor::new(input_data)).map_err(ConvertError::from_decode)?;
+            if !decoder.is_apng().map_err(ConvertError::from_decode)? {
+                return Ok(None);
+            }
+            check_limits(&mut decoder, frame_count, limits)?;
+            let frames = decoder
+                .apng()
+                .map_err(ConvertError::from_decode)?
+                .into_frames()
+                .collect_frames();
+            (frames, apng_loop_count(input_data))
+        }
+        ImageFormat::WebP => {
+            let mut decoder =
+                WebPDecoder::new(Cursor::new(input_data)).map_err(ConvertError::from_decode)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            check_limits(&mut decoder, frame_count, limits)?;
+            let frames = decoder.into_frames().collect_frames();
+            (frames, webp_loop_count(input_data))
+        }
+        _ => return Ok(None),
+    };
+
+    let frames = frames.map_err(ConvertError::from_decode)?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(Animation { frames, loop_count }))
+}
+
+/// Hands `limits` to the decoder and checks that all `frames` frames, composited onto the
+/// full canvas as the decoders return them, fit at once.
+fn check_limits<D: ImageDecoder>(
+    decoder: &mut D,
+    frames: u32,
+    limits: &DecodeLimits,
+) -> Result<(), ConvertError> {
+    decoder.set_limits(limits.to_image_limits()).map_err(ConvertError::from_decode)?;
+    let (width, height) = decoder.dimensions();
+    let frame_bytes = u64::from(width) * u64::from(height) * 4;
+    limits.check((width, height), frame_bytes, frames).map_err(ConvertError::from_decode)
+}
+
+fn gif_loop_count(input_data: &[u8]) -> LoopCount {
+    match gif::DecodeOptions::new().read_info(Cursor::new(input_data)) {
+        Ok(decoder) => match decoder.repeat() {
+            gif::Repeat::Infinite => LoopCount::Infinite,
+            // Covers files without a NETSCAPE block, which the decoder reports as 0 repeats.
+            gif::Repeat::Finite(repeats) => LoopCount::Finite(repeats.saturating_add(1)),
+        },
+        Err(_) => LoopCount::Infinite,
+    }
+}
+
+fn apng_loop_count(input_data: &[u8]) -> LoopCount {
+    let plays = png::Decoder::new(Cursor::new(input_data))
+        .read_info()
+        .ok()
+        .and_then(|reader| reader.info().animation_control.map(|a| a.num_plays));
+    match plays {
+        None | Some(0) => LoopCount::Infinite,
+        Some(n) => LoopCount::Finite(u16::try_from(n).unwrap_or(u16::MAX)),
+    }
+}
+
+fn webp_loop_count(input_data: &[u8]) -> LoopCount {
+    match image_webp::WebPDecoder::new(Cursor::new(input_data)).map(|d| d.loop_count()) {
+        Ok(image_webp::LoopCount::Times(n)) => LoopCount::Finite(n.get()),
+        _ => LoopCount::Infinite,
+    }
+}
+
+/// Number of frames, read from the container without decoding any pixels.
+/// Formats that cannot be animated count as a single frame.
+pub fn count_frames(input_data: &[u8], format: ImageFormat) -> u32 {
+    let frames = match format {
+        ImageFormat::Gif => gif_frame_count(input_data),
+        ImageFormat::Png => png::Decoder::new(Cursor::new(input_data))
+            .read_info()
+            .ok()
+            .and_then(|reader| reader.info().animation_control.map(|a| a.num_frames))
+            .unwrap_or(1),
+        ImageFormat::WebP => image_webp::WebPDecoder::new(Cursor::new(input_data))
+            .map(|decoder| decoder.num_frames())
+            .unwrap_or(1),
+        // Pages of a multi-page TIFF count as frames.
+        ImageFormat::Tiff => count_tiff_pages(input_data),
+        _ => 1,
+    };
+    // Still WebP reports zero frames.
+    frames.max(1)
+}
+
+fn gif_frame_count(input_data: &[u8]) -> u32 {
+    let mut options = gif::DecodeOptions::new();
+    // Walk the frame headers and skip the LZW data.
+    options.skip_frame_decoding(true);
+    let Ok(mut decoder) = options.read_info(Cursor::new(input_data)) else {
+        return 1;
+    };
+    let mut frames = 0;
+    while let Ok(Some(_)) = decoder.read_next_frame() {
+        frames += 1;
+    }
+    frames
+}
+
+/// Frame delay in whole milliseconds.
+pub fn delay_ms(frame: &Frame) -> u32 {
+    let (numer, denom) = frame.delay().numer_denom_ms();
+    numer.checked_div(denom).unwrap_or(0)
+}
+
+/// Encodes an animation whose frames were already adjusted for `output_format`.
+/// Only valid for formats where `supports_animation` is true. GIF cannot hold `metadata`.
+pub fn encode_animation(
+    animation: Animation,
+    output_format: ImageFormat,
+    options: &ConvertOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = animation.frames[0].buffer().dimensions();
+    match output_format {
+        ImageFormat::Gif => encode_gif(animation, options),
+        ImageFormat::Png => encode_apng(animation, options).map(|png| embed_png(png, metadata)),
+        ImageFormat::WebP => encode_animated_webp(animation, options)
+            .map(|webp| embed_webp(webp, metadata, width, height, true)),
+        f => Err(ConvertError::UnsupportedTarget(format!("animated {:?}", f))),
+    }
+}
+
+fn encode_gif(animation: Animation, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let dimensions = animation.frames[0].buffer().dimensions();
+    let repeat = match animation.loop_count {
+        LoopCount::Infinite => gif::Repeat::Infinite,
+        // 0 repeats writes no NETSCAPE block at all.
+        LoopCount::Finite(plays) => gif::Repeat::Finite(plays.saturating_sub(1)),
+    };
+    let frames = animation.frames.iter().map(|frame| (frame.buffer(), delay_ms(frame)));
+    encode_gif_frames(frames, dimensions, Some(repeat), options)
+}
+
+fn encode_apng(animation: Animation, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let to_error = |e: png::EncodingError| ConvertError::encoder(ImageFormat::Png, e);
+
+    let (width, height) = animation.frames[0].buffer().dimensions();
+    let plays = match animation.loop_count {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(plays) => u32::from(plays.max(1)),
+    };
+
+    let mut buffer = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buffer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(animation.frames.len() as u32, plays)
+        .map_err(to_error)?;
+    if let Some(level) = options.png.compression_level {
+        encoder.set_deflate_compression(match level {
+            0 => png::DeflateCompression::NoCompression,
+            n => png::DeflateCompression::Level(n.min(9)),
+        });
+    }
+
+    let mut writer = encoder.write_header().map_err(to_error)?;
+    for frame in &animation.frames {
+        let delay = u16::try_from(delay_ms(frame)).unwrap_or(u16::MAX);
+        writer.set_frame_delay(delay, 1000).map_err(to_error)?;
+        writer.write_image_data(frame.buffer().as_raw()).map_err(to_error)?;
+    }
+    writer.finish().map_err(to_error)?;
+
+    Ok(buffer)
+}
+
+#[cfg(feature = "webp-lossy")]
+fn encode_animated_webp(
+    animation: Animation,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    let config = webp_config(options)?;
+
+    let (width, height) = animation.frames[0].buffer().dimensions();
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(match animation.loop_count {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(plays) => i32::from(plays.max(1)),
+    });
+
+    // libwebp wants the start time of each frame rather than its duration.
+    let mut timestamp = 0i32;
+    for frame in &animation.frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            frame.buffer().as_raw(),
+            width,
+            height,
+            timestamp,
+        ));
+        timestamp = timestamp.saturating_add(delay_ms(frame) as i32);
+    }
+
+    let memory = encoder
+        .try_encode()
+        .map_err(|e| ConvertError::encoder(ImageFormat::WebP, format!("{:?}", e)))?;
+
+    Ok(memory.to_vec())
+}
+
+#[cfg(not(feature = "webp-lossy"))]
+fn encode_animated_webp(
+    _animation: Animation,
+    _options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    Err(ConvertError::UnsupportedTarget(
+        "animated webp needs the `webp-lossy` feature".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn get_asset_path(filename: &str) -> PathBuf {
+        let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        PathBuf::from(manifest_dir).join("assets").join(filename)
+    }
+
+    fn decode_gif_asset() -> Animation {
+        let img_data = fs::read(get_asset_path("original.gif")).expect("Failed to read test image");
+        decode_with_defaults(&img_data, ImageFormat::Gif)
+            .expect("original.gif should be animated")
+    }
+
+    fn decode_with_defaults(input_data: &[u8], format: ImageFormat) -> Option<Animation> {
+        decode_animation(input_data, format, &DecodeLimits::default()).expect("Decoding failed")
+    }
+
+    fn encode_with_defaults(animation: Animation, format: ImageFormat) -> Vec<u8> {
+        encode_animation(animation, format, &ConvertOptions::default(), &Metadata::default())
+            .expect("Encoding failed")
+    }
+
+    #[test]
+    fn decode_animated_gif_keeps_every_frame() {
+        let animation = decode_gif_asset();
+        assert_eq!(animation.frames.len(), 50);
+        assert!(animation.frames.iter().all(|f| f.buffer().dimensions() == (220, 224)));
+    }
+
+    #[test]
+    fn still_png_is_not_an_animation() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let animation = decode_with_defaults(&img_data, ImageFormat::Png);
+        assert!(animation.is_none());
+    }
+
+    #[test]
+    fn single_frame_gif_is_not_an_animation() {
+        let animation = decode_gif_asset();
+        let frames = [(animation.frames[0].buffer(), 100)];
+        let dimensions = animation.frames[0].buffer().dimensions();
+        let gif = encode_gif_frames(frames, dimensions, None, &ConvertOptions::default()).unwrap();
+
+        // Counted from the headers, so even a limit the frame breaks is never reached.
+        let limits = DecodeLimits { max_memory_mb: Some(0), ..DecodeLimits::default() };
+        assert!(decode_animation(&gif, ImageFormat::Gif, &limits).unwrap().is_none());
+    }
+
+    #[test]
+    fn count_frames_reads_headers_only() {
+        let img_data = fs::read(get_asset_path("original.gif")).expect("Failed to read test image");
+        assert_eq!(count_frames(&img_data, ImageFormat::Gif), 50);
+
+        let apng = encode_with_defaults(decode_gif_asset(), ImageFormat::Png);
+        assert_eq!(count_frames(&apng, ImageFormat::Png), 50);
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        assert_eq!(count_frames(&img_data, ImageFormat::Png), 1);
+    }
+
+    #[test]
+    fn gif_to_apng_round_trip() {
+        let animation = decode_gif_asset();
+        let delays: Vec<u32> = animation.frames.iter().map(delay_ms).collect();
+        let loop_count = animation.loop_count;
+
+        let apng = encode_with_defaults(animation, ImageFormat::Png);
+        let reloaded = decode_with_defaults(&apng, ImageFormat::Png)
+            .expect("APNG output should be animated");
+
+        assert_eq!(reloaded.frames.iter().map(delay_ms).collect::<Vec<_>>(), delays);
+        assert_eq!(reloaded.loop_count, loop_count);
+    }
+
+    #[test]
+    fn gif_round_trip_keeps_finite_loop_count() {
+        let mut animation = decode_gif_asset();
+        animation.frames.truncate(3);
+        animation.loop_count = LoopCount::Finite(2);
+
+        let gif = encode_with_defaults(animation, ImageFormat::Gif);
+        let reloaded = decode_with_defaults(&gif, ImageFormat::Gif)
+            .expect("GIF output should be animated");
+
+        assert_eq!(reloaded.frames.len(), 3);
+        assert_eq!(reloaded.loop_count, LoopCount::Finite(2));
+    }
+
+    /// The loop count as stored in the file: GIF repeats and APNG plays.
+    fn stored_gif_repeat(gif: &[u8]) -> gif::Repeat {
+        gif::DecodeOptions::new().read_info(Cursor::new(gif)).unwrap().repeat()
+    }
+
+    fn stored_apng_plays(apng: &[u8]) -> u32 {
+        let reader = png::Decoder::new(Cursor::new(apng)).read_info().unwrap();
+        reader.info().animation_control.unwrap().num_plays
+    }
+
+    #[test]
+    fn gif_without_netscape_block_plays_once() {
+        let animation = decode_gif_asset();
+        let frames = animation.frames.iter().take(3).map(|frame| (frame.buffer(), 100));
+        let dimensions = animation.frames[0].buffer().dimensions();
+        let gif = encode_gif_frames(frames, dimensions, None, &ConvertOptions::default()).unwrap();
+
+        let animation = decode_with_defaults(&gif, ImageFormat::Gif).unwrap();
+        assert_eq!(animation.loop_count, LoopCount::Finite(1));
+
+        // Must not turn into an endless APNG (0 plays).
+        let apng = encode_with_defaults(animation, ImageFormat::Png);
+        assert_eq!(stored_apng_plays(&apng), 1);
+    }
+
+    #[test]
+    fn loop_counts_survive_gif_apng_gif() {
+        for (repeat, plays) in [
+            (gif::Repeat::Finite(0), 1),
+            (gif::Repeat::Finite(2), 3),
+            (gif::Repeat::Infinite, 0),
+        ] {
+            let animation = decode_gif_asset();
+            let frames = animation.frames.iter().take(3).map(|frame| (frame.buffer(), 100));
+            let dimensions = animation.frames[0].buffer().dimensions();
+            let options = ConvertOptions::default();
+            let gif = encode_gif_frames(frames, dimensions, Some(repeat), &options).unwrap();
+
+            let animation = decode_with_defaults(&gif, ImageFormat::Gif).unwrap();
+            let apng = encode_with_defaults(animation, ImageFormat::Png);
+            assert_eq!(stored_apng_plays(&apng), plays, "{:?}", repeat);
+
+            let animation = decode_with_defaults(&apng, ImageFormat::Png).unwrap();
+            let gif = encode_with_defaults(animation, ImageFormat::Gif);
+            assert_eq!(stored_gif_repeat(&gif), repeat);
+        }
+    }
+
+    #[cfg(feature = "webp-lossy")]
+    #[test]
+    fn gif_to_animated_webp_keeps_timing() {
+        let animation = decode_gif_asset();
+        let total: u32 = animation.frames.iter().map(delay_ms).sum();
+        let webp = encode_with_defaults(animation, ImageFormat::WebP);
+        let reloaded = decode_with_defaults(&webp, ImageFormat::WebP)
+            .expect("WebP output should be animated");
+
+        // libwebp merges identical consecutive frames, so compare the running time.
+        let reloaded_total: u32 = reloaded.frames.iter().map(delay_ms).sum();
+        assert_eq!(reloaded_total, total);
+    }
+}
@@ -0,0 +1,153 @@
+use image::{imageops, DynamicImage, ImageBuffer, Pixel, Rgba};
+use crate::error::ConvertError;
+use crate::linear;
+
+// ----------------------------------------------------------------
+// Alpha flattening (matte colour or preview checkerboard)
+// ----------------------------------------------------------------
+
+/// Light and dark squares of the preview checkerboard, as image editors draw them.
+const CHECKER_LIGHT: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const CHECKER_DARK: Rgba<u8> = Rgba([204, 204, 204, 255]);
+
+/// What transparent pixels are composited onto when the alpha channel has to go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Background {
+    /// A single opaque colour.
+    Color(Rgba<u8>),
+    /// Alternating light and dark squares of `size` pixels, to show where the image was
+    /// transparent. Meant for previews.
+    Checkerboard { size: u32 },
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color(Rgba([255, 255, 255, 255]))
+    }
+}
+
+impl Background {
+    /// Parses "#rrggbb", "#rgb" (the `#` is optional), "black" or "white".
+    pub fn parse_color(value: &str) -> Result<Background, ConvertError> {
+        let value = value.trim();
+        match value.to_lowercase().as_str() {
+            "black" => return Ok(Background::Color(Rgba([0, 0, 0, 255]))),
+            "white" => return Ok(Background::default()),
+            _ => {}
+        }
+
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        let digits = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<_>>>();
+        let rgb = match digits.as_deref() {
+            Some(&[r, g, b]) => [r * 17, g * 17, b * 17],
+            Some(&[r1, r2, g1, g2, b1, b2]) => [r1 * 16 + r2, g1 * 16 + g2, b1 * 16 + b2],
+            _ => {
+                return Err(ConvertError::InvalidOption(format!(
+                    "background colour {:?} is not \"#rrggbb\", \"#rgb\", \"black\" or \"white\"",
+                    value
+                )));
+            }
+        };
+        Ok(Background::Color(Rgba([rgb[0], rgb[1], rgb[2], 255])))
+    }
+
+    /// The matte pixel at (`x`, `y`).
+    fn pixel_at(&self, x: u32, y: u32) -> Rgba<u8> {
+        match *self {
+            Background::Color(color) => color,
+            Background::Checkerboard { size } => {
+                let size = size.max(1);
+                if (x / size + y / size).is_multiple_of(2) { CHECKER_LIGHT } else { CHECKER_DARK }
+            }
+        }
+    }
+}
+
+/// Composites `img` onto `background` and drops the alpha channel, blending in linear light
+/// when `linear_light` is set. 8-bit images come back as RGB8, deeper ones as RGB16.
+/// Images without alpha are returned untouched.
+pub fn flatten(img: DynamicImage, background: &Background, linear_light: bool) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+
+    let color = img.color();
+    let eight_bit = color.bytes_per_pixel() / color.channel_count() == 1;
+    if linear_light {
+        let flat = linear::composite(&img, |x, y| background.pixel_at(x, y));
+        return if eight_bit {
+            DynamicImage::ImageRgb8(flat.into_rgb8())
+        } else {
+            DynamicImage::ImageRgb16(flat.into_rgb16())
+        };
+    }
+
+    if eight_bit {
+        let flat = composite(&img.to_rgba8(), |x, y| background.pixel_at(x, y));
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(flat).into_rgb8())
+    } else {
+        let flat = composite(&img.to_rgba16(), |x, y| {
+            Rgba(background.pixel_at(x, y).0.map(|c| u16::from(c) * 257))
+        });
+        DynamicImage::ImageRgb16(DynamicImage::ImageRgba16(flat).into_rgb16())
+    }
+}
+
+fn composite<P: Pixel>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    matte: impl Fn(u32, u32) -> P,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let mut canvas = ImageBuffer::from_fn(img.width(), img.height(), matte);
+    imageops::overlay(&mut canvas, img, 0, 0);
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    #[test]
+    fn parses_hex_and_names() {
+        let color = |value| match Background::parse_color(value) {
+            Ok(Background::Color(color)) => color.0,
+            other => panic!("{:?} parsed as {:?}", value, other),
+        };
+        assert_eq!(color("#ff8000"), [255, 128, 0, 255]);
+        assert_eq!(color("FF8000"), [255, 128, 0, 255]);
+        assert_eq!(color("#f80"), [255, 136, 0, 255]);
+        assert_eq!(color(" Black "), [0, 0, 0, 255]);
+        assert_eq!(color("white"), [255, 255, 255, 255]);
+
+        for bad in ["", "#12345", "#gggggg", "red"] {
+            let result = Background::parse_color(bad);
+            assert!(matches!(result, Err(ConvertError::InvalidOption(_))), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn flatten_blends_onto_the_colour_and_drops_alpha() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([0, 0, 0, 0]) } else { Rgba([255, 255, 255, 128]) }
+        }));
+
+        let flat = flatten(img, &Background::Color(Rgba([0, 0, 255, 255])), false);
+        let DynamicImage::ImageRgb8(flat) = flat else { panic!("expected RGB8") };
+        assert_eq!(flat.get_pixel(0, 0).0, [0, 0, 255]);
+        let half = flat.get_pixel(1, 0).0;
+        assert!((126..=130).contains(&half[0]) && half[2] == 255, "got {:?}", half);
+    }
+
+    #[test]
+    fn flatten_checkerboard_and_16_bit() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::new(4, 4));
+        let flat = flatten(img, &Background::Checkerboard { size: 2 }, false);
+        let DynamicImage::ImageRgb16(flat) = flat else { panic!("expected RGB16") };
+        assert_eq!(flat.get_pixel(0, 0).0, [65535; 3]);
+        assert_eq!(flat.get_pixel(2, 0).0, [204 * 257; 3]);
+        assert_eq!(flat.get_pixel(2, 2).0, [65535; 3]);
+    }
+}
@@ -0,0 +1,224 @@
+use image::{DynamicImage, ImageFormat};
+use crate::error::ConvertError;
+use crate::options::{BmpFormat, BmpOptions};
+use crate::quantize::quantize;
+
+// ----------------------------------------------------------------
+// BMP variants (paletted, RGB565, top-down)
+// ----------------------------------------------------------------
+//
+// The `image` BMP encoder only writes 24/32-bit or 8-bit gray bottom-up files. Small
+// embedded displays often want something else, so those layouts are written here.
+// Paletted and 24-bit files use a BITMAPINFOHEADER; RGB565 and RGBA need the channel
+// masks of a BITMAPV4HEADER.
+
+const FILE_HEADER_LEN: u32 = 14;
+const INFO_HEADER_LEN: u32 = 40;
+const V4_HEADER_LEN: u32 = 108;
+
+/// BI_RGB and BI_BITFIELDS.
+const COMPRESSION_NONE: u32 = 0;
+const COMPRESSION_BITFIELDS: u32 = 3;
+
+/// "sRGB" as the V4 header's colour space type.
+const LCS_SRGB: u32 = 0x7352_4742;
+
+/// Writes `img` in the layout `options` asks for. Images with alpha must already have been
+/// flattened for layouts without it; their alpha is ignored here.
+pub fn encode_bmp(img: &DynamicImage, options: &BmpOptions) -> Result<Vec<u8>, ConvertError> {
+    let format = match options.format {
+        BmpFormat::Auto if img.color().has_alpha() => BmpFormat::Rgba32,
+        BmpFormat::Auto => BmpFormat::Rgb24,
+        format => format,
+    };
+    let (width, height) = (img.width(), img.height());
+    let too_large = || ConvertError::encoder(ImageFormat::Bmp, "image is too large for BMP");
+    let signed_width = i32::try_from(width).map_err(|_| too_large())?;
+    let signed_height = i32::try_from(height).map_err(|_| too_large())?;
+
+    let rgba = img.to_rgba8();
+    let (bits, pixels, palette): (u16, Vec<u8>, Vec<[u8; 3]>) =
+        if let Some(settings) = options.quantize_settings() {
+            let indexed = quantize(&rgba, &settings);
+            (settings.colors.trailing_zeros() as u16, indexed.indices, indexed.palette)
+        } else {
+            match format {
+                BmpFormat::Rgb565 => {
+                    let pixels = rgba
+                        .pixels()
+                        .flat_map(|p| {
+                            let [r, g, b, _] = p.0.map(u16::from);
+                            ((r >> 3) << 11 | (g >> 2) << 5 | b >> 3).to_le_bytes()
+                        })
+                        .collect();
+                    (16, pixels, Vec::new())
+                }
+                BmpFormat::Rgba32 => {
+                    (32, rgba.pixels().flat_map(|p| [p[2], p[1], p[0], p[3]]).collect(), Vec::new())
+                }
+                _ => (24, rgba.pixels().flat_map(|p| [p[2], p[1], p[0]]).collect(), Vec::new()),
+            }
+        };
+
+    // Rows are padded to whole 32-bit words.
+    let row_len = (width as usize * usize::from(bits)).div_ceil(32) * 4;
+    let image_len = u32::try_from(row_len * height as usize).map_err(|_| too_large())?;
+    let (header_len, compression) = match format {
+        BmpFormat::Rgb565 | BmpFormat::Rgba32 => (V4_HEADER_LEN, COMPRESSION_BITFIELDS),
+        _ => (INFO_HEADER_LEN, COMPRESSION_NONE),
+    };
+    let data_offset = FILE_HEADER_LEN + header_len + palette.len() as u32 * 4;
+    let file_len = data_offset.checked_add(image_len).ok_or_else(too_large)?;
+
+    let mut data = Vec::with_capacity(file_len as usize);
+    // BITMAPFILEHEADER
+    data.extend(b"BM");
+    data.extend(file_len.to_le_bytes());
+    data.extend([0; 4]);
+    data.extend(data_offset.to_le_bytes());
+    // BITMAPINFOHEADER. A negative height marks top-down row order.
+    data.extend(header_len.to_le_bytes());
+    data.extend(signed_width.to_le_bytes());
+    data.extend((if options.top_down { -signed_height } else { signed_height }).to_le_bytes());
+    data.extend(1u16.to_le_bytes());
+    data.extend(bits.to_le_bytes());
+    data.extend(compression.to_le_bytes());
+    data.extend(image_len.to_le_bytes());
+    data.extend([0; 8]);
+    data.extend((palette.len() as u32).to_le_bytes());
+    data.extend([0; 4]);
+    if header_len == V4_HEADER_LEN {
+        let masks: [u32; 4] = if format == BmpFormat::Rgb565 {
+            [0xF800, 0x07E0, 0x001F, 0]
+        } else {
+            [0xFF << 16, 0xFF << 8, 0xFF, 0xFF << 24]
+        };
+        data.extend(masks.iter().flat_map(|mask| mask.to_le_bytes()));
+        data.extend(LCS_SRGB.to_le_bytes());
+        // Colour space endpoints and gamma, unused for sRGB.
+        data.extend([0; 48]);
+    }
+    for [r, g, b] in &palette {
+        data.extend([*b, *g, *r, 0]);
+    }
+
+    let src_row_len = match bits {
+        1 | 4 | 8 => width as usize,
+        _ => width as usize * usize::from(bits / 8),
+    };
+    let mut row = Vec::with_capacity(row_len);
+    for y in 0..height as usize {
+        let y = if options.top_down { y } else { height as usize - 1 - y };
+        let src = &pixels[y * src_row_len..(y + 1) * src_row_len];
+        row.clear();
+        match bits {
+            // Sub-byte indices are packed with the leftmost pixel in the high bits.
+            1 | 4 => row.extend(src.chunks(usize::from(8 / bits)).map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |byte, (i, &index)| byte | index << (8 - bits as usize * (i + 1)))
+            })),
+            _ => row.extend_from_slice(src),
+        }
+        row.resize(row_len, 0);
+        data.extend_from_slice(&row);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Dither, Quantizer};
+    use image::{Rgba, RgbaImage};
+
+    // 4 flat colours with 7 (odd) columns, so rows need padding and bit packing.
+    fn sample_image() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(7, 5, |x, y| match (x < 3, y < 2) {
+            (true, true) => Rgba([0, 0, 0, 255]),
+            (false, true) => Rgba([255, 255, 255, 255]),
+            (true, false) => Rgba([255, 0, 0, 255]),
+            (false, false) => Rgba([0, 255, 255, 255]),
+        }))
+    }
+
+    fn encode(img: &DynamicImage, format: BmpFormat, top_down: bool) -> (Vec<u8>, DynamicImage) {
+        let options =
+            BmpOptions { format, top_down, dither: Dither::None, ..BmpOptions::default() };
+        let data = encode_bmp(img, &options).unwrap();
+        let decoded = image::load_from_memory_with_format(&data, ImageFormat::Bmp).unwrap();
+        (data, decoded)
+    }
+
+    /// Bits per pixel and height as stored in the header.
+    fn header_fields(data: &[u8]) -> (u16, i32) {
+        let bits = u16::from_le_bytes([data[28], data[29]]);
+        let height = i32::from_le_bytes([data[22], data[23], data[24], data[25]]);
+        (bits, height)
+    }
+
+    #[test]
+    fn every_layout_round_trips() {
+        let img = sample_image();
+        for (format, bits) in [
+            (BmpFormat::Indexed4, 4),
+            (BmpFormat::Indexed8, 8),
+            (BmpFormat::Rgb565, 16),
+            (BmpFormat::Rgb24, 24),
+            (BmpFormat::Rgba32, 32),
+        ] {
+            for top_down in [false, true] {
+                let (data, decoded) = encode(&img, format, top_down);
+                let expected_height = if top_down { -5 } else { 5 };
+                assert_eq!(header_fields(&data), (bits, expected_height), "{:?}", format);
+                // Full-on and full-off channels are exact in 5-6-5 too.
+                assert_eq!(decoded.to_rgba8(), img.to_rgba8(), "{:?} {}", format, top_down);
+            }
+        }
+    }
+
+    #[test]
+    fn one_bit_keeps_two_colours() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 3, |x, y| {
+            if (x + y) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+        let (data, decoded) = encode(&img, BmpFormat::Indexed1, false);
+        assert_eq!(header_fields(&data), (1, 3));
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+
+        // More colours than fit end up as the nearest of two entries.
+        let (_, decoded) = encode(&sample_image(), BmpFormat::Indexed1, true);
+        let mut colours: Vec<_> = decoded.to_rgb8().pixels().map(|p| p.0).collect();
+        colours.sort_unstable();
+        colours.dedup();
+        assert_eq!(colours.len(), 2);
+    }
+
+    #[test]
+    fn every_quantizer_builds_the_palette() {
+        for quantizer in [Quantizer::NeuQuant, Quantizer::MedianCut] {
+            let options = BmpOptions {
+                format: BmpFormat::Indexed4,
+                quantizer,
+                sample_factor: 1,
+                dither: Dither::None,
+                ..BmpOptions::default()
+            };
+            let data = encode_bmp(&sample_image(), &options).unwrap();
+            let decoded = image::load_from_memory_with_format(&data, ImageFormat::Bmp).unwrap();
+            assert_eq!(decoded.to_rgba8(), sample_image().to_rgba8(), "{:?}", quantizer);
+        }
+    }
+
+    #[test]
+    fn auto_follows_alpha() {
+        let (data, _) = encode(&sample_image(), BmpFormat::Auto, false);
+        assert_eq!(header_fields(&data).0, 32);
+        let opaque = DynamicImage::ImageRgb8(sample_image().to_rgb8());
+        let (data, decoded) = encode(&opaque, BmpFormat::Auto, false);
+        assert_eq!(header_fields(&data).0, 24);
+        assert_eq!(decoded.to_rgb8(), opaque.to_rgb8());
+    }
+}
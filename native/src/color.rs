@@ -0,0 +1,348 @@
+use image::{DynamicImage, ImageBuffer, ImageFormat, RgbImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformExecutor, TransformOptions};
+use zune_jpeg::JpegDecoder;
+use zune_jpeg::zune_core::bytestream::ZCursor;
+use zune_jpeg::zune_core::colorspace::ColorSpace;
+use zune_jpeg::zune_core::options::DecoderOptions;
+use crate::metadata::Metadata;
+use crate::options::IccHandling;
+
+// ----------------------------------------------------------------
+// ICC profiles: embedded in the output or converted to sRGB
+// ----------------------------------------------------------------
+//
+// Pixel values only mean something together with their profile. Untagged output is read
+// as sRGB everywhere, so a profile is either carried along with the untouched pixels or
+// the pixels are transformed to sRGB and the profile is dropped.
+
+/// What a conversion does with the source's ICC profile. Decided once per input and
+/// applied to every frame right after decoding; `for_target` then settles what is left
+/// for each output format.
+#[derive(Clone)]
+pub enum ProfileAction {
+    /// Pixels stay as they are; the profile travels only if the metadata policy keeps it.
+    Keep,
+    /// Pixels stay as they are and the output is tagged with this profile.
+    Embed(Vec<u8>),
+    /// Pixels are transformed from this profile to sRGB and the output is untagged.
+    ToSrgb(Box<ColorProfile>),
+    /// The profile cannot be honoured (e.g. CMYK outside JPEG, whose pixels the decoder
+    /// already turned into RGB) and no longer describes the pixels, so it is dropped.
+    Drop,
+}
+
+impl ProfileAction {
+    pub fn new(
+        icc: Option<&[u8]>,
+        handling: IccHandling,
+        input_format: ImageFormat,
+    ) -> ProfileAction {
+        let Some(icc) = icc else { return ProfileAction::Keep };
+        if handling == IccHandling::Ignore {
+            return ProfileAction::Keep;
+        }
+        // A profile we cannot parse is left to the metadata policy, as with `Ignore`.
+        let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+            return ProfileAction::Keep;
+        };
+
+        match profile.color_space {
+            DataColorSpace::Rgb if handling == IccHandling::Embed => {
+                ProfileAction::Embed(icc.to_vec())
+            }
+            DataColorSpace::Rgb | DataColorSpace::Gray => {
+                ProfileAction::ToSrgb(Box::new(profile))
+            }
+            DataColorSpace::Cmyk if input_format == ImageFormat::Jpeg => {
+                ProfileAction::ToSrgb(Box::new(profile))
+            }
+            _ => ProfileAction::Drop,
+        }
+    }
+
+    /// What is left to do for `output_format` once `apply` has run on the decoded pixels.
+    /// Converted pixels only need the profile dropped, and an embedded profile falls back
+    /// to converting for targets that cannot hold one.
+    pub fn for_target(&self, output_format: ImageFormat) -> ProfileAction {
+        match self {
+            ProfileAction::Keep => ProfileAction::Keep,
+            ProfileAction::Embed(icc) if can_embed(output_format) => {
+                ProfileAction::Embed(icc.clone())
+            }
+            ProfileAction::Embed(icc) => match ColorProfile::new_from_slice(icc) {
+                Ok(profile) => ProfileAction::ToSrgb(Box::new(profile)),
+                Err(_) => ProfileAction::Drop,
+            },
+            ProfileAction::ToSrgb(_) | ProfileAction::Drop => ProfileAction::Drop,
+        }
+    }
+
+    /// Sets the profile the output carries.
+    pub fn tag(&self, metadata: &mut Metadata) {
+        match self {
+            ProfileAction::Keep => {}
+            ProfileAction::Embed(icc) => metadata.icc = Some(icc.clone()),
+            ProfileAction::ToSrgb(_) | ProfileAction::Drop => metadata.icc = None,
+        }
+    }
+
+    /// Transforms decoded pixels to sRGB when that is the plan. Pixels are left as they
+    /// are if the profile does not fit the image (e.g. a grayscale profile on RGB pixels).
+    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
+        let ProfileAction::ToSrgb(profile) = self else { return img };
+        let converted = match profile.color_space {
+            DataColorSpace::Rgb => rgb_to_srgb(profile, &img),
+            DataColorSpace::Gray if !img.color().has_color() => gray_to_srgb(profile, &img),
+            _ => None,
+        };
+        converted.unwrap_or(img)
+    }
+
+    /// Decodes a CMYK (or YCCK) JPEG straight to sRGB through its CMYK profile. The
+    /// regular decoder converts CMYK without a profile, which is badly off for print
+    /// files. `None` when there is no CMYK profile to apply or the JPEG is not CMYK.
+    pub fn decode_cmyk_jpeg(&self, input_data: &[u8]) -> Option<DynamicImage> {
+        match self {
+            ProfileAction::ToSrgb(profile) if profile.color_space == DataColorSpace::Cmyk => {
+                cmyk_jpeg_to_srgb(input_data, profile)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Targets whose encoders write an ICC profile.
+fn can_embed(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Tiff
+    )
+}
+
+fn run<T: Copy + Default>(
+    transform: &(impl TransformExecutor<T> + ?Sized),
+    src: &[T],
+) -> Option<Vec<T>> {
+    let mut dst = vec![T::default(); src.len()];
+    transform.transform(src, &mut dst).ok()?;
+    Some(dst)
+}
+
+/// Grayscale input is widened to RGB, since an RGB profile describes it as R = G = B.
+fn rgb_to_srgb(profile: &ColorProfile, img: &DynamicImage) -> Option<DynamicImage> {
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let (width, height) = (img.width(), img.height());
+    let color = img.color();
+    let bytes_per_channel = color.bytes_per_pixel() / color.channel_count();
+
+    match (bytes_per_channel, color.has_alpha()) {
+        (1, false) => {
+            let t = profile.create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, options);
+            let pixels = run(&*t.ok()?, img.to_rgb8().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+        }
+        (1, true) => {
+            let t = profile.create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, options);
+            let pixels = run(&*t.ok()?, img.to_rgba8().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+        }
+        (2, false) => {
+            let t = profile.create_transform_16bit(Layout::Rgb, &srgb, Layout::Rgb, options);
+            let pixels = run(&*t.ok()?, img.to_rgb16().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb16)
+        }
+        (2, true) => {
+            let t = profile.create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgba, options);
+            let pixels = run(&*t.ok()?, img.to_rgba16().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba16)
+        }
+        (_, false) => {
+            let t = profile.create_transform_f32(Layout::Rgb, &srgb, Layout::Rgb, options);
+            let pixels = run(&*t.ok()?, img.to_rgb32f().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgb32F)
+        }
+        (_, true) => {
+            let t = profile.create_transform_f32(Layout::Rgba, &srgb, Layout::Rgba, options);
+            let pixels = run(&*t.ok()?, img.to_rgba32f().as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageRgba32F)
+        }
+    }
+}
+
+/// Grayscale stays grayscale: the target is a gray profile with the sRGB tone curve.
+fn gray_to_srgb(profile: &ColorProfile, img: &DynamicImage) -> Option<DynamicImage> {
+    let mut srgb_gray = ColorProfile::new_srgb();
+    srgb_gray.color_space = DataColorSpace::Gray;
+    srgb_gray.gray_trc = srgb_gray.red_trc.clone();
+    let options = TransformOptions::default();
+    let (width, height) = (img.width(), img.height());
+
+    match img {
+        DynamicImage::ImageLuma8(gray) => {
+            let t = profile.create_transform_8bit(Layout::Gray, &srgb_gray, Layout::Gray, options);
+            let pixels = run(&*t.ok()?, gray.as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        }
+        DynamicImage::ImageLumaA8(gray) => {
+            let layout = Layout::GrayAlpha;
+            let t = profile.create_transform_8bit(layout, &srgb_gray, layout, options);
+            let pixels = run(&*t.ok()?, gray.as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA8)
+        }
+        DynamicImage::ImageLuma16(gray) => {
+            let layout = Layout::Gray;
+            let t = profile.create_transform_16bit(layout, &srgb_gray, layout, options);
+            let pixels = run(&*t.ok()?, gray.as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma16)
+        }
+        DynamicImage::ImageLumaA16(gray) => {
+            let layout = Layout::GrayAlpha;
+            let t = profile.create_transform_16bit(layout, &srgb_gray, layout, options);
+            let pixels = run(&*t.ok()?, gray.as_raw())?;
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLumaA16)
+        }
+        _ => None,
+    }
+}
+
+fn cmyk_jpeg_to_srgb(input_data: &[u8], profile: &ColorProfile) -> Option<DynamicImage> {
+    let mut decoder = JpegDecoder::new(ZCursor::new(input_data));
+    decoder.decode_headers().ok()?;
+    let colorspace = decoder.input_colorspace()?;
+    if !matches!(colorspace, ColorSpace::CMYK | ColorSpace::YCCK) {
+        return None;
+    }
+
+    // Ask for the samples exactly as stored; both CMYK and YCCK are handled below.
+    decoder.set_options(DecoderOptions::default().jpeg_set_out_colorspace(colorspace));
+    let mut samples = decoder.decode().ok()?;
+    let info = decoder.info()?;
+
+    for pixel in samples.chunks_exact_mut(4) {
+        if colorspace == ColorSpace::YCCK {
+            // YCCK is CMY run through the usual YCbCr transform, with K stored as is.
+            let [r, g, b] = ycbcr_to_rgb(pixel[0], pixel[1], pixel[2]);
+            pixel[..3].copy_from_slice(&[255 - r, 255 - g, 255 - b]);
+        }
+        // Adobe writes CMYK inverted (255 = no ink); ICC profiles count ink.
+        for sample in pixel {
+            *sample = 255 - *sample;
+        }
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    // Four channels in moxcms terms, which is what `Layout::Rgba` stands for on CMYK input.
+    let transform = profile
+        .create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgb, TransformOptions::default())
+        .ok()?;
+    let mut rgb = vec![0; samples.len() / 4 * 3];
+    transform.transform(&samples, &mut rgb).ok()?;
+
+    RgbImage::from_raw(info.width.into(), info.height.into(), rgb).map(DynamicImage::ImageRgb8)
+}
+
+/// Full range JFIF YCbCr to RGB.
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (f32::from(y), f32::from(cb) - 128.0, f32::from(cr) - 128.0);
+    let clamp = |v: f32| v.round().clamp(0.0, 255.0) as u8;
+    [
+        clamp(y + 1.402 * cr),
+        clamp(y - 0.344_136 * cb - 0.714_136 * cr),
+        clamp(y + 1.772 * cb),
+    ]
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{GrayImage, Luma, Rgb};
+    use moxcms::{LutDataType, LutStore, LutType, LutWarehouse, Matrix3d, ProfileClass, Xyzd};
+
+    /// A minimal CMYK profile that maps every ink combination to mid gray. Real press
+    /// profiles are hundreds of kilobytes; this one only has to be told apart from the
+    /// naive conversion, which turns "no ink" into white.
+    pub(crate) fn flat_cmyk_profile() -> Vec<u8> {
+        let mut profile = ColorProfile::default();
+        profile.color_space = DataColorSpace::Cmyk;
+        profile.pcs = DataColorSpace::Lab;
+        profile.profile_class = ProfileClass::ColorSpace;
+        profile.white_point = Xyzd { x: 0.9642, y: 1.0, z: 0.8249 };
+        profile.lut_a_to_b_perceptual = Some(LutWarehouse::Lut(LutDataType {
+            num_input_channels: 4,
+            num_output_channels: 3,
+            num_clut_grid_points: 2,
+            matrix: Matrix3d::IDENTITY,
+            num_input_table_entries: 2,
+            num_output_table_entries: 2,
+            input_table: LutStore::Store16([0, 65535].repeat(4)),
+            clut_table: LutStore::Store16([0x7F80, 0x8080, 0x8080].repeat(16)),
+            output_table: LutStore::Store16([0, 65535].repeat(3)),
+            lut_type: LutType::Lut16,
+        }));
+        profile.encode().expect("Failed to encode CMYK profile")
+    }
+
+    fn display_p3() -> Vec<u8> {
+        ColorProfile::new_display_p3().encode().expect("Failed to encode Display P3")
+    }
+
+    fn to_srgb(icc: &[u8]) -> ProfileAction {
+        ProfileAction::new(Some(icc), IccHandling::ConvertToSrgb, ImageFormat::Png)
+    }
+
+    #[test]
+    fn display_p3_pixels_are_converted_to_srgb() {
+        let img = RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgb([0, 200, 0]) } else { Rgb([128; 3]) }
+        });
+        let converted = to_srgb(&display_p3()).apply(DynamicImage::ImageRgb8(img)).to_rgb8();
+
+        // P3 green is outside sRGB: the red channel goes negative and clips to zero.
+        let green = converted.get_pixel(0, 0).0;
+        assert!(green[0] == 0 && green[1] > 200, "{:?}", green);
+        // Both spaces share the white point and tone curve, so grays do not move.
+        let gray = converted.get_pixel(1, 0).0;
+        assert!(gray.iter().all(|&v| v.abs_diff(128) <= 1), "{:?}", gray);
+    }
+
+    #[test]
+    fn gray_profiles_keep_gray_pixels_gray() {
+        let linear = ColorProfile::new_gray_with_gamma(1.0).encode().expect("Failed to encode");
+        let img = DynamicImage::ImageLuma8(GrayImage::from_pixel(1, 1, Luma([128])));
+        let converted = to_srgb(&linear).apply(img);
+
+        // Linear 50% is about 188 on the sRGB curve.
+        let DynamicImage::ImageLuma8(gray) = converted else { panic!("expected Luma8") };
+        assert!(gray.get_pixel(0, 0)[0].abs_diff(188) <= 2, "{:?}", gray.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn handling_picks_embed_convert_or_keep() {
+        let p3 = display_p3();
+        let action = |handling, output| {
+            ProfileAction::new(Some(&p3), handling, ImageFormat::Png).for_target(output)
+        };
+
+        assert!(matches!(action(IccHandling::Embed, ImageFormat::WebP), ProfileAction::Embed(_)));
+        // BMP cannot hold a profile, so embedding falls back to converting.
+        assert!(matches!(action(IccHandling::Embed, ImageFormat::Bmp), ProfileAction::ToSrgb(_)));
+        // Pixels were converted while decoding; every target just drops the profile.
+        let converted = action(IccHandling::ConvertToSrgb, ImageFormat::Png);
+        assert!(matches!(converted, ProfileAction::Drop));
+        assert!(matches!(action(IccHandling::Ignore, ImageFormat::Png), ProfileAction::Keep));
+
+        let convert = IccHandling::ConvertToSrgb;
+        let garbage = ProfileAction::new(Some(&[7; 200]), convert, ImageFormat::Png);
+        assert!(matches!(garbage, ProfileAction::Keep));
+        let cmyk = flat_cmyk_profile();
+        let cmyk_tiff = ProfileAction::new(Some(&cmyk), convert, ImageFormat::Tiff);
+        assert!(matches!(cmyk_tiff, ProfileAction::Drop));
+
+        let mut metadata = Metadata { icc: Some(vec![1]), ..Metadata::default() };
+        action(IccHandling::Embed, ImageFormat::Png).tag(&mut metadata);
+        assert_eq!(metadata.icc, Some(p3.clone()));
+        converted.tag(&mut metadata);
+        assert_eq!(metadata.icc, None);
+    }
+}
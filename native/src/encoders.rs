@@ -0,0 +1,859 @@
+use image::codecs::ico::{IcoEncoder, IcoFrame};
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::codecs::tga::TgaEncoder;
+use image::{DynamicImage, ExtendedColorType, ImageFormat, RgbaImage};
+use jpeg_encoder::{ColorType, Encoder as JpegEncoder, SamplingFactor};
+use std::borrow::Cow;
+use std::io::{Cursor, Seek, Write};
+use tiff::encoder::colortype::{self as tiff_color, ColorType as TiffColorType};
+use tiff::encoder::{Compression, DeflateLevel, Predictor, TiffEncoder, TiffValue};
+use tiff::tags::Tag;
+use crate::bmp::encode_bmp;
+use crate::error::ConvertError;
+use crate::metadata::{embed_png, embed_webp, Metadata};
+use crate::options::{
+    BmpFormat, ChromaSubsampling, ConvertOptions, PngFilter, ResizeFit, TiffCompression,
+};
+use crate::png_optimize::optimize_png;
+use crate::quantize::quantize;
+use crate::resize::resize_to;
+use crate::vp8::encode_lossy_webp;
+
+// ----------------------------------------------------------------
+// Format specific encoders
+// ----------------------------------------------------------------
+
+/// Quality used for lossy formats when `ConvertOptions::quality` is not set.
+/// Matches the default of the `image` crate's own JPEG encoder.
+const DEFAULT_QUALITY: u8 = 75;
+
+/// XMP in JPEG lives in an APP1 segment starting with this namespace.
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Encodes an already adjusted image. Formats without dedicated settings go through
+/// the `image` crate's generic `write_to`; those cannot carry `metadata`.
+pub fn encode_image(
+    img: &DynamicImage,
+    output_format: ImageFormat,
+    options: &ConvertOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, ConvertError> {
+    match output_format {
+        ImageFormat::Jpeg => encode_jpeg(img, options, metadata),
+        ImageFormat::Png => encode_png(img, options).map(|png| embed_png(png, metadata)),
+        ImageFormat::WebP => encode_webp(img, options).map(|webp| {
+            embed_webp(webp, metadata, img.width(), img.height(), img.color().has_alpha())
+        }),
+        ImageFormat::Avif => encode_avif(img, options),
+        ImageFormat::Pnm => encode_pnm(img, options),
+        ImageFormat::Tiff => encode_tiff(std::slice::from_ref(img), options, metadata),
+        ImageFormat::Ico if !options.ico.sizes.is_empty() => encode_ico(img, options),
+        ImageFormat::Tga => encode_tga(img, options),
+        ImageFormat::Bmp if options.bmp.format != BmpFormat::Auto || options.bmp.top_down => {
+            encode_bmp(img, &options.bmp)
+        }
+        ImageFormat::Gif => {
+            let pixels = img.to_rgba8();
+            encode_gif_frames([(&pixels, 0)], pixels.dimensions(), None, options)
+        }
+        _ => {
+            let mut buffer = Cursor::new(Vec::new());
+
+            // write_to accepts ImageFormat directly in version 0.25+
+            img.write_to(&mut buffer, output_format)
+                .map_err(ConvertError::EncodeFailed)?;
+
+            Ok(buffer.into_inner())
+        }
+    }
+}
+
+fn encode_jpeg(
+    img: &DynamicImage,
+    options: &ConvertOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, ConvertError> {
+    let to_error = |e: jpeg_encoder::EncodingError| ConvertError::encoder(ImageFormat::Jpeg, e);
+    let quality = options.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100);
+
+    // The JFIF header stores dimensions as u16.
+    let too_large = |side: &str| {
+        ConvertError::encoder(ImageFormat::Jpeg, format!("image is {side} than 65535 pixels"))
+    };
+    let width = u16::try_from(img.width()).map_err(|_| too_large("wider"))?;
+    let height = u16::try_from(img.height()).map_err(|_| too_large("taller"))?;
+
+    // JPEG has no alpha; transparent inputs were already blended onto the background.
+    let (data, color_type) = match img {
+        DynamicImage::ImageLuma8(gray) => (gray.as_raw().clone(), ColorType::Luma),
+        DynamicImage::ImageLuma16(_) => (img.to_luma8().into_raw(), ColorType::Luma),
+        _ => (img.to_rgb8().into_raw(), ColorType::Rgb),
+    };
+
+    let mut buffer = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut buffer, quality);
+    encoder.set_progressive(options.jpeg.progressive);
+    encoder.set_optimized_huffman_tables(options.jpeg.optimize_huffman);
+    if let Some(subsampling) = options.jpeg.subsampling {
+        encoder.set_sampling_factor(match subsampling {
+            ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+        });
+    }
+
+    if let Some(exif) = &metadata.exif {
+        encoder.add_exif_metadata(exif).map_err(to_error)?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        encoder
+            .add_app_segment(1, [JPEG_XMP_HEADER, xmp].concat())
+            .map_err(to_error)?;
+    }
+    if let Some(icc) = &metadata.icc {
+        encoder.add_icc_profile(icc).map_err(to_error)?;
+    }
+
+    encoder.encode(&data, width, height, color_type).map_err(to_error)?;
+
+    Ok(buffer)
+}
+
+fn encode_png(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let png = &options.png;
+
+    if png.optimize {
+        return optimize_png(img, png.compression_level.unwrap_or(9));
+    }
+
+    let compression = match png.compression_level {
+        None => CompressionType::default(),
+        Some(0) => CompressionType::Uncompressed,
+        Some(level) => CompressionType::Level(level.min(9)),
+    };
+    let filter = match png.filter {
+        PngFilter::NoFilter => PngFilterType::NoFilter,
+        PngFilter::Sub => PngFilterType::Sub,
+        PngFilter::Up => PngFilterType::Up,
+        PngFilter::Average => PngFilterType::Avg,
+        PngFilter::Paeth => PngFilterType::Paeth,
+        PngFilter::Adaptive => PngFilterType::Adaptive,
+    };
+
+    let mut buffer = Vec::new();
+    img.write_with_encoder(PngEncoder::new_with_quality(&mut buffer, compression, filter))
+        .map_err(ConvertError::EncodeFailed)?;
+
+    Ok(buffer)
+}
+
+fn encode_webp(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let webp = &options.webp;
+
+    // Plain lossless output stays on the pure Rust encoder of the `image` crate.
+    if webp.lossless && webp.near_lossless.is_none() {
+        let mut buffer = Cursor::new(Vec::new());
+        img.write_to(&mut buffer, ImageFormat::WebP)
+            .map_err(ConvertError::EncodeFailed)?;
+        return Ok(buffer.into_inner());
+    }
+    // Lossy output uses libwebp when it is compiled in, our own VP8 encoder otherwise.
+    // Near-lossless only exists in libwebp.
+    if cfg!(not(feature = "webp-lossy")) && !webp.lossless {
+        let quality = options.quality.unwrap_or(DEFAULT_QUALITY);
+        return encode_lossy_webp(img, quality, webp);
+    }
+
+    encode_webp_libwebp(img, options)
+}
+
+/// Writes `frames` (pixels and delay in milliseconds) as one GIF of `width` x `height`.
+/// Each frame is quantized to its own palette with the `gif` settings of `options`.
+/// `repeat` is only written for animations.
+pub fn encode_gif_frames<'a>(
+    frames: impl IntoIterator<Item = (&'a RgbaImage, u32)>,
+    (width, height): (u32, u32),
+    repeat: Option<gif::Repeat>,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    let to_error = |e: gif::EncodingError| ConvertError::encoder(ImageFormat::Gif, e);
+    let too_large = |_| ConvertError::encoder(ImageFormat::Gif, "GIF sides cannot exceed 65535");
+    let width = u16::try_from(width).map_err(too_large)?;
+    let height = u16::try_from(height).map_err(too_large)?;
+    let settings = options.gif.quantize_settings();
+
+    let mut buffer = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut buffer, width, height, &[]).map_err(to_error)?;
+    if let Some(repeat) = repeat {
+        encoder.set_repeat(repeat).map_err(to_error)?;
+    }
+    for (pixels, delay_ms) in frames {
+        let indexed = quantize(pixels, &settings);
+        let frame = gif::Frame {
+            width,
+            height,
+            delay: u16::try_from(delay_ms / 10).unwrap_or(u16::MAX),
+            // Frames cover the whole canvas; clear it so transparent pixels do not show
+            // the previous frame.
+            dispose: gif::DisposalMethod::Background,
+            transparent: indexed.transparent,
+            palette: Some(indexed.palette.concat()),
+            buffer: Cow::Owned(indexed.indices),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).map_err(to_error)?;
+    }
+    encoder.into_inner().map_err(to_error)?;
+
+    Ok(buffer)
+}
+
+/// One ICO entry per size in `options.ico.sizes`, smallest first. Each is resampled from
+/// `img` to fit the square and padded with transparency.
+fn encode_ico(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    let ico = &options.ico;
+    let mut sizes = ico.sizes.clone();
+    sizes.sort_unstable();
+    sizes.dedup();
+    if let Some(size) = sizes.iter().find(|size| !(1..=256).contains(*size)) {
+        return Err(ConvertError::InvalidOption(format!(
+            "ICO entry size {} is outside 1-256",
+            size
+        )));
+    }
+
+    let mut entry_options = options.clone();
+    entry_options.fit = ResizeFit::Contain;
+    let entries = sizes
+        .into_iter()
+        .map(|size| {
+            let entry = resize_to(img.clone(), Some(size), Some(size), &entry_options)?;
+            Ok((size, entry.into_rgba8()))
+        })
+        .collect::<Result<Vec<_>, ConvertError>>()?;
+    let frames = entries
+        .into_iter()
+        .map(|(size, entry)| {
+            let (width, height) = entry.dimensions();
+            if size >= ico.png_min_size {
+                IcoFrame::as_png(entry.as_raw(), width, height, ExtendedColorType::Rgba8)
+            } else {
+                IcoFrame::with_encoded(ico_bmp(&entry), width, height, ExtendedColorType::Rgba8)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(ConvertError::EncodeFailed)?;
+
+    let mut buffer = Vec::new();
+    IcoEncoder::new(&mut buffer)
+        .encode_images(&frames)
+        .map_err(ConvertError::EncodeFailed)?;
+    Ok(buffer)
+}
+
+/// A BMP entry as ICO stores it: a BITMAPINFOHEADER claiming twice the height, 32-bit BGRA
+/// rows bottom-up, then the 1-bit AND mask (set where the pixel is fully transparent).
+fn ico_bmp(img: &RgbaImage) -> Vec<u8> {
+    let (width, height) = img.dimensions();
+    let mask_row = width.div_ceil(32) as usize * 4;
+    let image_size = width * height * 4 + mask_row as u32 * height;
+
+    let mut data = Vec::with_capacity(40 + image_size as usize);
+    data.extend(40u32.to_le_bytes());
+    data.extend(width.to_le_bytes());
+    data.extend((height * 2).to_le_bytes());
+    data.extend(1u16.to_le_bytes()); // planes
+    data.extend(32u16.to_le_bytes()); // bits per pixel
+    data.extend(0u32.to_le_bytes()); // BI_RGB
+    data.extend(image_size.to_le_bytes());
+    data.extend([0; 16]); // resolution and palette counts
+    for row in img.rows().rev() {
+        for pixel in row {
+            data.extend([pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+    for row in img.rows().rev() {
+        let mut mask = vec![0u8; mask_row];
+        for (x, pixel) in row.enumerate() {
+            if pixel[3] == 0 {
+                mask[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        data.extend(mask);
+    }
+    data
+}
+
+fn encode_tga(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    // The TGA encoder takes 8-bit gray or RGB, with or without alpha.
+    let img = match img.color() {
+        image::ColorType::L8 | image::ColorType::La8 | image::ColorType::Rgb8
+        | image::ColorType::Rgba8 => Cow::Borrowed(img),
+        image::ColorType::L16 => Cow::Owned(DynamicImage::ImageLuma8(img.to_luma8())),
+        image::ColorType::La16 => Cow::Owned(DynamicImage::ImageLumaA8(img.to_luma_alpha8())),
+        color if color.has_alpha() => Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8())),
+        _ => Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8())),
+    };
+
+    let mut buffer = Vec::new();
+    let mut encoder = TgaEncoder::new(&mut buffer);
+    if !options.tga.rle {
+        encoder = encoder.disable_rle();
+    }
+    encoder
+        .encode(img.as_bytes(), img.width(), img.height(), img.color().into())
+        .map_err(ConvertError::EncodeFailed)?;
+
+    Ok(buffer)
+}
+
+/// Writes `pages` as the pages of one TIFF, in order. `metadata` goes on the first page.
+pub fn encode_tiff(
+    pages: &[DynamicImage],
+    options: &ConvertOptions,
+    metadata: &Metadata,
+) -> Result<Vec<u8>, ConvertError> {
+    let to_error = |e| ConvertError::encoder(ImageFormat::Tiff, e);
+    let compression = match options.tiff.compression {
+        TiffCompression::None => Compression::Uncompressed,
+        TiffCompression::Lzw => Compression::Lzw,
+        TiffCompression::Deflate => Compression::Deflate(DeflateLevel::Balanced),
+        TiffCompression::PackBits => Compression::Packbits,
+    };
+
+    let mut buffer = Cursor::new(Vec::new());
+    let mut encoder =
+        TiffEncoder::new(&mut buffer).map_err(to_error)?.with_compression(compression);
+    let no_metadata = Metadata::default();
+    for (index, img) in pages.iter().enumerate() {
+        let metadata = if index == 0 { metadata } else { &no_metadata };
+        // The horizontal predictor is only defined for integer samples.
+        let float = matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+        let predictor = if options.tiff.predictor
+            && !float
+            && options.tiff.compression != TiffCompression::None
+        {
+            Predictor::Horizontal
+        } else {
+            Predictor::None
+        };
+        encoder = encoder.with_predictor(predictor);
+        write_page(&mut encoder, img, metadata).map_err(to_error)?;
+    }
+
+    Ok(buffer.into_inner())
+}
+
+fn write_page<W: Write + Seek>(
+    encoder: &mut TiffEncoder<W>,
+    img: &DynamicImage,
+    metadata: &Metadata,
+) -> tiff::TiffResult<()> {
+    let (width, height) = (img.width(), img.height());
+
+    // Same sample layouts as the `image` crate's TIFF encoder; gray + alpha has no TIFF
+    // colour type there, so it is widened to RGBA.
+    match img {
+        DynamicImage::ImageLuma8(buf) => {
+            write_tiff::<tiff_color::Gray8, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageRgb8(buf) => {
+            write_tiff::<tiff_color::RGB8, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageLuma16(buf) => {
+            write_tiff::<tiff_color::Gray16, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageRgb16(buf) => {
+            write_tiff::<tiff_color::RGB16, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageRgba16(buf) => {
+            write_tiff::<tiff_color::RGBA16, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageLumaA16(_) => {
+            let buf = img.to_rgba16();
+            write_tiff::<tiff_color::RGBA16, _>(encoder, width, height, &buf, metadata)
+        }
+        DynamicImage::ImageRgb32F(buf) => {
+            write_tiff::<tiff_color::RGB32Float, _>(encoder, width, height, buf, metadata)
+        }
+        DynamicImage::ImageRgba32F(buf) => {
+            write_tiff::<tiff_color::RGBA32Float, _>(encoder, width, height, buf, metadata)
+        }
+        _ => {
+            let buf = img.to_rgba8();
+            write_tiff::<tiff_color::RGBA8, _>(encoder, width, height, &buf, metadata)
+        }
+    }
+}
+
+fn write_tiff<C: TiffColorType, W: Write + Seek>(
+    encoder: &mut TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    data: &[C::Inner],
+    metadata: &Metadata,
+) -> tiff::TiffResult<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut image = encoder.new_image::<C>(width, height)?;
+    let directory = image.encoder();
+
+    // TIFF has no slot for a raw EXIF block, but its own IFD0 tags cover the text ones.
+    for (tag, text) in metadata.exif_text_tags() {
+        directory.write_tag(Tag::from_u16_exhaustive(tag), text.as_str())?;
+    }
+    if let Some(orientation) = metadata.exif_orientation() {
+        directory.write_tag(Tag::Orientation, orientation)?;
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // XMLPacket, which `tiff` has no named tag for.
+        directory.write_tag(Tag::Unknown(700), xmp.as_slice())?;
+    }
+    if let Some(icc) = &metadata.icc {
+        directory.write_tag(Tag::IccProfile, icc.as_slice())?;
+    }
+
+    image.write_data(data)
+}
+
+/// libwebp settings for still and animated WebP output.
+#[cfg(feature = "webp-lossy")]
+pub fn webp_config(options: &ConvertOptions) -> Result<webp::WebPConfig, ConvertError> {
+    let webp = &options.webp;
+
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| ConvertError::encoder(ImageFormat::WebP, "invalid libwebp version"))?;
+    config.quality = f32::from(options.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100));
+    config.method = i32::from(webp.method.min(6));
+    config.alpha_quality = i32::from(webp.alpha_quality.min(100));
+    if webp.lossless {
+        config.lossless = 1;
+        config.near_lossless = i32::from(webp.near_lossless.unwrap_or(100).min(100));
+    }
+
+    Ok(config)
+}
+
+#[cfg(feature = "webp-lossy")]
+fn encode_webp_libwebp(
+    img: &DynamicImage,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    let config = webp_config(options)?;
+
+    // libwebp only takes 8-bit RGB(A).
+    let (data, has_alpha) = if img.color().has_alpha() {
+        (img.to_rgba8().into_raw(), true)
+    } else {
+        (img.to_rgb8().into_raw(), false)
+    };
+    let encoder = if has_alpha {
+        webp::Encoder::from_rgba(&data, img.width(), img.height())
+    } else {
+        webp::Encoder::from_rgb(&data, img.width(), img.height())
+    };
+
+    let memory = encoder
+        .encode_advanced(&config)
+        .map_err(|e| ConvertError::encoder(ImageFormat::WebP, format!("{:?}", e)))?;
+
+    Ok(memory.to_vec())
+}
+
+#[cfg(not(feature = "webp-lossy"))]
+fn encode_webp_libwebp(
+    _img: &DynamicImage,
+    _options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    Err(ConvertError::InvalidOption(
+        "near-lossless WebP needs the `webp-lossy` feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "pnm")]
+fn encode_pnm(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    use crate::options::PnmFormat;
+    use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+
+    let encoding = if options.pnm.ascii { SampleEncoding::Ascii } else { SampleEncoding::Binary };
+    let color = img.color();
+    let eight_bit = color.bytes_per_pixel() == color.channel_count();
+    let format = match options.pnm.format {
+        PnmFormat::Auto if color.has_alpha() || !eight_bit => PnmFormat::Pam,
+        PnmFormat::Auto if color.has_color() => PnmFormat::Ppm,
+        PnmFormat::Auto => PnmFormat::Pgm,
+        format => format,
+    };
+
+    let (subtype, img) = match format {
+        PnmFormat::Pbm => {
+            // PBM stores 1 for black.
+            let mut bits = img.to_luma8();
+            bits.iter_mut().for_each(|v| *v = u8::from(*v < 128));
+            (PnmSubtype::Bitmap(encoding), Cow::Owned(DynamicImage::ImageLuma8(bits)))
+        }
+        PnmFormat::Pgm => {
+            (PnmSubtype::Graymap(encoding), Cow::Owned(DynamicImage::ImageLuma8(img.to_luma8())))
+        }
+        PnmFormat::Ppm | PnmFormat::Auto => {
+            (PnmSubtype::Pixmap(encoding), Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8())))
+        }
+        // Float pixels were tone mapped to 8-bit before encoding.
+        PnmFormat::Pam => (PnmSubtype::ArbitraryMap, Cow::Borrowed(img)),
+    };
+
+    let mut buffer = Vec::new();
+    PnmEncoder::new(&mut buffer)
+        .with_subtype(subtype)
+        .encode(img.as_bytes(), img.width(), img.height(), img.color().into())
+        .map_err(ConvertError::EncodeFailed)?;
+
+    Ok(buffer)
+}
+
+#[cfg(not(feature = "pnm"))]
+fn encode_pnm(_img: &DynamicImage, _options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    Err(ConvertError::UnsupportedTarget("pnm (needs the `pnm` feature)".to_string()))
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(img: &DynamicImage, options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    use ravif::{Img, RGB8, RGBA8};
+
+    let avif = &options.avif;
+    let encoder = ravif::Encoder::new()
+        .with_quality(f32::from(options.quality.unwrap_or(DEFAULT_QUALITY).clamp(1, 100)))
+        .with_alpha_quality(f32::from(avif.alpha_quality.clamp(1, 100)))
+        .with_speed(avif.speed.clamp(1, 10));
+    let (width, height) = (img.width() as usize, img.height() as usize);
+
+    // ravif takes 8-bit RGB(A); an opaque image is encoded without an alpha plane.
+    let encoded = if img.color().has_alpha() {
+        let pixels: Vec<RGBA8> =
+            img.to_rgba8().pixels().map(|p| RGBA8::new(p[0], p[1], p[2], p[3])).collect();
+        encoder.encode_rgba(Img::new(pixels.as_slice(), width, height))
+    } else {
+        let pixels: Vec<RGB8> =
+            img.to_rgb8().pixels().map(|p| RGB8::new(p[0], p[1], p[2])).collect();
+        encoder.encode_rgb(Img::new(pixels.as_slice(), width, height))
+    };
+
+    encoded
+        .map(|encoded| encoded.avif_file)
+        .map_err(|e| ConvertError::encoder(ImageFormat::Avif, e))
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_img: &DynamicImage, _options: &ConvertOptions) -> Result<Vec<u8>, ConvertError> {
+    Err(ConvertError::UnsupportedTarget("avif (needs the `avif` feature)".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{Dither, IcoOptions, Quantizer};
+    use image::{Rgb, RgbImage};
+
+    // A noisy gradient, so subsampling and quality actually change the output size.
+    fn sample_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, ((x * y) % 251) as u8])
+        }))
+    }
+
+    fn encode_sample(format: ImageFormat, options: &ConvertOptions) -> Vec<u8> {
+        encode_image(&sample_image(), format, options, &Metadata::default())
+            .expect("Encoding failed")
+    }
+
+    fn is_progressive(jpeg: &[u8]) -> bool {
+        // SOF2 marks a progressive frame, SOF0 a baseline one.
+        jpeg.windows(2).any(|w| w == [0xFF, 0xC2])
+    }
+
+    #[test]
+    fn jpeg_baseline_by_default() {
+        let output = encode_sample(ImageFormat::Jpeg, &ConvertOptions::default());
+        assert!(!is_progressive(&output));
+        assert!(image::load_from_memory_with_format(&output, ImageFormat::Jpeg).is_ok());
+    }
+
+    #[test]
+    fn jpeg_progressive_with_optimized_tables() {
+        let mut options = ConvertOptions::default();
+        options.jpeg.progressive = true;
+        options.jpeg.optimize_huffman = true;
+
+        let output = encode_sample(ImageFormat::Jpeg, &options);
+        assert!(is_progressive(&output));
+
+        let reloaded = image::load_from_memory_with_format(&output, ImageFormat::Jpeg)
+            .expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (64, 48));
+    }
+
+    #[test]
+    fn jpeg_full_chroma_is_larger_than_subsampled() {
+        let mut full = ConvertOptions::default();
+        full.jpeg.subsampling = Some(ChromaSubsampling::Yuv444);
+        let mut subsampled = ConvertOptions::default();
+        subsampled.jpeg.subsampling = Some(ChromaSubsampling::Yuv420);
+
+        let full_data = encode_sample(ImageFormat::Jpeg, &full);
+        let subsampled_data = encode_sample(ImageFormat::Jpeg, &subsampled);
+        assert!(subsampled_data.len() < full_data.len());
+    }
+
+    #[test]
+    fn png_higher_compression_level_is_smaller() {
+        let mut stored = ConvertOptions::default();
+        stored.png.compression_level = Some(0);
+        let mut best = ConvertOptions::default();
+        best.png.compression_level = Some(9);
+
+        let stored_data = encode_sample(ImageFormat::Png, &stored);
+        let best_data = encode_sample(ImageFormat::Png, &best);
+        assert!(best_data.len() < stored_data.len());
+    }
+
+    /// Palette indices of the first GIF frame, and its transparent index.
+    fn gif_indices(data: &[u8]) -> (Vec<u8>, Option<u8>) {
+        let mut decoder = gif::DecodeOptions::new();
+        decoder.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = decoder.read_info(Cursor::new(data)).expect("Invalid GIF");
+        let frame = decoder.read_next_frame().expect("Invalid frame").expect("No frame");
+        (frame.buffer.to_vec(), frame.transparent)
+    }
+
+    #[test]
+    fn gif_palette_size_and_dithering() {
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let mut options = ConvertOptions::default();
+            options.gif.colors = 8;
+            options.gif.quantizer = Quantizer::MedianCut;
+            options.gif.dither = dither;
+
+            let output = encode_sample(ImageFormat::Gif, &options);
+            let (mut indices, transparent) = gif_indices(&output);
+            indices.sort_unstable();
+            indices.dedup();
+            assert!(indices.len() <= 8 && indices.len() > 1, "{:?}: {:?}", dither, indices);
+            assert_eq!(transparent, None);
+
+            let reloaded = image::load_from_memory_with_format(&output, ImageFormat::Gif)
+                .expect("Failed to reload output");
+            assert_eq!((reloaded.width(), reloaded.height()), (64, 48));
+        }
+    }
+
+    #[test]
+    fn gif_transparency_follows_the_alpha_threshold() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 1, |x, _| {
+            image::Rgba([200, 10, 10, (x * 80) as u8])
+        }));
+        let encode = |threshold| {
+            let mut options = ConvertOptions::default();
+            options.gif.alpha_threshold = threshold;
+            let data = encode_image(&img, ImageFormat::Gif, &options, &Metadata::default())
+                .expect("Encoding failed");
+            gif_indices(&data)
+        };
+
+        // Alpha 0, 80, 160, 240: the first two fall below the default threshold of 128.
+        let (indices, transparent) = encode(128);
+        assert_eq!(transparent, Some(1));
+        assert_eq!(indices, vec![1, 1, 0, 0]);
+
+        let (indices, transparent) = encode(0);
+        assert_eq!(transparent, None);
+        assert_eq!(indices, vec![0; 4]);
+    }
+
+    /// (width, height, starts with the PNG signature) of every ICO directory entry.
+    fn ico_entries(ico: &[u8]) -> Vec<(u8, u8, bool)> {
+        let count = usize::from(u16::from_le_bytes([ico[4], ico[5]]));
+        (0..count)
+            .map(|i| {
+                let entry = &ico[6 + i * 16..6 + (i + 1) * 16];
+                let offset = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+                (entry[0], entry[1], ico[offset..].starts_with(b"\x89PNG"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ico_holds_every_size_as_png_or_bmp() {
+        let mut options = ConvertOptions::default();
+        options.ico = IcoOptions::favicon();
+        options.ico.png_min_size = 64;
+
+        let output = encode_sample(ImageFormat::Ico, &options);
+        // 256 is stored as 0.
+        let expected =
+            [16, 24, 32, 48, 64, 128, 0].map(|size| (size, size, size >= 64 || size == 0));
+        assert_eq!(ico_entries(&output), expected);
+        let reloaded = image::load_from_memory_with_format(&output, ImageFormat::Ico)
+            .expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (256, 256));
+
+        // A lone BMP entry decodes back to the padded, resampled image.
+        options.ico.sizes = vec![16];
+        let output = encode_sample(ImageFormat::Ico, &options);
+        assert_eq!(ico_entries(&output), [(16, 16, false)]);
+        let reloaded = image::load_from_memory_with_format(&output, ImageFormat::Ico)
+            .expect("Failed to reload output")
+            .to_rgba8();
+        assert_eq!(reloaded.dimensions(), (16, 16));
+        // 64x48 fits as 16x12, leaving two transparent rows above and below.
+        assert_eq!(reloaded.get_pixel(8, 0)[3], 0);
+        assert_eq!(reloaded.get_pixel(8, 8)[3], 255);
+
+        options.ico.sizes = vec![16, 512];
+        let result =
+            encode_image(&sample_image(), ImageFormat::Ico, &options, &Metadata::default());
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    fn is_lossy_webp(webp: &[u8]) -> bool {
+        // Lossy bitstreams live in a "VP8 " chunk, lossless ones in "VP8L".
+        webp.windows(4).any(|w| w == b"VP8 ")
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_quality_and_alpha() {
+        let mut options = ConvertOptions::default();
+        // The fastest preset keeps this bearable in debug builds.
+        options.avif.speed = 10;
+        options.quality = Some(90);
+        let high = encode_sample(ImageFormat::Avif, &options);
+        options.quality = Some(20);
+        let low = encode_sample(ImageFormat::Avif, &options);
+
+        assert_eq!(image::guess_format(&high).unwrap(), ImageFormat::Avif);
+        assert!(low.len() < high.len());
+
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::from_fn(16, 16, |x, _| {
+            image::Rgba([200, 30, 30, (x * 16) as u8])
+        }));
+        let with_alpha =
+            encode_image(&transparent, ImageFormat::Avif, &options, &Metadata::default()).unwrap();
+        // The alpha plane is a second AV1 item, flagged with an "auxC" alpha property.
+        assert!(with_alpha.windows(4).any(|w| w == b"auxC"));
+        assert!(!low.windows(4).any(|w| w == b"auxC"));
+    }
+
+    #[test]
+    fn tga_rle_is_optional() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, _| {
+            image::Rgba([if x < 16 { 255 } else { 0 }, 40, 90, 200])
+        }));
+        let mut options = ConvertOptions::default();
+        let rle = encode_image(&img, ImageFormat::Tga, &options, &Metadata::default()).unwrap();
+        options.tga.rle = false;
+        let raw = encode_image(&img, ImageFormat::Tga, &options, &Metadata::default()).unwrap();
+
+        // Image type 10 is run-length encoded truecolour, 2 uncompressed.
+        assert_eq!((rle[2], raw[2]), (10, 2));
+        assert!(rle.len() < raw.len() / 4);
+        for data in [rle, raw] {
+            let decoded = image::load_from_memory_with_format(&data, ImageFormat::Tga).unwrap();
+            assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+        }
+
+        // 16-bit input is written as 8-bit.
+        let deep = DynamicImage::ImageRgb16(image::ImageBuffer::new(4, 4));
+        let data = encode_image(&deep, ImageFormat::Tga, &options, &Metadata::default()).unwrap();
+        assert_eq!(data[16], 24);
+    }
+
+    #[test]
+    fn tiff_compression_round_trips() {
+        // Flat gray bands have byte runs, so even PackBits shrinks them.
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |_, y| {
+            Rgb([(y / 8 * 40) as u8; 3])
+        }));
+        let encode = |compression, predictor| {
+            let mut options = ConvertOptions::default();
+            options.tiff.compression = compression;
+            options.tiff.predictor = predictor;
+            encode_image(&img, ImageFormat::Tiff, &options, &Metadata::default()).unwrap()
+        };
+
+        let raw = encode(TiffCompression::None, false);
+        for (compression, tag) in [
+            (TiffCompression::Lzw, 5),
+            (TiffCompression::Deflate, 8),
+            (TiffCompression::PackBits, 32773),
+        ] {
+            for predictor in [false, true] {
+                let data = encode(compression, predictor);
+                assert!(data.len() < raw.len(), "{:?} is not smaller", compression);
+                let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&data)).unwrap();
+                assert_eq!(decoder.get_tag_u32(Tag::Compression).unwrap(), tag);
+                let decoded = image::load_from_memory(&data).unwrap();
+                assert_eq!(decoded.to_rgb8(), img.to_rgb8(), "{:?}", compression);
+            }
+        }
+    }
+
+    #[test]
+    fn tiff_writes_every_page() {
+        let pages = [
+            sample_image(),
+            DynamicImage::ImageRgba32F(image::Rgba32FImage::new(3, 2)),
+            DynamicImage::ImageLuma16(image::ImageBuffer::new(5, 5)),
+        ];
+        let mut options = ConvertOptions::default();
+        options.tiff.compression = TiffCompression::Lzw;
+        options.tiff.predictor = true;
+        let data = encode_tiff(&pages, &options, &Metadata::default()).unwrap();
+
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&data)).unwrap();
+        let mut sizes = vec![decoder.dimensions().unwrap()];
+        while decoder.more_images() {
+            decoder.next_image().unwrap();
+            sizes.push(decoder.dimensions().unwrap());
+        }
+        assert_eq!(sizes, [(64, 48), (3, 2), (5, 5)]);
+    }
+
+    #[test]
+    fn webp_lossless_when_requested() {
+        let mut options = ConvertOptions::default();
+        options.webp.lossless = true;
+
+        let output = encode_sample(ImageFormat::WebP, &options);
+        assert!(!is_lossy_webp(&output));
+
+        let reloaded = image::load_from_memory_with_format(&output, ImageFormat::WebP)
+            .expect("Failed to reload output");
+        assert_eq!(reloaded.to_rgb8(), sample_image().to_rgb8());
+    }
+
+    #[test]
+    fn webp_lossy_by_default() {
+        let mut low = ConvertOptions::default();
+        low.quality = Some(10);
+
+        let default_data = encode_sample(ImageFormat::WebP, &ConvertOptions::default());
+        let low_data = encode_sample(ImageFormat::WebP, &low);
+
+        assert!(is_lossy_webp(&default_data));
+        assert!(low_data.len() < default_data.len());
+        assert!(image::load_from_memory_with_format(&low_data, ImageFormat::WebP).is_ok());
+    }
+
+    #[cfg(feature = "webp-lossy")]
+    #[test]
+    fn webp_near_lossless_stays_vp8l() {
+        let mut options = ConvertOptions::default();
+        options.webp.lossless = true;
+        options.webp.near_lossless = Some(60);
+
+        let output = encode_sample(ImageFormat::WebP, &options);
+        assert!(!is_lossy_webp(&output));
+        assert!(image::load_from_memory_with_format(&output, ImageFormat::WebP).is_ok());
+    }
+}
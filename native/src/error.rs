@@ -0,0 +1,183 @@
+use image::error::{DecodingError, EncodingError, ImageFormatHint};
+use image::{ImageError, ImageFormat};
+use std::fmt;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// ----------------------------------------------------------------
+// Errors
+// ----------------------------------------------------------------
+
+/// Everything that can go wrong while converting an image.
+///
+/// `code()` and `stage()` are stable identifiers meant for the UI to branch on (and to
+/// localize), `Display` is a human readable English message.
+#[derive(Debug)]
+pub enum ConvertError {
+    /// The input bytes are not in any format we recognize.
+    DetectFailed(ImageError),
+    /// The format was recognized but the data could not be decoded.
+    DecodeFailed(ImageError),
+    /// The requested output format is not one we can write.
+    UnsupportedTarget(String),
+    /// A `ConvertOptions` value is out of range or inconsistent.
+    InvalidOption(String),
+    /// The encoder rejected the image.
+    EncodeFailed(ImageError),
+    /// Decoding would exceed the configured dimension or memory limits.
+    LimitExceeded(ImageError),
+}
+
+impl ConvertError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConvertError::DetectFailed(_) => "detect_failed",
+            ConvertError::DecodeFailed(_) => "decode_failed",
+            ConvertError::UnsupportedTarget(_) => "unsupported_target",
+            ConvertError::InvalidOption(_) => "invalid_option",
+            ConvertError::EncodeFailed(_) => "encode_failed",
+            ConvertError::LimitExceeded(_) => "limit_exceeded",
+        }
+    }
+
+    /// The pipeline step that failed: "detect", "decode", "options" or "encode".
+    pub fn stage(&self) -> &'static str {
+        match self {
+            ConvertError::DetectFailed(_) => "detect",
+            ConvertError::DecodeFailed(_) | ConvertError::LimitExceeded(_) => "decode",
+            ConvertError::UnsupportedTarget(_) | ConvertError::InvalidOption(_) => "options",
+            ConvertError::EncodeFailed(_) => "encode",
+        }
+    }
+
+    /// Wraps an error coming from an encoder crate other than `image` itself.
+    pub fn encoder(
+        format: ImageFormat,
+        err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> ConvertError {
+        ConvertError::EncodeFailed(ImageError::Encoding(EncodingError::new(
+            ImageFormatHint::Exact(format),
+            err,
+        )))
+    }
+
+    /// Wraps an error coming from a decoder crate other than `image` itself.
+    pub fn decoder(
+        format: ImageFormat,
+        err: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> ConvertError {
+        ConvertError::DecodeFailed(ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(format),
+            err,
+        )))
+    }
+
+    /// Maps a decoder error, keeping limit violations distinguishable from corrupt data.
+    pub fn from_decode(err: ImageError) -> ConvertError {
+        match err {
+            ImageError::Limits(_) => ConvertError::LimitExceeded(err),
+            _ => ConvertError::DecodeFailed(err),
+        }
+    }
+}
+
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvertError::DetectFailed(e) => write!(f, "Failed to detect format: {}", e),
+            ConvertError::DecodeFailed(e) => write!(f, "Failed to decode image: {}", e),
+            ConvertError::UnsupportedTarget(fmt) => {
+                write!(f, "Unsupported output format requested: {}", fmt)
+            }
+            ConvertError::InvalidOption(msg) => write!(f, "Invalid option: {}", msg),
+            ConvertError::EncodeFailed(e) => write!(f, "Failed to write output: {}", e),
+            ConvertError::LimitExceeded(e) => write!(f, "Image exceeds decoding limits: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConvertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConvertError::DetectFailed(e)
+            | ConvertError::DecodeFailed(e)
+            | ConvertError::EncodeFailed(e)
+            | ConvertError::LimitExceeded(e) => Some(e),
+            ConvertError::UnsupportedTarget(_) | ConvertError::InvalidOption(_) => None,
+        }
+    }
+}
+
+// ----------------------------------------------------------------
+// JS facing error object
+// ----------------------------------------------------------------
+
+/// What JavaScript receives when a conversion throws.
+#[wasm_bindgen(js_name = ConvertError)]
+pub struct JsConvertError {
+    code: String,
+    message: String,
+    stage: String,
+    detail: Option<String>,
+}
+
+#[wasm_bindgen(js_class = ConvertError)]
+impl JsConvertError {
+    /// Stable machine readable identifier, e.g. "decode_failed".
+    #[wasm_bindgen(getter)]
+    pub fn code(&self) -> String {
+        self.code.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stage(&self) -> String {
+        self.stage.clone()
+    }
+
+    /// The underlying codec error, when there is one.
+    #[wasm_bindgen(getter)]
+    pub fn detail(&self) -> Option<String> {
+        self.detail.clone()
+    }
+}
+
+impl From<ConvertError> for JsConvertError {
+    fn from(err: ConvertError) -> Self {
+        use std::error::Error;
+
+        JsConvertError {
+            code: err.code().to_string(),
+            message: err.to_string(),
+            stage: err.stage().to_string(),
+            detail: err.source().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::error::{LimitError, LimitErrorKind};
+
+    #[test]
+    fn limit_errors_are_reported_separately() {
+        let err = ConvertError::from_decode(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+        assert_eq!(err.code(), "limit_exceeded");
+        assert_eq!(err.stage(), "decode");
+    }
+
+    #[test]
+    fn js_error_carries_code_stage_and_detail() {
+        let js_err = JsConvertError::from(ConvertError::UnsupportedTarget("xyz".to_string()));
+        assert_eq!(js_err.code(), "unsupported_target");
+        assert_eq!(js_err.stage(), "options");
+        assert_eq!(js_err.message(), "Unsupported output format requested: xyz");
+        assert_eq!(js_err.detail(), None);
+    }
+}
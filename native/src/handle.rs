@@ -0,0 +1,95 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+use crate::error::JsConvertError;
+use crate::image_converter::{decode_pure, encode_source, Source};
+use crate::options::ConvertOptions;
+use crate::report::ConvertResult;
+
+// ----------------------------------------------------------------
+// Decoded image handle for JavaScript
+// ----------------------------------------------------------------
+
+/// An upload decoded once and kept in WASM memory, so previews and several exports do not
+/// pay for decoding again. Call `free()` when done: the pixels are not garbage collected.
+///
+/// From JavaScript:
+/// ```js
+/// const image = new Image(bytes);
+/// const preview = image.resize(320, 320);
+/// const thumb = preview.encode("webp").data;
+/// const full = image.encode("png").data;
+/// preview.free();
+/// image.free();
+/// ```
+#[wasm_bindgen(js_name = Image)]
+pub struct ImageHandle {
+    source: Source,
+}
+
+#[wasm_bindgen(js_class = Image)]
+impl ImageHandle {
+    /// Decodes `input_data`. Only the decode side of `options` applies here: `metadata`,
+    /// `icc`, `auto_orient`, `keep_animation`, `keep_pages` and `limits`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        input_data: &[u8],
+        options: Option<ConvertOptions>,
+    ) -> Result<ImageHandle, JsConvertError> {
+        let options = options.unwrap_or_default();
+
+        decode_pure(input_data, &options)
+            .map(|source| ImageHandle { source })
+            .map_err(JsConvertError::from)
+    }
+
+    /// Detected input format, e.g. "png".
+    #[wasm_bindgen(getter)]
+    pub fn format(&self) -> String {
+        self.source.format_name().to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.source.dimensions().0
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.source.dimensions().1
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn frames(&self) -> usize {
+        self.source.frame_count()
+    }
+
+    /// Encodes the image like `convert_image_with_report` would. The image itself is left
+    /// untouched, so this can be called again for other targets or settings.
+    pub fn encode(
+        &self,
+        target_format_str: &str,
+        options: Option<ConvertOptions>,
+    ) -> Result<ConvertResult, JsConvertError> {
+        let options = options.unwrap_or_default();
+
+        encode_source(&self.source, target_format_str, &options)
+            .map(ConvertResult::from)
+            .map_err(JsConvertError::from)
+    }
+
+    /// A new handle resized to `width` x `height`; either side may be omitted. `options`
+    /// picks the `fit`, `never_upscale` and `filter` (by default the image is scaled to fit
+    /// inside the box), and its `limits` cap the new size. This handle is left as it is.
+    pub fn resize(
+        &self,
+        width: Option<u32>,
+        height: Option<u32>,
+        options: Option<ConvertOptions>,
+    ) -> Result<ImageHandle, JsConvertError> {
+        let options = options.unwrap_or_default();
+
+        self.source
+            .resized(width, height, &options)
+            .map(|source| ImageHandle { source })
+            .map_err(JsConvertError::from)
+    }
+}
@@ -1,111 +1,692 @@
 use image::imageops::FilterType;
-use image::codecs::tga::TgaDecoder;
-use image::{ImageFormat, Rgba, RgbaImage};
+use image::metadata::Orientation;
+use image::{DynamicImage, ExtendedColorType, Frame, ImageDecoder, ImageFormat, ImageReader};
 use std::io::Cursor;
+use crate::animation::{
+    count_frames, decode_animation, delay_ms, encode_animation, supports_animation, Animation,
+};
+use crate::background::flatten;
+use crate::linear::{to_linear_rgb32f, tone_map};
+use crate::color::ProfileAction;
+use crate::encoders::{encode_image, encode_tiff};
+use crate::error::ConvertError;
+use crate::metadata::Metadata;
+use crate::options::{ConvertOptions, IccHandling, MetadataPolicy};
+use crate::pages::{count_tiff_pages, decode_tiff_pages};
+use crate::resize::{resize, resize_to};
+use crate::report::{ConvertReport, ConvertWarning, ExtractedFrame, ImageInfo};
+use crate::tga::{tga_confidence, MIN_TGA_CONFIDENCE};
+use crate::transform::apply_transforms;
 
 // ----------------------------------------------------------------
 // 1. Pure Rust Implementation
 // ----------------------------------------------------------------
 
-fn detect_image_format(input_data: &[u8]) -> Result<ImageFormat, String> {
+fn detect_image_format(input_data: &[u8]) -> Result<ImageFormat, ConvertError> {
+    detect_with_confidence(input_data).map(|(format, _)| format)
+}
+
+/// The input format and how sure the guess is (0-100). Formats with a magic number are
+/// certain; TGA has none and is scored from its header.
+fn detect_with_confidence(input_data: &[u8]) -> Result<(ImageFormat, u8), ConvertError> {
     match image::guess_format(input_data) {
-        Ok(fmt) => Ok(fmt),
+        Ok(fmt) => Ok((fmt, 100)),
         Err(primary_err) => {
-            // TGA lacks a magic number; try decoding to recognize it.
-            let mut cursor = Cursor::new(input_data);
-            if TgaDecoder::new(&mut cursor).is_ok() {
-                return Ok(ImageFormat::Tga);
+            let confidence = tga_confidence(input_data);
+            if confidence >= MIN_TGA_CONFIDENCE {
+                return Ok((ImageFormat::Tga, confidence));
             }
 
-            Err(format!("Failed to detect format: {}", primary_err))
+            Err(ConvertError::DetectFailed(primary_err))
         }
     }
 }
-pub fn convert_image_pure(input_data: &[u8], target_format_str: &str) -> Result<Vec<u8>, String> {
+pub fn convert_image_pure(
+    input_data: &[u8],
+    target_format_str: &str,
+) -> Result<Vec<u8>, ConvertError> {
+    convert_image_with_options(input_data, target_format_str, &ConvertOptions::default())
+}
+
+pub fn convert_image_with_options(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: &ConvertOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    convert_image_detailed(input_data, target_format_str, options).map(|report| report.data)
+}
+
+/// Like `convert_image_with_options`, but also reports frame counts and warnings.
+pub fn convert_image_detailed(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: &ConvertOptions,
+) -> Result<ConvertReport, ConvertError> {
     // A. Guess the format
     let detected_format = detect_image_format(input_data)?;
 
-    // B. Decode
-    let mut img = image::load_from_memory_with_format(input_data, detected_format)
-        .map_err(|e| format!("Failed to decode image: {}", e))?;
-
-    // C. Determine Output Format
-    let output_format = match target_format_str.to_lowercase().as_str() {
-        "png" => ImageFormat::Png,
-        "jpeg" | "jpg" => ImageFormat::Jpeg,
-        "gif" => ImageFormat::Gif,
-        "webp" => ImageFormat::WebP,
-        "bmp" => ImageFormat::Bmp,
-        "ico" => ImageFormat::Ico,
-        "tiff" => ImageFormat::Tiff,
-        "tga" => ImageFormat::Tga,
-        "ff" | "farbfeld" => ImageFormat::Farbfeld,
-        f => return Err(format!("Unsupported output format requested: {}", f)),
+    // B. Determine Output Format
+    let output_format = parse_target_format(target_format_str)?;
+
+    // C. Decode (every frame or page when the input has several and the target keeps them)
+    let source =
+        decode_source(input_data, detected_format, Some(&[output_format]), false, options)?;
+
+    // D. + E. Format specific adjustments and encoding
+    encode_for_target(source, output_format, options)
+}
+
+/// Decodes the input once and encodes it to every format in `target_format_strs`, in the
+/// same order. Each target is adjusted on its own copy of the decoded pixels. Fails as a
+/// whole if any target fails.
+pub fn convert_image_multi_pure<S: AsRef<str>>(
+    input_data: &[u8],
+    target_format_strs: &[S],
+    options: &ConvertOptions,
+) -> Result<Vec<ConvertReport>, ConvertError> {
+    let detected_format = detect_image_format(input_data)?;
+    let output_formats = target_format_strs
+        .iter()
+        .map(|target| parse_target_format(target.as_ref()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some((&last, rest)) = output_formats.split_last() else {
+        return Err(ConvertError::InvalidOption("no target formats given".to_string()));
     };
 
-    // --- FIX: Handle Transparency for JPEG ---
-    // If target is JPEG and input image has transparency, blend it onto a white background.
-    if output_format == ImageFormat::Jpeg && img.color().has_alpha() {
-        let rgba = img.to_rgba8();
+    let source =
+        decode_source(input_data, detected_format, Some(&output_formats), false, options)?;
+    let mut reports = Vec::with_capacity(output_formats.len());
+    for &output_format in rest {
+        reports.push(encode_for_target(source.clone(), output_format, options)?);
+    }
+    // The last target can have the decoded pixels themselves.
+    reports.push(encode_for_target(source, last, options)?);
+    Ok(reports)
+}
+
+/// Decodes the input for any number of later `encode_source` calls. Only the decode side
+/// of `options` matters here: metadata policy, ICC handling, orientation, animation, pages
+/// and limits.
+pub fn decode_pure(input_data: &[u8], options: &ConvertOptions) -> Result<Source, ConvertError> {
+    let detected_format = detect_image_format(input_data)?;
+    // The targets are not known yet, so everything `options` asks for is kept.
+    decode_source(input_data, detected_format, None, false, options)
+}
+
+/// Encodes a copy of an input decoded by `decode_pure`, using the encode side of `options`
+/// (quality, size, background, per-format settings).
+pub fn encode_source(
+    source: &Source,
+    target_format_str: &str,
+    options: &ConvertOptions,
+) -> Result<ConvertReport, ConvertError> {
+    let output_format = parse_target_format(target_format_str)?;
+    encode_for_target(source.clone(), output_format, options)
+}
+
+/// Encodes the frames selected by `options.frame_start`/`frame_end` as separate images.
+/// Still inputs are treated as a single frame with no delay.
+pub fn extract_frames_pure(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: &ConvertOptions,
+) -> Result<Vec<ExtractedFrame>, ConvertError> {
+    let detected_format = detect_image_format(input_data)?;
+    let output_format = parse_target_format(target_format_str)?;
+
+    // Frames are pulled out whether or not `keep_animation` is set. The pages of a
+    // multi-page TIFF count as frames with no delay.
+    let source = decode_source(input_data, detected_format, None, true, options)?;
+    let (metadata, profile) = source.target_profile(output_format);
+    let frames: Vec<(DynamicImage, u32)> = match source.pixels {
+        SourcePixels::Animated(animation) => animation
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let delay = delay_ms(&frame);
+                (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+            })
+            .collect(),
+        SourcePixels::Pages(pages) => pages.into_iter().map(|page| (page, 0)).collect(),
+        SourcePixels::Still(img) => vec![(img, 0)],
+    };
+
+    let last = frames.len() as u32 - 1;
+    let start = options.frame_start.unwrap_or(0);
+    let end = options.frame_end.unwrap_or(last).min(last);
+    if start > end {
+        return Err(ConvertError::InvalidOption(format!(
+            "frame range {}..={} is empty; the input has {} frame(s)",
+            start,
+            end,
+            frames.len()
+        )));
+    }
+
+    let mut timestamp_ms = 0u32;
+    let mut extracted = Vec::new();
+    for (index, (img, delay)) in (0u32..).zip(frames) {
+        if (start..=end).contains(&index) {
+            let img = prepare_for_target(profile.apply(img), output_format, options)?;
+            let data = encode_image(&img, output_format, options, &metadata)?;
+            extracted.push(ExtractedFrame::new(index, delay, timestamp_ms, data));
+        }
+        timestamp_ms = timestamp_ms.saturating_add(delay);
+    }
+
+    Ok(extracted)
+}
+
+/// An input decoded once, before anything target specific happens.
+#[derive(Clone)]
+pub struct Source {
+    format: ImageFormat,
+    pixels: SourcePixels,
+    /// What `options.metadata` lets through, not yet tagged for a target.
+    metadata: Metadata,
+    /// The ICC step decided for this input. Any conversion to sRGB has already been done.
+    profile: ProfileAction,
+    /// Set when the input has several pages or frames but only the first was decoded,
+    /// because none of the targets could hold the rest.
+    flattened: Option<ConvertWarning>,
+}
+
+#[derive(Clone)]
+enum SourcePixels {
+    Still(DynamicImage),
+    Animated(Animation),
+    /// Every page of a multi-page TIFF, each with its own size and colour type.
+    Pages(Vec<DynamicImage>),
+}
+
+impl Source {
+    /// The detected input format, spelled like the `convert_image` targets.
+    pub fn format_name(&self) -> &'static str {
+        format_name(self.format)
+    }
 
-        // 2. Create an RGBA background (White + Fully Opaque)
-        // We use Rgba([255, 255, 255, 255]) so the types match the source image.
-        let mut background =
-            RgbaImage::from_pixel(rgba.width(), rgba.height(), Rgba([255, 255, 255, 255]));
+    pub fn dimensions(&self) -> (u32, u32) {
+        match &self.pixels {
+            SourcePixels::Still(img) => (img.width(), img.height()),
+            SourcePixels::Animated(animation) => animation.frames[0].buffer().dimensions(),
+            SourcePixels::Pages(pages) => (pages[0].width(), pages[0].height()),
+        }
+    }
 
-        // 3. Overlay RGBA onto RGBA (Types now match, so no error)
-        image::imageops::overlay(&mut background, &rgba, 0, 0);
+    /// 1 for still images, and for animations decoded with `keep_animation` off. Pages of
+    /// a multi-page TIFF count as frames.
+    pub fn frame_count(&self) -> usize {
+        match &self.pixels {
+            SourcePixels::Still(_) => 1,
+            SourcePixels::Animated(animation) => animation.frames.len(),
+            SourcePixels::Pages(pages) => pages.len(),
+        }
+    }
 
-        // 4. Update the image to the new blended version
-        // When writing to JPEG, the encoder will ignore the alpha channel,
-        // leaving you with the RGB values blended on white.
-        img = image::DynamicImage::ImageRgba8(background);
+    /// A copy resized to `width` x `height` (either may be `None`) with the `fit`,
+    /// `never_upscale` and `filter` settings of `options`. Every frame of an animation and
+    /// every page is resized. Fails with `LimitExceeded` when a frame would break
+    /// `options.limits`.
+    pub fn resized(
+        &self,
+        width: Option<u32>,
+        height: Option<u32>,
+        options: &ConvertOptions,
+    ) -> Result<Source, ConvertError> {
+        let pixels = match &self.pixels {
+            SourcePixels::Still(img) => {
+                SourcePixels::Still(resize_to(img.clone(), width, height, options)?)
+            }
+            SourcePixels::Animated(animation) => {
+                let frames = animation
+                    .frames
+                    .iter()
+                    .map(|frame| {
+                        let img = DynamicImage::ImageRgba8(frame.buffer().clone());
+                        let img = resize_to(img, width, height, options)?;
+                        Ok(Frame::from_parts(img.into_rgba8(), 0, 0, frame.delay()))
+                    })
+                    .collect::<Result<_, ConvertError>>()?;
+                SourcePixels::Animated(Animation { frames, loop_count: animation.loop_count })
+            }
+            SourcePixels::Pages(pages) => SourcePixels::Pages(
+                pages
+                    .iter()
+                    .map(|page| resize_to(page.clone(), width, height, options))
+                    .collect::<Result<_, _>>()?,
+            ),
+        };
+        Ok(Source {
+            format: self.format,
+            pixels,
+            metadata: self.metadata.clone(),
+            profile: self.profile.clone(),
+            flattened: self.flattened.clone(),
+        })
+    }
+
+    /// The metadata one target gets, and what is left to do with the pixels' profile.
+    fn target_profile(&self, output_format: ImageFormat) -> (Metadata, ProfileAction) {
+        let profile = self.profile.for_target(output_format);
+        let mut metadata = self.metadata.clone();
+        profile.tag(&mut metadata);
+        (metadata, profile)
+    }
+}
+
+/// Decodes the input. Every frame of an animation and every page of a multi-page TIFF is
+/// kept when `options.keep_animation`/`keep_pages` asks for it and one of `outputs` can
+/// hold them, or always with `every_frame`. `None` for `outputs` stands for any target.
+/// When the rest is skipped only the first frame or page is decoded, and `flattened` says so.
+fn decode_source(
+    input_data: &[u8],
+    format: ImageFormat,
+    outputs: Option<&[ImageFormat]>,
+    every_frame: bool,
+    options: &ConvertOptions,
+) -> Result<Source, ConvertError> {
+    let any_output = |wanted: fn(ImageFormat) -> bool| {
+        outputs.is_none_or(|outputs| outputs.iter().any(|&output| wanted(output)))
+    };
+    let mut flattened = None;
+
+    let keep_pages =
+        every_frame || (options.keep_pages && any_output(|output| output == ImageFormat::Tiff));
+    if format == ImageFormat::Tiff
+        && keep_pages
+        && let Some(pages) = decode_tiff_pages(input_data, &options.limits, options.auto_orient)?
+    {
+        let (metadata, profile) = read_metadata(input_data, format, options, options.auto_orient);
+        let pages = pages.into_iter().map(|page| profile.apply(page)).collect();
+        let pixels = SourcePixels::Pages(pages);
+        return Ok(Source { format, pixels, metadata, profile, flattened });
+    }
+    if format == ImageFormat::Tiff && options.keep_pages && !keep_pages {
+        // Only the page count is read; the other pages are never decoded.
+        let pages = count_tiff_pages(input_data) as usize;
+        flattened = (pages > 1).then_some(ConvertWarning::PagesFlattened { pages });
+    }
+
+    let keep_animation =
+        every_frame || (options.keep_animation && any_output(supports_animation));
+    let animation = if keep_animation {
+        decode_animation(input_data, format, &options.limits)?
+    } else {
+        None
+    };
+    if options.keep_animation && !keep_animation && format != ImageFormat::Tiff {
+        // As with pages: the frames are counted, only the first one is decoded.
+        let frames = count_frames(input_data, format) as usize;
+        flattened = (frames > 1).then_some(ConvertWarning::AnimationFlattened { frames });
+    }
+
+    match animation {
+        Some(mut animation) => {
+            let (metadata, profile) = read_metadata(input_data, format, options, false);
+            animation.frames = animation
+                .frames
+                .into_iter()
+                .map(|frame| {
+                    let delay = frame.delay();
+                    let img = profile.apply(DynamicImage::ImageRgba8(frame.into_buffer()));
+                    Frame::from_parts(img.into_rgba8(), 0, 0, delay)
+                })
+                .collect();
+            let pixels = SourcePixels::Animated(animation);
+            Ok(Source { format, pixels, metadata, profile, flattened })
+        }
+        None => {
+            let (img, metadata, profile) = decode_image(input_data, format, options)?;
+            Ok(Source { format, pixels: SourcePixels::Still(img), metadata, profile, flattened })
+        }
+    }
+}
+
+/// Applies everything `output_format` needs to the decoded input and encodes it.
+fn encode_for_target(
+    source: Source,
+    output_format: ImageFormat,
+    options: &ConvertOptions,
+) -> Result<ConvertReport, ConvertError> {
+    let (metadata, profile) = source.target_profile(output_format);
+
+    let mut warnings: Vec<ConvertWarning> = source.flattened.into_iter().collect();
+    let palette = match output_format {
+        ImageFormat::Gif => Some(options.gif.quantize_settings()),
+        ImageFormat::Bmp => options.bmp.quantize_settings(),
+        _ => None,
+    };
+    if let Some(settings) = palette
+        && settings.replaces_neuquant()
+    {
+        let colors = settings.colors.clamp(2, 256);
+        warnings.push(ConvertWarning::QuantizerReplaced { colors });
+    }
+    let img = match source.pixels {
+        SourcePixels::Animated(animation) if supports_animation(output_format) => {
+            let frames = animation
+                .frames
+                .into_iter()
+                .map(|frame| {
+                    let delay = frame.delay();
+                    let img = prepare_for_target(
+                        profile.apply(DynamicImage::ImageRgba8(frame.into_buffer())),
+                        output_format,
+                        options,
+                    )?;
+                    Ok(Frame::from_parts(img.into_rgba8(), 0, 0, delay))
+                })
+                .collect::<Result<Vec<_>, ConvertError>>()?;
+            let frame_count = frames.len();
+
+            let animation = Animation { frames, loop_count: animation.loop_count };
+            let data = encode_animation(animation, output_format, options, &metadata)?;
+            return Ok(ConvertReport { data, frames: frame_count, warnings });
+        }
+        SourcePixels::Animated(mut animation) => {
+            // The target cannot animate: fall back to the first frame and say so.
+            warnings.push(ConvertWarning::AnimationFlattened { frames: animation.frames.len() });
+            let frame = animation.frames.swap_remove(0);
+            DynamicImage::ImageRgba8(frame.into_buffer())
+        }
+        SourcePixels::Pages(pages) if output_format == ImageFormat::Tiff => {
+            let pages = pages
+                .into_iter()
+                .map(|page| prepare_for_target(profile.apply(page), output_format, options))
+                .collect::<Result<Vec<_>, ConvertError>>()?;
+            let data = encode_tiff(&pages, options, &metadata)?;
+            return Ok(ConvertReport { data, frames: pages.len(), warnings });
+        }
+        SourcePixels::Pages(mut pages) => {
+            // The target holds a single image: fall back to the first page and say so.
+            warnings.push(ConvertWarning::PagesFlattened { pages: pages.len() });
+            pages.swap_remove(0)
+        }
+        SourcePixels::Still(img) => img,
+    };
+
+    // D. Format specific adjustments
+    let img = prepare_for_target(profile.apply(img), output_format, options)?;
+
+    // E. Encode
+    let data = encode_image(&img, output_format, options, &metadata)?;
+    Ok(ConvertReport { data, frames: 1, warnings })
+}
+
+/// Decodes each of `inputs` and writes them as the pages of one TIFF, in order. Every page
+/// of a multi-page input is kept (unless `keep_pages` is off); animations contribute their
+/// first frame. Metadata comes from the first input.
+pub fn merge_to_tiff_pure<B: AsRef<[u8]>>(
+    inputs: &[B],
+    options: &ConvertOptions,
+) -> Result<ConvertReport, ConvertError> {
+    let mut metadata = None;
+    let mut pages = Vec::new();
+    let mut warnings = Vec::new();
+    for input in inputs {
+        let input = input.as_ref();
+        let format = detect_image_format(input)?;
+        let source = decode_source(input, format, Some(&[ImageFormat::Tiff]), false, options)?;
+        let (source_metadata, profile) = source.target_profile(ImageFormat::Tiff);
+        metadata.get_or_insert(source_metadata);
+        warnings.extend(source.flattened);
+
+        let images = match source.pixels {
+            SourcePixels::Still(img) => vec![img],
+            SourcePixels::Pages(pages) => pages,
+            SourcePixels::Animated(mut animation) => {
+                warnings
+                    .push(ConvertWarning::AnimationFlattened { frames: animation.frames.len() });
+                vec![DynamicImage::ImageRgba8(animation.frames.swap_remove(0).into_buffer())]
+            }
+        };
+        for img in images {
+            pages.push(prepare_for_target(profile.apply(img), ImageFormat::Tiff, options)?);
+        }
+    }
+
+    let Some(metadata) = metadata else {
+        return Err(ConvertError::InvalidOption("no input images given".to_string()));
+    };
+    let data = encode_tiff(&pages, options, &metadata)?;
+    Ok(ConvertReport { data, frames: pages.len(), warnings })
+}
+
+/// Reads what the input holds (format, size, colour type, frames, metadata) from its
+/// headers, without decoding any pixels.
+pub fn probe_image_pure(input_data: &[u8]) -> Result<ImageInfo, ConvertError> {
+    let (format, confidence) = detect_with_confidence(input_data)?;
+    let mut decoder = ImageReader::with_format(Cursor::new(input_data), format)
+        .into_decoder()
+        .map_err(ConvertError::from_decode)?;
+
+    let (width, height) = decoder.dimensions();
+    let (color_type, bit_depth) = describe_color(decoder.original_color_type());
+    let orientation = decoder.orientation().map_or(1, Orientation::to_exif);
+
+    Ok(ImageInfo {
+        format: format_name(format).to_string(),
+        confidence,
+        width,
+        height,
+        color_type: color_type.to_string(),
+        bit_depth,
+        frames: count_frames(input_data, format),
+        has_alpha: decoder.color_type().has_alpha(),
+        has_icc: decoder.icc_profile().ok().flatten().is_some(),
+        has_exif: decoder.exif_metadata().ok().flatten().is_some(),
+        orientation,
+    })
+}
+
+/// Colour model name and bits per channel, as reported by `probe_image_pure`.
+fn describe_color(color: ExtendedColorType) -> (&'static str, u8) {
+    use ExtendedColorType as C;
+    let name = match color {
+        C::L1 | C::L2 | C::L4 | C::L8 | C::L16 => "gray",
+        C::La1 | C::La2 | C::La4 | C::La8 | C::La16 => "gray_alpha",
+        C::Rgb1 | C::Rgb2 | C::Rgb4 | C::Rgb8 | C::Rgb16 | C::Rgb32F | C::Bgr8 => "rgb",
+        C::Rgba1 | C::Rgba2 | C::Rgba4 | C::Rgba8 | C::Rgba16 | C::Rgba32F | C::Bgra8 => "rgba",
+        C::Cmyk8 | C::Cmyk16 => "cmyk",
+        C::A8 => "alpha",
+        C::Unknown(bits) => return ("indexed", bits),
+        _ => "unknown",
+    };
+    let bits = color.bits_per_pixel() / u16::from(color.channel_count());
+    (name, bits as u8)
+}
+
+/// Decodes a still image, converting it to sRGB if `options.icc` asks for that and
+/// applying its EXIF orientation unless `auto_orient` is off. Also returns the source
+/// metadata that `options.metadata` lets through and the ICC step decided for it.
+fn decode_image(
+    input_data: &[u8],
+    format: ImageFormat,
+    options: &ConvertOptions,
+) -> Result<(DynamicImage, Metadata, ProfileAction), ConvertError> {
+    let mut reader = ImageReader::with_format(Cursor::new(input_data), format);
+    reader.limits(options.limits.to_image_limits());
+    let mut decoder = reader.into_decoder().map_err(ConvertError::from_decode)?;
+    // Nothing is allocated yet: refuse decompression bombs while only the header is read.
+    options
+        .limits
+        .check(decoder.dimensions(), decoder.total_bytes(), 1)
+        .map_err(ConvertError::from_decode)?;
+
+    // Read before decoding: the decoder is consumed by `from_decoder`.
+    let orientation = if options.auto_orient {
+        decoder.orientation().map_err(ConvertError::from_decode)?
+    } else {
+        Orientation::NoTransforms
+    };
+    let (metadata, profile) =
+        read_source_metadata(&mut decoder, format, options, options.auto_orient);
+
+    let mut img = match profile.decode_cmyk_jpeg(input_data) {
+        Some(img) => img,
+        None => profile.apply(
+            DynamicImage::from_decoder(decoder).map_err(ConvertError::from_decode)?,
+        ),
+    };
+    img.apply_orientation(orientation);
+
+    Ok((img, metadata, profile))
+}
+
+/// Source metadata for inputs that go through `decode_animation` or `decode_tiff_pages`
+/// instead of `decode_image`. Animation frames are never re-oriented, so they pass
+/// `oriented: false` and the Orientation tag is kept as is.
+fn read_metadata(
+    input_data: &[u8],
+    format: ImageFormat,
+    options: &ConvertOptions,
+    oriented: bool,
+) -> (Metadata, ProfileAction) {
+    match ImageReader::with_format(Cursor::new(input_data), format).into_decoder() {
+        Ok(mut decoder) => read_source_metadata(&mut decoder, format, options, oriented),
+        Err(_) => (Metadata::default(), ProfileAction::Keep),
+    }
+}
+
+/// The metadata `options.metadata` lets through, plus what `options.icc` decides to do
+/// with the source's ICC profile.
+fn read_source_metadata<D: ImageDecoder + ?Sized>(
+    decoder: &mut D,
+    format: ImageFormat,
+    options: &ConvertOptions,
+    oriented: bool,
+) -> (Metadata, ProfileAction) {
+    if options.metadata == MetadataPolicy::Strip && options.icc == IccHandling::Ignore {
+        return (Metadata::default(), ProfileAction::Keep);
+    }
+    let source = Metadata::read(decoder);
+    let profile = ProfileAction::new(source.icc.as_deref(), options.icc, format);
+    (source.filter(options.metadata, oriented), profile)
+}
+
+/// The name `parse_target_format` accepts for `format`.
+fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        ImageFormat::Jpeg => "jpeg",
+        ImageFormat::Gif => "gif",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Bmp => "bmp",
+        ImageFormat::Ico => "ico",
+        ImageFormat::Tiff => "tiff",
+        ImageFormat::Tga => "tga",
+        ImageFormat::Farbfeld => "ff",
+        ImageFormat::Pnm => "pnm",
+        other => other.extensions_str().first().copied().unwrap_or("unknown"),
+    }
+}
+
+fn parse_target_format(target_format_str: &str) -> Result<ImageFormat, ConvertError> {
+    match target_format_str.to_lowercase().as_str() {
+        "png" => Ok(ImageFormat::Png),
+        "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+        "gif" => Ok(ImageFormat::Gif),
+        "webp" => Ok(ImageFormat::WebP),
+        "bmp" => Ok(ImageFormat::Bmp),
+        "ico" => Ok(ImageFormat::Ico),
+        "tiff" => Ok(ImageFormat::Tiff),
+        "tga" => Ok(ImageFormat::Tga),
+        "ff" | "farbfeld" => Ok(ImageFormat::Farbfeld),
+        "avif" if cfg!(feature = "avif") => Ok(ImageFormat::Avif),
+        "qoi" if cfg!(feature = "qoi") => Ok(ImageFormat::Qoi),
+        "pnm" if cfg!(feature = "pnm") => Ok(ImageFormat::Pnm),
+        "hdr" if cfg!(feature = "hdr") => Ok(ImageFormat::Hdr),
+        f => Err(ConvertError::UnsupportedTarget(f.to_string())),
+    }
+}
+
+/// Applies the transforms and resize from `options` and everything the target format needs
+/// (alpha flattening, size caps, bit depth) to a decoded image or animation frame.
+pub fn prepare_for_target(
+    img: DynamicImage,
+    output_format: ImageFormat,
+    options: &ConvertOptions,
+) -> Result<DynamicImage, ConvertError> {
+    // --- Optional user requested crop / rotate / flip steps, in order ---
+    let img = apply_transforms(img, options.transforms(), &options.limits)?;
+
+    // --- Optional user requested resize (fit, percentage, filter) ---
+    let mut img = resize(img, options)?;
+
+    // --- Float (HDR) pixels on integer targets ---
+    // Converting straight to 8 or 16 bits would clip every highlight above 1.0.
+    let float = matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    if float && !matches!(output_format, ImageFormat::Tiff | ImageFormat::Hdr) {
+        img = tone_map(&img);
+    }
+
+    // --- FIX: Handle Transparency for JPEG ---
+    // JPEG has no alpha, so transparent pixels are blended onto the background (white unless
+    // the caller picked another colour or the checkerboard). `remove_alpha` asks for the same
+    // on every target, and so do the BMP layouts without an alpha channel.
+    if output_format == ImageFormat::Jpeg
+        || (output_format == ImageFormat::Bmp && !options.bmp.format.has_alpha())
+        || (output_format == ImageFormat::Pnm && !options.pnm.format.has_alpha())
+        || (output_format == ImageFormat::Hdr && !float)
+        || options.remove_alpha
+    {
+        img = flatten(img, options.background(), options.linear_light);
     }
 
     // --- FIX: Handle ICO Size Limit (Max 256x256) ---
-    if output_format == ImageFormat::Ico {
+    // With several `ico.sizes` every entry is resampled from the full image when encoding.
+    if output_format == ImageFormat::Ico && options.ico.sizes.is_empty() {
         let (width, height) = (img.width(), img.height());
+        let max_ico_size = options.ico.max_dimension.clamp(1, 256);
 
-        if width > 256 || height > 256 {
-            // Resize to 256x256 while maintaining aspect ratio.
+        if width > max_ico_size || height > max_ico_size {
+            // Resize to fit max_ico_size while maintaining aspect ratio.
             // "Lanczos3" is slower but gives the best quality for downscaling.
-            img = img.resize(256, 256, FilterType::Lanczos3);
+            img = img.resize(max_ico_size, max_ico_size, FilterType::Lanczos3);
         }
     }
 
     // --- FIX: Handle Farbfeld Color Depth (Requires 16-bit RGBA) ---
     if output_format == ImageFormat::Farbfeld {
         // Promotes 8-bit images to 16-bit (e.g. 255 becomes 65535)
-        img = image::DynamicImage::ImageRgba16(img.into_rgba16());
+        img = DynamicImage::ImageRgba16(img.into_rgba16());
+    }
+
+    // --- QOI only stores 8-bit RGB(A), Radiance HDR linear float RGB ---
+    if output_format == ImageFormat::Qoi
+        && !matches!(img.color(), image::ColorType::Rgb8 | image::ColorType::Rgba8)
+    {
+        img = if img.color().has_alpha() {
+            DynamicImage::ImageRgba8(img.into_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(img.into_rgb8())
+        };
+    }
+    if output_format == ImageFormat::Hdr {
+        img = to_linear_rgb32f(img);
     }
 
     // --- FIX: OPTIMIZE GIF SPEED ---
     if output_format == ImageFormat::Gif {
-        // 1. Resize if too big (Optimization)
-        let max_gif_size = 800;
-        if img.width() > max_gif_size || img.height() > max_gif_size {
+        // 1. Resize if too big (Optimization, unless `gif.max_dimension` is unset)
+        if let Some(max_gif_size) = options.gif.max_dimension.map(|size| size.max(1))
+            && (img.width() > max_gif_size || img.height() > max_gif_size)
+        {
             img = img.resize(max_gif_size, max_gif_size, FilterType::Triangle);
         }
 
         // 2. NEW FIX: Convert 16-bit (Farbfeld) to 8-bit
         // The GIF encoder crashes if fed 16-bit data. We must have downscale it.
         // img.into_rgba8() safely converts Rgba16 (64-bit) -> Rgba8 (32-bit).
-        if matches!(img, image::DynamicImage::ImageRgba16(_))
-            || matches!(img, image::DynamicImage::ImageRgb16(_))
+        if matches!(img, DynamicImage::ImageRgba16(_))
+            || matches!(img, DynamicImage::ImageRgb16(_))
         {
-            img = image::DynamicImage::ImageRgba8(img.into_rgba8());
+            img = DynamicImage::ImageRgba8(img.into_rgba8());
         }
     }
     // -----------------------------------------
 
-    // D. Encode
-    let mut buffer = Cursor::new(Vec::new());
-
-    // write_to accepts ImageFormat directly in version 0.25+
-    img.write_to(&mut buffer, output_format)
-        .map_err(|e| format!("Failed to write output: {}", e))?;
-
-    Ok(buffer.into_inner())
+    Ok(img)
 }
 
 #[cfg(test)]
@@ -113,6 +694,9 @@ mod tests {
     use super::*;
     use std::fs;
     use std::path::PathBuf;
+    use crate::options::{BmpFormat, Dither, Quantizer, TiffCompression};
+    use crate::transform::Transform;
+    use image::RgbaImage;
 
     // Helper to get absolute path to assets
     fn get_asset_path(filename: &str) -> PathBuf {
@@ -864,18 +1448,954 @@ mod tests {
         convert_and_assert_ff("farbfeld", ImageFormat::Farbfeld, "output_from_ff.ff");
     }
 
+    #[test]
+    fn convert_with_default_options_matches_plain_call() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let plain = convert_image_pure(&img_data, "webp").expect("Conversion failed");
+        let with_defaults =
+            convert_image_with_options(&img_data, "webp", &ConvertOptions::default())
+                .expect("Conversion failed");
+        assert_eq!(plain, with_defaults);
+    }
+
+    #[test]
+    fn convert_jpeg_with_lower_quality_is_smaller() {
+        let img_path = get_asset_path("original.jpg");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+
+        let mut high = ConvertOptions::default();
+        high.quality = Some(95);
+        let mut low = ConvertOptions::default();
+        low.quality = Some(20);
+
+        let high_data =
+            convert_image_with_options(&img_data, "jpeg", &high).expect("Conversion failed");
+        let low_data =
+            convert_image_with_options(&img_data, "jpeg", &low).expect("Conversion failed");
+        assert!(low_data.len() < high_data.len());
+    }
+
+    #[test]
+    fn convert_with_resize_keeps_aspect_ratio() {
+        // original.jpg is 1536x2048
+        let img_path = get_asset_path("original.jpg");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.width = Some(300);
+
+        let output_data =
+            convert_image_with_options(&img_data, "png", &options).expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (300, 400));
+    }
+
+    #[test]
+    fn convert_runs_transforms_in_order_before_the_resize() {
+        // original.jpg is 1536x2048
+        let img_data = fs::read(get_asset_path("original.jpg")).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.add_transform(&Transform::rotate(90.0));
+        options.add_transform(&Transform::crop(0, 0, 1000, 1536));
+        options.add_transform(&Transform::flip_vertical());
+        options.width = Some(500);
+
+        let output_data =
+            convert_image_with_options(&img_data, "png", &options).expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (500, 768));
+
+        // A crop that misses the image is an options error, not a silent no-op.
+        let mut options = ConvertOptions::default();
+        options.add_transform(&Transform::crop(5000, 0, 10, 10));
+        let result = convert_image_with_options(&img_data, "png", &options);
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn convert_transparent_png_to_jpeg_uses_background() {
+        let transparent = image::DynamicImage::ImageRgba8(RgbaImage::new(8, 8));
+        let mut png_data = Cursor::new(Vec::new());
+        transparent.write_to(&mut png_data, ImageFormat::Png).expect("Failed to build PNG");
+
+        let mut options = ConvertOptions::default();
+        options.set_background(0, 0, 0);
+        let output_data = convert_image_with_options(png_data.get_ref(), "jpeg", &options)
+            .expect("Conversion failed");
+
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        let pixel = reloaded.to_rgb8().get_pixel(4, 4).0;
+        assert!(pixel.iter().all(|&c| c < 10), "expected black, got {:?}", pixel);
+    }
+
+    #[test]
+    fn convert_remove_alpha_flattens_other_targets() {
+        let transparent = image::DynamicImage::ImageRgba8(RgbaImage::new(16, 16));
+        let mut png_data = Cursor::new(Vec::new());
+        transparent.write_to(&mut png_data, ImageFormat::Png).expect("Failed to build PNG");
+
+        // Without the flag PNG keeps its transparency.
+        let output_data = convert_image_pure(png_data.get_ref(), "png").expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert!(reloaded.color().has_alpha());
+
+        let mut options = ConvertOptions::default();
+        options.remove_alpha = true;
+        assert!(options.set_background_color("#336699").is_ok());
+        let output_data = convert_image_with_options(png_data.get_ref(), "png", &options)
+            .expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert!(!reloaded.color().has_alpha());
+        assert_eq!(reloaded.to_rgb8().get_pixel(3, 3).0, [0x33, 0x66, 0x99]);
+
+        options.set_background_checkerboard(8);
+        let output_data = convert_image_with_options(png_data.get_ref(), "png", &options)
+            .expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert_ne!(reloaded.to_rgb8().get_pixel(0, 0), reloaded.to_rgb8().get_pixel(8, 0));
+    }
+
+    #[test]
+    fn convert_png_to_png_optimized() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.png.optimize = true;
+
+        let plain = convert_image_pure(&img_data, "png").expect("Conversion failed");
+        let optimized =
+            convert_image_with_options(&img_data, "png", &options).expect("Conversion failed");
+
+        let output_path = get_asset_path("output_from_png_optimized.png");
+        fs::write(&output_path, &optimized).expect("Failed to write output image");
+
+        assert!(optimized.len() < plain.len());
+        let original = image::load_from_memory(&img_data).expect("Failed to decode original");
+        let reloaded = image::load_from_memory(&optimized).expect("Failed to reload output");
+        assert_eq!(original.to_rgba8(), reloaded.to_rgba8());
+    }
+
+    #[test]
+    fn convert_jpeg_to_lossy_webp_is_smaller_than_lossless() {
+        let img_path = get_asset_path("original.jpg");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut lossless = ConvertOptions::default();
+        lossless.webp.lossless = true;
+
+        let lossy_data = convert_image_pure(&img_data, "webp").expect("Conversion failed");
+        let lossless_data =
+            convert_image_with_options(&img_data, "webp", &lossless).expect("Conversion failed");
+        assert!(lossy_data.len() < lossless_data.len());
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn convert_png_to_avif() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.width = Some(64);
+        options.avif.speed = 10;
+
+        let output_data =
+            convert_image_with_options(&img_data, "avif", &options).expect("Conversion failed");
+        let output_path = get_asset_path("output_from_png.avif");
+        fs::write(&output_path, &output_data).expect("Failed to write output image");
+        assert_eq!(detect_image_format(&output_data).unwrap(), ImageFormat::Avif);
+    }
+
+    #[cfg(not(feature = "avif"))]
+    #[test]
+    fn avif_target_needs_the_feature() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let result = convert_image_pure(&img_data, "avif");
+        assert!(matches!(result, Err(ConvertError::UnsupportedTarget(_))));
+    }
+
+    #[cfg(feature = "qoi")]
+    #[test]
+    fn convert_png_to_qoi_and_back() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let qoi = convert_image_pure(&img_data, "qoi").expect("Conversion failed");
+        let output_path = get_asset_path("output_from_png.qoi");
+        fs::write(&output_path, &qoi).expect("Failed to write output image");
+        assert_eq!(detect_image_format(&qoi).unwrap(), ImageFormat::Qoi);
+
+        // QOI is lossless, so the PNG pixels survive the trip.
+        let png = convert_image_pure(&qoi, "png").expect("Conversion failed");
+        let original = image::load_from_memory(&img_data).unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8(), original.to_rgba8());
+
+        // 16-bit input is narrowed to what QOI stores.
+        let mut png_16 = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb16(image::ImageBuffer::new(4, 4))
+            .write_to(&mut png_16, ImageFormat::Png)
+            .unwrap();
+        let deep = convert_image_pure(png_16.get_ref(), "qoi").expect("Conversion failed");
+        assert_eq!(image::load_from_memory(&deep).unwrap().color(), image::ColorType::Rgb8);
+
+        // So is grayscale, which QOI has no channel layout for.
+        let gray = DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_fn(16, 8, |x, y| {
+            image::LumaA([x as u8 * 16, 255 - y as u8])
+        }));
+        for img in [DynamicImage::ImageLuma8(gray.to_luma8()), gray] {
+            let mut png = Cursor::new(Vec::new());
+            img.write_to(&mut png, ImageFormat::Png).unwrap();
+            let qoi = convert_image_pure(png.get_ref(), "qoi").expect("Conversion failed");
+            let output = image::load_from_memory(&qoi).unwrap();
+            assert_eq!(output.color().has_alpha(), img.color().has_alpha());
+            assert_eq!(output.to_luma_alpha8(), img.to_luma_alpha8());
+        }
+    }
+
+    #[cfg(feature = "pnm")]
+    #[test]
+    fn convert_png_to_pnm_variants() {
+        use crate::options::PnmFormat;
+
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let original = image::load_from_memory(&img_data).unwrap();
+        let convert = |format, ascii| {
+            let mut options = ConvertOptions::default();
+            options.pnm.format = format;
+            options.pnm.ascii = ascii;
+            let data = convert_image_with_options(&img_data, "pnm", &options)
+                .expect("Conversion failed");
+            let decoded = image::load_from_memory(&data).expect("Failed to reload PNM");
+            (data[..2].to_vec(), decoded)
+        };
+
+        // The PNG has alpha, so the automatic choice is PAM, which keeps it.
+        let (magic, decoded) = convert(PnmFormat::Auto, false);
+        assert_eq!(magic, b"P7");
+        assert_eq!(decoded.to_rgba8(), original.to_rgba8());
+
+        let (magic, decoded) = convert(PnmFormat::Ppm, true);
+        assert_eq!(magic, b"P3");
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        let (magic, decoded) = convert(PnmFormat::Pgm, false);
+        assert_eq!((magic.as_slice(), decoded.color()), (&b"P5"[..], image::ColorType::L8));
+        let (magic, decoded) = convert(PnmFormat::Pbm, false);
+        assert_eq!(magic, b"P4");
+        assert!(decoded.to_luma8().pixels().all(|p| p[0] == 0 || p[0] == 255));
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn convert_hdr_tone_maps_for_integer_targets() {
+        // A gradient from black to 16 times brighter than SDR white.
+        let radiance = image::Rgb32FImage::from_fn(64, 8, |x, _| image::Rgb([x as f32 / 4.0; 3]));
+        let mut hdr = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb32F(radiance.clone()).write_to(&mut hdr, ImageFormat::Hdr).unwrap();
+        let hdr = hdr.into_inner();
+        assert_eq!(probe_image_pure(&hdr).unwrap().format, "hdr");
+
+        let png = convert_image_pure(&hdr, "png").expect("Conversion failed");
+        let output_path = get_asset_path("output_from_hdr.png");
+        fs::write(&output_path, &png).expect("Failed to write output image");
+        let png = image::load_from_memory(&png).unwrap().to_rgb8();
+        // Clipping would make everything past x = 4 white; tone mapping keeps it a ramp.
+        let row: Vec<u8> = (0..64).map(|x| png.get_pixel(x, 0)[0]).collect();
+        assert!(row.windows(2).all(|w| w[0] <= w[1]));
+        assert!(row[8] < row[32] && row[32] < 255 && row[63] == 255, "got {:?}", row);
+
+        // Float targets keep the radiance.
+        let tiff = convert_image_pure(&hdr, "tiff").expect("Conversion failed");
+        let tiff = image::load_from_memory(&tiff).unwrap();
+        assert_eq!(tiff.color(), image::ColorType::Rgb32F);
+        assert!((tiff.to_rgb32f().get_pixel(63, 0)[0] - 15.75).abs() < 0.1);
+
+        // And 8-bit sources are written to HDR as linear light.
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let back = convert_image_pure(&img_data, "hdr").expect("Conversion failed");
+        let back = image::load_from_memory(&back).unwrap();
+        assert_eq!(back.color(), image::ColorType::Rgb32F);
+    }
+
+    #[cfg(feature = "hdr")]
+    #[test]
+    fn convert_hdr_resize_keeps_highlights() {
+        // Columns alternating between 0 and 8 (three stops over SDR white).
+        let radiance =
+            image::Rgb32FImage::from_fn(64, 8, |x, _| image::Rgb([(x % 2) as f32 * 8.0; 3]));
+        let mut hdr = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb32F(radiance).write_to(&mut hdr, ImageFormat::Hdr).unwrap();
+        let hdr = hdr.into_inner();
+
+        for linear_light in [false, true] {
+            let mut options = ConvertOptions::default();
+            options.width = Some(32);
+            options.filter = crate::options::ResizeFilter::Triangle;
+            options.linear_light = linear_light;
+            let resized = convert_image_with_options(&hdr, "hdr", &options).unwrap();
+            let resized = image::load_from_memory(&resized).unwrap().into_rgb32f();
+            assert_eq!(resized.dimensions(), (32, 4));
+            // Averaging pairs gives 4, where clamping to 1.0 first gives at most 0.5.
+            let value = resized.get_pixel(16, 2)[0];
+            assert!((value - 4.0).abs() < 0.1, "{} gave {}", linear_light, value);
+        }
+    }
+
+    #[test]
+    fn convert_animated_gif_to_png_keeps_frames() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let report = convert_image_detailed(&img_data, "png", &ConvertOptions::default())
+            .expect("Conversion failed");
+
+        let output_path = get_asset_path("output_from_gif_animated.png");
+        fs::write(&output_path, &report.data).expect("Failed to write output image");
+
+        assert_eq!(report.frames, 50);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn convert_animated_gif_to_jpeg_reports_flattening() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let report = convert_image_detailed(&img_data, "jpeg", &ConvertOptions::default())
+            .expect("Conversion failed");
+
+        assert_eq!(report.frames, 1);
+        assert_eq!(report.warnings, vec![ConvertWarning::AnimationFlattened { frames: 50 }]);
+        assert_eq!(detect_image_format(&report.data).unwrap(), ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn convert_animated_gif_without_keep_animation() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.keep_animation = false;
+
+        let report =
+            convert_image_detailed(&img_data, "png", &options).expect("Conversion failed");
+        assert_eq!(report.frames, 1);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn small_neuquant_palettes_are_reported() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.gif.colors = 16;
+        options.gif.max_dimension = Some(64);
+        let report =
+            convert_image_detailed(&img_data, "gif", &options).expect("Conversion failed");
+        assert_eq!(report.warnings, vec![ConvertWarning::QuantizerReplaced { colors: 16 }]);
+
+        options.gif.quantizer = Quantizer::MedianCut;
+        let report =
+            convert_image_detailed(&img_data, "gif", &options).expect("Conversion failed");
+        assert!(report.warnings.is_empty());
+
+        options.bmp.format = BmpFormat::Indexed4;
+        options.width = Some(64);
+        let report =
+            convert_image_detailed(&img_data, "bmp", &options).expect("Conversion failed");
+        assert_eq!(report.warnings, vec![ConvertWarning::QuantizerReplaced { colors: 16 }]);
+    }
+
+    #[test]
+    fn merge_images_into_multi_page_tiff() {
+        let inputs = ["original.jpg", "original.png", "original.gif"]
+            .map(|name| fs::read(get_asset_path(name)).expect("Failed to read test image"));
+        let mut options = ConvertOptions::default();
+        options.tiff.compression = TiffCompression::Deflate;
+        options.width = Some(64);
+
+        let report = merge_to_tiff_pure(&inputs, &options).expect("Merge failed");
+        let output_path = get_asset_path("output_merged_pages.tiff");
+        fs::write(&output_path, &report.data).expect("Failed to write output image");
+
+        assert_eq!(report.frames, 3);
+        assert_eq!(report.warnings, vec![ConvertWarning::AnimationFlattened { frames: 50 }]);
+        assert_eq!(probe_image_pure(&report.data).unwrap().frames, 3);
+        assert!(merge_to_tiff_pure(&[] as &[Vec<u8>], &options).is_err());
+
+        // Every page survives a TIFF to TIFF conversion and comes out of `extract_frames`.
+        let pages = convert_image_detailed(&report.data, "tiff", &ConvertOptions::default())
+            .expect("Conversion failed");
+        assert_eq!(pages.frames, 3);
+        let frames = extract_frames_pure(&report.data, "png", &ConvertOptions::default())
+            .expect("Extraction failed");
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|frame| frame.delay_ms() == 0));
+
+        // Single image targets keep the first page.
+        let png = convert_image_detailed(&report.data, "png", &ConvertOptions::default())
+            .expect("Conversion failed");
+        assert_eq!(png.warnings, vec![ConvertWarning::PagesFlattened { pages: 3 }]);
+        let mut first_page = ConvertOptions::default();
+        first_page.keep_pages = false;
+        let tiff = convert_image_detailed(&report.data, "tiff", &first_page)
+            .expect("Conversion failed");
+        assert_eq!((tiff.frames, tiff.warnings.len()), (1, 0));
+    }
+
+    #[test]
+    fn convert_multi_page_tiff_decodes_only_the_first_page_for_single_image_targets() {
+        use tiff::encoder::{colortype, TiffEncoder};
+
+        // Three 1 MiB pages, under a 2 MiB limit: one fits, all three do not.
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        for value in [10, 20, 30] {
+            encoder.write_image::<colortype::Gray8>(1024, 1024, &vec![value; 1 << 20]).unwrap();
+        }
+        let data = buffer.into_inner();
+        let mut options = ConvertOptions::default();
+        options.limits.max_memory_mb = Some(2);
+
+        let png = convert_image_detailed(&data, "png", &options).expect("Conversion failed");
+        assert_eq!(png.warnings, vec![ConvertWarning::PagesFlattened { pages: 3 }]);
+        let png = image::load_from_memory(&png.data).unwrap().to_luma8();
+        assert_eq!(png.get_pixel(0, 0).0, [10]);
+
+        // Keeping every page still counts them all against the limit.
+        let tiff = convert_image_detailed(&data, "tiff", &options);
+        assert!(matches!(tiff, Err(ConvertError::LimitExceeded(_))));
+        let multi = convert_image_multi_pure(&data, &["png", "tiff"], &options);
+        assert!(matches!(multi, Err(ConvertError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn convert_multi_page_tiff_applies_orientation() {
+        use tiff::encoder::{colortype, TiffEncoder};
+        use tiff::tags::Tag;
+
+        // Two 4x2 scans, both tagged as rotated 90° clockwise.
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        for _ in 0..2 {
+            let mut page = encoder.new_image::<colortype::Gray8>(4, 2).unwrap();
+            page.encoder().write_tag(Tag::Orientation, 6u16).unwrap();
+            page.write_data(&[0; 8]).unwrap();
+        }
+        let data = buffer.into_inner();
+
+        // All pages and the first page alone come out the same way up.
+        let pages = convert_image_detailed(&data, "tiff", &ConvertOptions::default())
+            .expect("Conversion failed");
+        assert_eq!(pages.frames, 2);
+        let mut first_page = ConvertOptions::default();
+        first_page.keep_pages = false;
+        let single = convert_image_detailed(&data, "tiff", &first_page)
+            .expect("Conversion failed");
+        for output in [&pages.data, &single.data] {
+            let info = probe_image_pure(output).unwrap();
+            assert_eq!((info.width, info.height, info.orientation), (2, 4, 1));
+        }
+        // The second page too.
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&pages.data)).unwrap();
+        decoder.next_image().unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 4));
+
+        // With `auto_orient` off the pixels are left alone.
+        let mut stored = ConvertOptions::default();
+        stored.auto_orient = false;
+        let pages = convert_image_detailed(&data, "tiff", &stored).expect("Conversion failed");
+        assert_eq!(probe_image_pure(&pages.data).unwrap().width, 4);
+    }
+
+    #[test]
+    fn extract_all_frames_from_gif() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let frames = extract_frames_pure(&img_data, "png", &ConvertOptions::default())
+            .expect("Extraction failed");
+
+        assert_eq!(frames.len(), 50);
+        assert_eq!(frames[0].timestamp_ms(), 0);
+        assert_eq!(frames[1].timestamp_ms(), frames[0].delay_ms());
+        assert_eq!(detect_image_format(&frames[0].data()).unwrap(), ImageFormat::Png);
+    }
+
+    #[test]
+    fn extract_frame_range_from_gif() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.frame_start = Some(10);
+        options.frame_end = Some(12);
+
+        let frames = extract_frames_pure(&img_data, "jpeg", &options).expect("Extraction failed");
+        assert_eq!(frames.iter().map(|f| f.index()).collect::<Vec<_>>(), vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn extract_frames_from_still_image() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+
+        let frames = extract_frames_pure(&img_data, "webp", &options).expect("Extraction failed");
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].delay_ms(), 0);
+
+        options.frame_start = Some(3);
+        let result = extract_frames_pure(&img_data, "webp", &options);
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    // A 4x2 JPEG whose EXIF says "rotate 90 degrees clockwise" (orientation 6).
+    fn rotated_jpeg() -> Vec<u8> {
+        let exif = [
+            b'M', b'M', 0, 42, 0, 0, 0, 8, // big-endian TIFF header, IFD at offset 8
+            0, 1, // one entry
+            0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // Orientation, SHORT, 1 value: 6
+            0, 0, 0, 0, // no next IFD
+        ];
+        let pixels: Vec<u8> = (0..8).flat_map(|i| [i * 30, 0, 0]).collect();
+
+        let mut jpeg = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 90);
+        encoder.add_exif_metadata(&exif).expect("Failed to add EXIF");
+        encoder
+            .encode(&pixels, 4, 2, jpeg_encoder::ColorType::Rgb)
+            .expect("Failed to build JPEG");
+        jpeg
+    }
+
+    #[test]
+    fn convert_applies_exif_orientation() {
+        let output_data = convert_image_pure(&rotated_jpeg(), "png").expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (2, 4));
+    }
+
+    #[test]
+    fn convert_without_auto_orient_keeps_stored_layout() {
+        let mut options = ConvertOptions::default();
+        options.auto_orient = false;
+
+        let output_data = convert_image_with_options(&rotated_jpeg(), "png", &options)
+            .expect("Conversion failed");
+        let reloaded = image::load_from_memory(&output_data).expect("Failed to reload output");
+        assert_eq!((reloaded.width(), reloaded.height()), (4, 2));
+    }
+
+    // A 4x2 JPEG carrying EXIF (orientation 6, copyright "(c)", a GPS block), XMP and ICC.
+    fn tagged_jpeg() -> Vec<u8> {
+        let exif = [
+            b'I', b'I', 42, 0, 8, 0, 0, 0, // little-endian TIFF header, IFD at offset 8
+            3, 0, // three entries
+            0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0, // Orientation, SHORT: 6
+            0x98, 0x82, 2, 0, 4, 0, 0, 0, b'(', b'c', b')', 0, // Copyright, ASCII: "(c)"
+            0x25, 0x88, 4, 0, 1, 0, 0, 0, 50, 0, 0, 0, // GPS IFD at offset 50
+            0, 0, 0, 0, // no next IFD
+            1, 0, // GPS IFD: one entry
+            0, 0, 1, 0, 4, 0, 0, 0, 2, 3, 0, 0, // GPSVersionID 2.3.0.0
+            0, 0, 0, 0,
+        ];
+        let pixels: Vec<u8> = (0..8).flat_map(|i| [i * 30, 0, 0]).collect();
+
+        let mut jpeg = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 90);
+        encoder.add_exif_metadata(&exif).expect("Failed to add EXIF");
+        encoder
+            .add_app_segment(1, b"http://ns.adobe.com/xap/1.0/\0<x:xmpmeta/>".to_vec())
+            .expect("Failed to add XMP");
+        encoder.add_icc_profile(&[7; 200]).expect("Failed to add ICC profile");
+        encoder
+            .encode(&pixels, 4, 2, jpeg_encoder::ColorType::Rgb)
+            .expect("Failed to build JPEG");
+        jpeg
+    }
+
+    fn output_metadata(output_data: &[u8]) -> Metadata {
+        let format = detect_image_format(output_data).expect("Failed to detect output format");
+        let mut decoder = ImageReader::with_format(Cursor::new(output_data), format)
+            .into_decoder()
+            .expect("Failed to read output");
+        Metadata::read(&mut decoder)
+    }
+
+    #[test]
+    fn convert_strips_metadata_by_default() {
+        let output_data = convert_image_pure(&tagged_jpeg(), "jpeg").expect("Conversion failed");
+        assert!(output_metadata(&output_data).is_empty());
+    }
+
+    #[test]
+    fn convert_keeps_metadata_in_jpeg_png_and_webp() {
+        let mut options = ConvertOptions::default();
+        options.metadata = MetadataPolicy::KeepAll;
+
+        for target in ["jpeg", "png", "webp"] {
+            let output_data = convert_image_with_options(&tagged_jpeg(), target, &options)
+                .expect("Conversion failed");
+            let metadata = output_metadata(&output_data);
+
+            assert_eq!(metadata.icc, Some(vec![7; 200]), "ICC lost in {}", target);
+            let xmp = metadata.xmp.as_deref();
+            assert_eq!(xmp, Some(&b"<x:xmpmeta/>"[..]), "XMP lost in {}", target);
+            // The pixels were rotated upright, so the tag must not rotate them again.
+            assert_eq!(metadata.exif_orientation(), Some(1), "EXIF lost in {}", target);
+            assert_eq!(image::load_from_memory(&output_data).unwrap().width(), 2);
+        }
+    }
+
+    #[test]
+    fn convert_keeps_metadata_in_tiff() {
+        let mut options = ConvertOptions::default();
+        options.metadata = MetadataPolicy::CopyrightAndIcc;
+
+        let output_data = convert_image_with_options(&tagged_jpeg(), "tiff", &options)
+            .expect("Conversion failed");
+
+        // Read the tags directly; the `image` TIFF decoder looks the ICC profile up by an
+        // `Unknown` tag that never matches.
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(&output_data))
+            .expect("Failed to read TIFF");
+        let icc = decoder.get_tag_u8_vec(tiff::tags::Tag::IccProfile);
+        assert_eq!(icc.expect("ICC profile missing"), vec![7; 200]);
+        let copyright = decoder.get_tag_ascii_string(tiff::tags::Tag::Copyright);
+        assert_eq!(copyright.expect("Copyright tag missing"), "(c)");
+    }
+
+    #[test]
+    fn convert_strip_gps_drops_only_the_location() {
+        let mut options = ConvertOptions::default();
+        options.metadata = MetadataPolicy::StripGps;
+
+        let output_data = convert_image_with_options(&tagged_jpeg(), "jpeg", &options)
+            .expect("Conversion failed");
+        let metadata = output_metadata(&output_data);
+        let exif = metadata.exif.as_deref().expect("EXIF should be kept");
+
+        assert!(!exif.windows(2).any(|w| w == [0x25, 0x88]), "GPS IFD pointer still present");
+        assert!(exif.windows(4).any(|w| w == b"(c)\0"));
+        assert!(metadata.icc.is_some());
+    }
+
+    /// A 2x1 PNG with a P3 green and a mid gray pixel, tagged as Display P3.
+    fn display_p3_png() -> Vec<u8> {
+        let img = image::RgbImage::from_fn(2, 1, |x, _| {
+            if x == 0 { image::Rgb([0, 200, 0]) } else { image::Rgb([128; 3]) }
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .expect("Failed to build PNG");
+        let icc = moxcms::ColorProfile::new_display_p3().encode().expect("Failed to encode");
+        crate::metadata::embed_png(png, &Metadata { icc: Some(icc), ..Metadata::default() })
+    }
+
+    #[test]
+    fn convert_transforms_display_p3_to_srgb_by_default() {
+        let output_data = convert_image_pure(&display_p3_png(), "png").expect("Conversion failed");
+
+        let output = image::load_from_memory(&output_data).expect("Failed to read output");
+        let green = output.to_rgb8().get_pixel(0, 0).0;
+        assert!(green[0] == 0 && green[1] > 200, "{:?}", green);
+        assert_eq!(output_metadata(&output_data).icc, None);
+    }
+
+    #[test]
+    fn convert_embeds_the_profile_when_asked() {
+        let mut options = ConvertOptions::default();
+        options.icc = IccHandling::Embed;
+
+        let output_data = convert_image_with_options(&display_p3_png(), "webp", &options)
+            .expect("Conversion failed");
+        let icc = output_metadata(&output_data).icc.expect("ICC profile missing");
+        let profile = moxcms::ColorProfile::new_from_slice(&icc).expect("Unreadable profile");
+        assert_eq!(profile.color_space, moxcms::DataColorSpace::Rgb);
+
+        // GIF cannot carry a profile, so the pixels are converted instead.
+        let output_data = convert_image_with_options(&display_p3_png(), "gif", &options)
+            .expect("Conversion failed");
+        let output = image::load_from_memory(&output_data).expect("Failed to read output");
+        assert_eq!(output.to_rgb8().get_pixel(0, 0)[0], 0);
+    }
+
+    #[test]
+    fn convert_cmyk_jpeg_through_its_profile() {
+        // No ink at all, which the profile-less conversion turns into white.
+        let mut jpeg = Vec::new();
+        let mut encoder = jpeg_encoder::Encoder::new(&mut jpeg, 100);
+        encoder
+            .add_icc_profile(&crate::color::tests::flat_cmyk_profile())
+            .expect("Failed to add ICC profile");
+        encoder
+            .encode(&[0; 4 * 16], 4, 4, jpeg_encoder::ColorType::Cmyk)
+            .expect("Failed to build JPEG");
+
+        let output_data = convert_image_pure(&jpeg, "png").expect("Conversion failed");
+        let output = image::load_from_memory(&output_data).expect("Failed to read output");
+        let pixel = output.to_rgb8().get_pixel(0, 0).0;
+        assert!(pixel.iter().all(|&v| v.abs_diff(118) <= 3), "{:?}", pixel);
+
+        let mut options = ConvertOptions::default();
+        options.icc = IccHandling::Ignore;
+        let output_data = convert_image_with_options(&jpeg, "png", &options)
+            .expect("Conversion failed");
+        let output = image::load_from_memory(&output_data).expect("Failed to read output");
+        assert_eq!(output.to_rgb8().get_pixel(0, 0).0, [255; 3]);
+    }
+
+    #[test]
+    fn convert_png_to_paletted_bmp_flattens_alpha() {
+        let img_data = fs::read(get_asset_path("original.png")).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.bmp.format = BmpFormat::Indexed8;
+        options.bmp.top_down = true;
+        options.bmp.dither = Dither::FloydSteinberg;
+        options.set_background(0, 0, 0);
+
+        let output_data =
+            convert_image_with_options(&img_data, "bmp", &options).expect("Conversion failed");
+        let output_path = get_asset_path("output_from_png_indexed.bmp");
+        fs::write(&output_path, &output_data).expect("Failed to write output image");
+
+        let info = probe_image_pure(&output_data).expect("Probe failed");
+        assert_eq!(info.format, "bmp");
+        assert!(!info.has_alpha);
+        let reloaded = image::load_from_memory_with_format(&output_data, ImageFormat::Bmp)
+            .expect("Failed to reload BMP");
+        let original = image::load_from_memory(&img_data).unwrap();
+        assert_eq!((reloaded.width(), reloaded.height()), (original.width(), original.height()));
+    }
+
+    #[test]
+    fn probe_reports_header_fields() {
+        let img_data = fs::read(get_asset_path("original.gif")).expect("Failed to read test image");
+        let info = probe_image_pure(&img_data).expect("Probe failed");
+        assert_eq!((info.format.as_str(), info.width, info.height), ("gif", 220, 224));
+        assert_eq!(info.frames, 50);
+        assert!(!info.has_icc && !info.has_exif);
+
+        let img_data =
+            fs::read(get_asset_path("original.tiff")).expect("Failed to read test image");
+        let info = probe_image_pure(&img_data).expect("Probe failed");
+        assert_eq!(info.format, "tiff");
+        assert_eq!((info.frames, info.confidence), (1, 100));
+        assert!(info.has_icc);
+    }
+
+    #[test]
+    fn probe_reports_tga_detection_confidence() {
+        let img_data = fs::read(get_asset_path("original.tga")).expect("Failed to read test image");
+        let info = probe_image_pure(&img_data).expect("Probe failed");
+        assert_eq!((info.format.as_str(), info.confidence), ("tga", 100));
+
+        // Without the TGA 2.0 footer the header alone has to convince the detector.
+        let old_style = &img_data[..img_data.len() - 26];
+        let info = probe_image_pure(old_style).expect("Probe failed");
+        assert_eq!(info.format, "tga");
+        assert!((MIN_TGA_CONFIDENCE..100).contains(&info.confidence));
+
+        // Header-shaped noise is no longer taken for TGA.
+        let mut noise = img_data[..64].to_vec();
+        noise[2] = 7;
+        assert!(matches!(detect_image_format(&noise), Err(ConvertError::DetectFailed(_))));
+    }
+
+    #[test]
+    fn probe_reports_metadata_and_orientation() {
+        let info = probe_image_pure(&tagged_jpeg()).expect("Probe failed");
+
+        // Stored size: the orientation is reported, not applied.
+        assert_eq!((info.width, info.height), (4, 2));
+        assert_eq!((info.color_type.as_str(), info.bit_depth), ("rgb", 8));
+        assert_eq!(info.orientation, 6);
+        assert!(info.has_icc && info.has_exif && !info.has_alpha);
+    }
+
+    #[test]
+    fn probe_fails_like_convert_on_unknown_data() {
+        let result = probe_image_pure(b"This is not an image!");
+        assert!(matches!(result, Err(ConvertError::DetectFailed(_))));
+    }
+
+    #[test]
+    fn convert_multi_matches_single_conversions() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let targets = ["png", "jpeg", "ico", "ff"];
+
+        let reports = convert_image_multi_pure(&img_data, &targets, &ConvertOptions::default())
+            .expect("Conversion failed");
+        assert_eq!(reports.len(), targets.len());
+        for (target, report) in targets.iter().zip(&reports) {
+            let single = convert_image_pure(&img_data, target).expect("Conversion failed");
+            assert_eq!(report.data, single, "{} output differs", target);
+        }
+    }
+
+    #[test]
+    fn convert_multi_animates_only_where_possible() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+
+        let options = ConvertOptions::default();
+        let reports = convert_image_multi_pure(&img_data, &["gif", "jpeg"], &options)
+            .expect("Conversion failed");
+        assert_eq!(reports[0].frames, 50);
+        assert!(reports[0].warnings.is_empty());
+        assert_eq!(reports[1].frames, 1);
+        assert_eq!(reports[1].warnings, vec![ConvertWarning::AnimationFlattened { frames: 50 }]);
+    }
+
+    #[test]
+    fn convert_multi_embeds_the_profile_per_target() {
+        let mut options = ConvertOptions::default();
+        options.icc = IccHandling::Embed;
+
+        let reports = convert_image_multi_pure(&display_p3_png(), &["png", "bmp"], &options)
+            .expect("Conversion failed");
+        assert!(output_metadata(&reports[0].data).icc.is_some());
+        let png = image::load_from_memory(&reports[0].data).expect("Failed to read output");
+        assert_eq!(png.to_rgb8().get_pixel(0, 0).0, [0, 200, 0]);
+        // BMP has no room for the profile, so only its copy is converted.
+        let bmp = image::load_from_memory(&reports[1].data).expect("Failed to read output");
+        assert_ne!(bmp.to_rgb8().get_pixel(0, 0).0, [0, 200, 0]);
+    }
+
+    #[test]
+    fn convert_multi_rejects_bad_target_lists() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let options = ConvertOptions::default();
+
+        let result = convert_image_multi_pure::<&str>(&img_data, &[], &options);
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+        let result = convert_image_multi_pure(&img_data, &["png", "pdf"], &options);
+        assert!(matches!(result, Err(ConvertError::UnsupportedTarget(_))));
+    }
+
+    #[test]
+    fn decoded_source_encodes_like_a_conversion() {
+        let img_path = get_asset_path("original.jpg");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let options = ConvertOptions::default();
+
+        let source = decode_pure(&img_data, &options).expect("Decoding failed");
+        assert_eq!(source.format_name(), "jpeg");
+        for target in ["png", "webp"] {
+            let report = encode_source(&source, target, &options).expect("Encoding failed");
+            let single = convert_image_pure(&img_data, target).expect("Conversion failed");
+            assert_eq!(report.data, single, "{} output differs", target);
+        }
+    }
+
+    #[test]
+    fn decoded_source_resizes_every_frame() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let source = decode_pure(&img_data, &ConvertOptions::default()).expect("Decoding failed");
+        assert_eq!(source.dimensions(), (220, 224));
+
+        let small = source.resized(Some(110), None, &ConvertOptions::default()).unwrap();
+        assert_eq!(small.dimensions(), (110, 112));
+        assert_eq!(small.frame_count(), 50);
+        // The original stays as it was.
+        assert_eq!(source.dimensions(), (220, 224));
+
+        let report = encode_source(&small, "gif", &ConvertOptions::default())
+            .expect("Encoding failed");
+        assert_eq!(report.frames, 50);
+        let output = image::load_from_memory(&report.data).expect("Failed to read output");
+        assert_eq!((output.width(), output.height()), (110, 112));
+    }
+
+    #[test]
+    fn decoded_source_resize_is_held_to_the_limits() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let source = decode_pure(&img_data, &ConvertOptions::default()).expect("Decoding failed");
+
+        let result = source.resized(Some(100_000), None, &ConvertOptions::default());
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+
+        let mut options = ConvertOptions::default();
+        options.limits.max_width = Some(100);
+        let result = source.resized(Some(110), None, &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+        assert_eq!(source.dimensions(), (220, 224));
+    }
+
+    /// A 1x1 PNG whose IHDR claims 60000x60000 pixels.
+    fn png_bomb() -> Vec<u8> {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(1, 1))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .expect("Failed to build PNG");
+        // Signature (8), chunk length (4), "IHDR" (4), then width and height.
+        png[16..20].copy_from_slice(&60000u32.to_be_bytes());
+        png[20..24].copy_from_slice(&60000u32.to_be_bytes());
+        let crc = crc32fast::hash(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.to_be_bytes());
+        png
+    }
+
+    #[test]
+    fn convert_rejects_decompression_bombs() {
+        let err = convert_image_pure(&png_bomb(), "jpeg").expect_err("Bomb should not convert");
+        assert!(matches!(err, ConvertError::LimitExceeded(_)));
+        assert_eq!(err.code(), "limit_exceeded");
+        assert_eq!(err.stage(), "decode");
+    }
+
+    #[test]
+    fn convert_honours_configured_limits() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        options.limits.max_width = Some(16);
+
+        let result = convert_image_with_options(&img_data, "jpeg", &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+
+        options.limits = crate::options::DecodeLimits::new();
+        options.limits.max_memory_mb = Some(0);
+        let result = convert_image_with_options(&img_data, "jpeg", &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn convert_counts_every_animation_frame_against_the_limit() {
+        let img_path = get_asset_path("original.gif");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let mut options = ConvertOptions::default();
+        // One 220x224 frame fits in 1 MiB, all 50 do not.
+        options.limits.max_memory_mb = Some(1);
+
+        let result = convert_image_with_options(&img_data, "png", &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+
+        // Targets that cannot animate only decode the first frame.
+        let jpeg =
+            convert_image_detailed(&img_data, "jpeg", &options).expect("Conversion failed");
+        assert_eq!(jpeg.warnings, vec![ConvertWarning::AnimationFlattened { frames: 50 }]);
+
+        options.keep_animation = false;
+        convert_image_with_options(&img_data, "png", &options).expect("Conversion failed");
+    }
+
     #[test]
     fn convert_unsupported_format() {
         let img_path = get_asset_path("original.png");
         let img_data = fs::read(img_path).expect("Failed to read test image");
         let result = convert_image_pure(&img_data, "unsupported_format");
-        assert!(result.is_err());
+        assert!(matches!(result, Err(ConvertError::UnsupportedTarget(_))));
     }
 
     #[test]
     fn convert_invalid_image_data() {
         let invalid_data = b"This is not an image!";
         let result = convert_image_pure(invalid_data, "png");
-        assert!(result.is_err());
+        assert!(matches!(result, Err(ConvertError::DetectFailed(_))));
+    }
+
+    #[test]
+    fn convert_truncated_image_reports_decode_stage() {
+        let img_path = get_asset_path("original.png");
+        let img_data = fs::read(img_path).expect("Failed to read test image");
+        let err = convert_image_pure(&img_data[..img_data.len() / 2], "jpeg")
+            .expect_err("Truncated PNG should not convert");
+        assert_eq!(err.code(), "decode_failed");
+        assert_eq!(err.stage(), "decode");
     }
 }
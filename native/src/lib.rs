@@ -1,11 +1,41 @@
-use wasm_bindgen::JsError;
 use wasm_bindgen::prelude::wasm_bindgen;
-use crate::image_converter::convert_image_pure;
+use crate::error::JsConvertError;
+use crate::image_converter::{
+    convert_image_detailed, convert_image_multi_pure, convert_image_pure,
+    convert_image_with_options, extract_frames_pure, merge_to_tiff_pure, probe_image_pure,
+};
+use crate::options::ConvertOptions;
+use crate::report::{ConvertResult, ExtractedFrame, ImageInfo};
 
+mod animation;
+mod background;
+mod bmp;
+mod color;
+mod encoders;
+mod error;
+mod handle;
 mod image_converter;
+mod linear;
+mod metadata;
+mod options;
+mod pages;
+mod png_optimize;
+mod quantize;
+mod report;
+mod resize;
+mod tga;
+mod transform;
+mod vp8;
 
+/// `options` may be omitted from JS, in which case the defaults are used.
+/// Note that wasm-bindgen moves the options object into the call, so build a new
+/// `ConvertOptions` for every conversion.
 #[wasm_bindgen]
-pub fn convert_image(input_data: &[u8], target_format_str: &str) -> Result<Vec<u8>, JsError> {
+pub fn convert_image(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: Option<ConvertOptions>,
+) -> Result<Vec<u8>, JsConvertError> {
 
     // (Optional) Side effect: Logging to JS Console
     if let Ok(fmt) = image::guess_format(input_data) {
@@ -13,8 +43,80 @@ pub fn convert_image(input_data: &[u8], target_format_str: &str) -> Result<Vec<u
     }
 
     // Call the pure Rust function
-    match convert_image_pure(input_data, target_format_str) {
-        Ok(bytes) => Ok(bytes),
-        Err(e) => Err(JsError::new(&e)),
-    }
-}
\ No newline at end of file
+    let result = match options {
+        Some(options) => convert_image_with_options(input_data, target_format_str, &options),
+        None => convert_image_pure(input_data, target_format_str),
+    };
+
+    // Failures are thrown as a `ConvertError` object with `code`, `message` and `stage`.
+    result.map_err(JsConvertError::from)
+}
+
+/// Same as `convert_image`, but returns a `ConvertResult` that also carries the frame
+/// count and any warnings (e.g. an animation flattened to its first frame).
+#[wasm_bindgen]
+pub fn convert_image_with_report(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: Option<ConvertOptions>,
+) -> Result<ConvertResult, JsConvertError> {
+    let options = options.unwrap_or_default();
+
+    convert_image_detailed(input_data, target_format_str, &options)
+        .map(ConvertResult::from)
+        .map_err(JsConvertError::from)
+}
+
+/// Decodes the input once and converts it to each of `target_format_strs` (e.g.
+/// `["png", "webp", "jpeg"]`). Returns one `ConvertResult` per target, in the same order;
+/// one failing target fails the whole call.
+#[wasm_bindgen]
+pub fn convert_image_multi(
+    input_data: &[u8],
+    target_format_strs: Vec<String>,
+    options: Option<ConvertOptions>,
+) -> Result<Vec<ConvertResult>, JsConvertError> {
+    let options = options.unwrap_or_default();
+
+    convert_image_multi_pure(input_data, &target_format_strs, &options)
+        .map(|reports| reports.into_iter().map(ConvertResult::from).collect())
+        .map_err(JsConvertError::from)
+}
+
+/// Encodes every frame of an animated GIF/APNG/WebP as its own image in `target_format_str`,
+/// with its delay and start time. Set `frame_start`/`frame_end` on the options to pick a
+/// single frame or a range.
+#[wasm_bindgen]
+pub fn extract_frames(
+    input_data: &[u8],
+    target_format_str: &str,
+    options: Option<ConvertOptions>,
+) -> Result<Vec<ExtractedFrame>, JsConvertError> {
+    let options = options.unwrap_or_default();
+
+    extract_frames_pure(input_data, target_format_str, &options).map_err(JsConvertError::from)
+}
+
+/// Writes every image in `inputs` (any supported format) as the pages of one TIFF, e.g. to
+/// bundle scans into a single document. `options.tiff` picks the compression; resize and
+/// other settings apply to every page.
+#[wasm_bindgen]
+pub fn merge_to_tiff(
+    inputs: Vec<js_sys::Uint8Array>,
+    options: Option<ConvertOptions>,
+) -> Result<ConvertResult, JsConvertError> {
+    let options = options.unwrap_or_default();
+    let inputs: Vec<Vec<u8>> = inputs.iter().map(js_sys::Uint8Array::to_vec).collect();
+
+    merge_to_tiff_pure(&inputs, &options)
+        .map(ConvertResult::from)
+        .map_err(JsConvertError::from)
+}
+
+/// Reports the input's format, size, colour type, bit depth, frame count, alpha, ICC/EXIF
+/// presence and EXIF orientation. Only headers are read, so this is cheap even for large
+/// files and can run before deciding on a conversion.
+#[wasm_bindgen]
+pub fn probe_image(input_data: &[u8]) -> Result<ImageInfo, JsConvertError> {
+    probe_image_pure(input_data).map_err(JsConvertError::from)
+}
@@ -0,0 +1,265 @@
+use image::imageops::{self, FilterType};
+use image::{ColorType, DynamicImage, Rgba, Rgba32FImage};
+
+// ----------------------------------------------------------------
+// Linear light helpers (gamma correct blending and resampling)
+// ----------------------------------------------------------------
+
+/// sRGB encoded value (0-1) to linear light.
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Linear light value (0-1) to sRGB encoding.
+fn linear_to_srgb(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Float pixels (Radiance HDR, float TIFF) hold linear light that may go past 1.0.
+pub fn is_float(color: ColorType) -> bool {
+    matches!(color, ColorType::Rgb32F | ColorType::Rgba32F)
+}
+
+/// The image as linear light RGBA with the colour multiplied by alpha, so resampling
+/// weighs each pixel's colour by how much of it is actually there. Float images are
+/// linear already and keep their range.
+fn to_linear_premultiplied(img: &DynamicImage) -> Rgba32FImage {
+    let mut buf = img.to_rgba32f();
+    let float = is_float(img.color());
+    // Decoding 8-bit input through a table keeps `powf` out of the per-pixel loop.
+    let table: Vec<f32> = (0..=255u8).map(|v| srgb_to_linear(f32::from(v) / 255.0)).collect();
+    let eight_bit = img.color().bytes_per_pixel() == img.color().channel_count();
+    for Rgba([r, g, b, a]) in buf.pixels_mut() {
+        for c in [&mut *r, &mut *g, &mut *b] {
+            *c = if float {
+                *c
+            } else if eight_bit {
+                table[(*c * 255.0).round() as usize]
+            } else {
+                srgb_to_linear(c.clamp(0.0, 1.0))
+            } * *a;
+        }
+    }
+    buf
+}
+
+/// Undoes `to_linear_premultiplied` and converts back to `color`.
+fn from_linear_premultiplied(mut buf: Rgba32FImage, color: ColorType) -> DynamicImage {
+    let float = is_float(color);
+    for Rgba([r, g, b, a]) in buf.pixels_mut() {
+        *a = a.clamp(0.0, 1.0);
+        for c in [&mut *r, &mut *g, &mut *b] {
+            *c = match *a > 0.0 {
+                true if float => *c / *a,
+                true => linear_to_srgb(*c / *a),
+                false => 0.0,
+            };
+        }
+    }
+    with_color(DynamicImage::ImageRgba32F(buf), color)
+}
+
+/// `imageops::resize` for float buffers without its clamp to 0-1: the pixels are scaled
+/// into that range first and back afterwards, which the (linear) filters do not notice.
+pub fn resize_float(
+    buf: &Rgba32FImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> Rgba32FImage {
+    let peak = buf.iter().copied().filter(|v| v.is_finite()).fold(1.0f32, f32::max);
+    if peak <= 1.0 {
+        return imageops::resize(buf, width, height, filter);
+    }
+    let mut scaled = buf.clone();
+    scaled.iter_mut().for_each(|v| *v /= peak);
+    let mut resized = imageops::resize(&scaled, width, height, filter);
+    resized.iter_mut().for_each(|v| *v *= peak);
+    resized
+}
+
+/// `img` converted to the colour type `color` (bit depth and channels).
+pub fn with_color(img: DynamicImage, color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.into_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.into_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.into_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.into_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.into_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.into_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.into_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.into_rgb32f()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(img.into_rgba32f()),
+        _ => DynamicImage::ImageRgba8(img.into_rgba8()),
+    }
+}
+
+/// `DynamicImage::resize_exact`, but resampling in linear light with premultiplied alpha.
+/// Keeps the colour type of `img`.
+pub fn resize_exact(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    let linear = to_linear_premultiplied(img);
+    let resized = resize_float(&linear, width, height, filter);
+    from_linear_premultiplied(resized, img.color())
+}
+
+/// Composites `img` over the opaque `matte` in linear light. The result is opaque and
+/// keeps the depth of `img`; the caller drops the alpha channel.
+pub fn composite(img: &DynamicImage, matte: impl Fn(u32, u32) -> Rgba<u8>) -> DynamicImage {
+    let mut linear = to_linear_premultiplied(img);
+    for (x, y, Rgba([r, g, b, a])) in linear.enumerate_pixels_mut() {
+        let Rgba(background) = matte(x, y);
+        for (c, bg) in [&mut *r, &mut *g, &mut *b].into_iter().zip(background) {
+            *c += srgb_to_linear(f32::from(bg) / 255.0) * (1.0 - *a);
+        }
+        *a = 1.0;
+    }
+    from_linear_premultiplied(linear, img.color())
+}
+
+/// Tone maps float pixels holding linear light (Radiance HDR, float TIFF) to 8-bit sRGB for
+/// targets that cannot store floats. Extended Reinhard on luminance, with the brightest pixel
+/// as white: highlights are compressed instead of clipped, and images that stay within 0-1
+/// come out unchanged apart from the sRGB encoding.
+pub fn tone_map(img: &DynamicImage) -> DynamicImage {
+    let mut buf = img.to_rgba32f();
+    let luminance = |r: f32, g: f32, b: f32| 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let white = buf
+        .pixels()
+        .map(|&Rgba([r, g, b, _])| luminance(r, g, b))
+        .filter(|l| l.is_finite())
+        .fold(1.0f32, f32::max);
+
+    for Rgba([r, g, b, a]) in buf.pixels_mut() {
+        let l = luminance(*r, *g, *b);
+        let scale = if l > 0.0 && l.is_finite() {
+            (1.0 + l / (white * white)) / (1.0 + l)
+        } else {
+            0.0
+        };
+        for c in [&mut *r, &mut *g, &mut *b] {
+            *c = linear_to_srgb(*c * scale);
+        }
+        *a = a.clamp(0.0, 1.0);
+    }
+
+    let mapped = DynamicImage::ImageRgba32F(buf);
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(mapped.into_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(mapped.into_rgb8())
+    }
+}
+
+/// `img` as linear light RGB floats, for targets that store radiance. Float images are taken
+/// to be linear already; integer ones are decoded from sRGB. Alpha is dropped.
+pub fn to_linear_rgb32f(img: DynamicImage) -> DynamicImage {
+    if matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) {
+        return DynamicImage::ImageRgb32F(img.into_rgb32f());
+    }
+    let mut buf = img.into_rgb32f();
+    for c in buf.iter_mut() {
+        *c = srgb_to_linear(c.clamp(0.0, 1.0));
+    }
+    DynamicImage::ImageRgb32F(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    #[test]
+    fn transfer_functions_round_trip() {
+        for v in 0..=255u8 {
+            let value = f32::from(v) / 255.0;
+            let back = linear_to_srgb(srgb_to_linear(value));
+            assert!((back - value).abs() < 1e-4, "{} came back as {}", value, back);
+        }
+        // Mid grey in linear light is sRGB 188, not 128.
+        assert_eq!((linear_to_srgb(0.5) * 255.0).round(), 188.0);
+    }
+
+    #[test]
+    fn tone_mapping_compresses_highlights() {
+        let img = DynamicImage::ImageRgb32F(image::Rgb32FImage::from_fn(4, 1, |x, _| {
+            image::Rgb([[0.0, 0.5, 2.0, 8.0][x as usize]; 3])
+        }));
+        let DynamicImage::ImageRgb8(mapped) = tone_map(&img) else { panic!("expected RGB8") };
+        let values: Vec<u8> = mapped.pixels().map(|p| p[0]).collect();
+        // Clipping would turn both highlights into 255; here only the brightest one is white.
+        assert_eq!((values[0], values[3]), (0, 255));
+        assert!(values[1] < values[2] && values[2] < 255, "got {:?}", values);
+
+        // Within 0-1 nothing is compressed: linear 0.5 is sRGB 188.
+        let sdr = image::Rgb32FImage::from_pixel(1, 1, image::Rgb([0.5; 3]));
+        let sdr = DynamicImage::ImageRgb32F(sdr);
+        assert_eq!(tone_map(&sdr).to_rgb8().get_pixel(0, 0).0, [188; 3]);
+        let back = to_linear_rgb32f(tone_map(&sdr)).into_rgb32f();
+        assert!((back.get_pixel(0, 0)[0] - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn half_coverage_blends_to_the_linear_midpoint() {
+        // White at 50% alpha over black: the reference is sRGB(0.5 linear) = 188.
+        let white = Rgba([255, 255, 255, 128]);
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, white));
+        let flat = composite(&img, |_, _| Rgba([0, 0, 0, 255])).to_rgba8();
+        let Rgba([r, g, b, a]) = *flat.get_pixel(0, 0);
+        assert!((187..=189).contains(&r) && r == g && g == b, "got {}", r);
+        assert_eq!(a, 255);
+    }
+
+    #[test]
+    fn resizing_an_edge_keeps_its_colour() {
+        // An anti-aliased red logo edge next to transparent black pixels.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 0, 0, 0]) }
+        }));
+        // Straight sRGB averaging would give a dark red around [128, 0, 0, 128].
+        let edge = resize_exact(&img, 1, 1, FilterType::Triangle).to_rgba8();
+        assert_eq!(edge.get_pixel(0, 0).0, [255, 0, 0, 128]);
+
+        // Black and white average to linear mid grey.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        }));
+        let grey = resize_exact(&img, 1, 1, FilterType::Triangle).to_rgba8();
+        assert_eq!(grey.get_pixel(0, 0).0, [188, 188, 188, 255]);
+        assert_eq!(resize_exact(&img, 4, 4, FilterType::Nearest).color(), img.color());
+    }
+
+    #[test]
+    fn resizing_floats_keeps_radiance() {
+        // Radiance is linear already: 0 and 4 average to 2, not to a clamped, decoded 1.0.
+        let img = DynamicImage::ImageRgb32F(image::Rgb32FImage::from_fn(2, 1, |x, _| {
+            image::Rgb([[0.0, 4.0][x as usize], 0.5, 0.25])
+        }));
+        let resized = resize_exact(&img, 1, 1, FilterType::Triangle);
+        let DynamicImage::ImageRgb32F(resized) = resized else { panic!("expected Rgb32F") };
+        let [r, g, b] = resized.get_pixel(0, 0).0;
+        assert!((r - 2.0).abs() < 1e-4 && (g - 0.5).abs() < 1e-4 && (b - 0.25).abs() < 1e-4);
+
+        // Compositing keeps highlights too.
+        let img = DynamicImage::ImageRgba32F(image::Rgba32FImage::from_pixel(
+            1,
+            1,
+            Rgba([8.0, 8.0, 8.0, 0.5]),
+        ));
+        let flat = composite(&img, |_, _| Rgba([0, 0, 0, 255])).into_rgba32f();
+        assert_eq!(flat.get_pixel(0, 0).0, [4.0, 4.0, 4.0, 1.0]);
+    }
+}
@@ -0,0 +1,545 @@
+use image::ImageDecoder;
+use crate::options::MetadataPolicy;
+
+// ----------------------------------------------------------------
+// EXIF / XMP / ICC carried from the source to the output
+// ----------------------------------------------------------------
+//
+// Decoders hand out the raw blocks; the encoders write them back in whatever way the
+// target container stores them. EXIF is parsed and rebuilt instead of being copied byte
+// for byte, which is what lets a policy drop single tags. The thumbnail (IFD1) and the
+// MakerNote are never carried over: both point at data that no longer matches.
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_ARTIST: u16 = 0x013B;
+const TAG_COPYRIGHT: u16 = 0x8298;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_INTEROP_IFD: u16 = 0xA005;
+const TAG_MAKER_NOTE: u16 = 0x927C;
+
+/// IFD0 text tags that TIFF output can store natively (TIFF has no EXIF APP segment).
+pub const TIFF_TEXT_TAGS: [u16; 7] = [
+    0x010E, // ImageDescription
+    0x010F, // Make
+    0x0110, // Model
+    0x0131, // Software
+    0x0132, // DateTime
+    TAG_ARTIST,
+    TAG_COPYRIGHT,
+];
+
+/// Metadata blocks of one image, already filtered by the `MetadataPolicy`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// A TIFF structure ("II*\0" or "MM\0*"), without the JPEG "Exif\0\0" prefix.
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// Reads whatever the decoder exposes. Unreadable metadata never fails a conversion;
+    /// that block is just left out.
+    pub fn read<D: ImageDecoder + ?Sized>(decoder: &mut D) -> Metadata {
+        Metadata {
+            exif: decoder.exif_metadata().ok().flatten(),
+            xmp: decoder.xmp_metadata().ok().flatten(),
+            icc: decoder.icc_profile().ok().flatten(),
+        }
+    }
+
+    /// Keeps what `policy` allows. `oriented` means the pixels were already turned upright,
+    /// so the Orientation tag is reset to 1 instead of rotating the output a second time.
+    pub fn filter(self, policy: MetadataPolicy, oriented: bool) -> Metadata {
+        let exif = |keep: &dyn Fn(u16) -> bool| {
+            self.exif
+                .as_deref()
+                .and_then(Exif::parse)
+                .and_then(|mut exif| {
+                    exif.ifd0.retain(|entry| keep(entry.tag));
+                    if oriented {
+                        exif.reset_orientation();
+                    }
+                    (!exif.ifd0.is_empty()).then(|| exif.to_bytes())
+                })
+        };
+
+        match policy {
+            MetadataPolicy::Strip => Metadata::default(),
+            MetadataPolicy::KeepAll => Metadata {
+                exif: exif(&|_| true),
+                xmp: self.xmp.clone(),
+                icc: self.icc.clone(),
+            },
+            MetadataPolicy::CopyrightAndIcc => Metadata {
+                exif: exif(&|tag| tag == TAG_COPYRIGHT || tag == TAG_ARTIST),
+                xmp: None,
+                icc: self.icc.clone(),
+            },
+            MetadataPolicy::StripGps => Metadata {
+                exif: exif(&|tag| tag != TAG_GPS_IFD),
+                // XMP can repeat the location as exif:GPS* properties; drop such packets whole.
+                xmp: self.xmp.clone().filter(|xmp| !contains(xmp, b"exif:GPS")),
+                icc: self.icc.clone(),
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.icc.is_none()
+    }
+
+    /// IFD0 text tags (see `TIFF_TEXT_TAGS`) and their values.
+    pub fn exif_text_tags(&self) -> Vec<(u16, String)> {
+        let Some(exif) = self.exif.as_deref().and_then(Exif::parse) else {
+            return Vec::new();
+        };
+        exif.ifd0
+            .iter()
+            .filter(|entry| entry.kind == TYPE_ASCII && TIFF_TEXT_TAGS.contains(&entry.tag))
+            .map(|entry| {
+                let text = entry.value.split(|&b| b == 0).next().unwrap_or_default();
+                (entry.tag, String::from_utf8_lossy(text).into_owned())
+            })
+            .collect()
+    }
+
+    /// The EXIF Orientation value (1-8), if the tag is present.
+    pub fn exif_orientation(&self) -> Option<u16> {
+        let exif = self.exif.as_deref().and_then(Exif::parse)?;
+        exif.ifd0
+            .iter()
+            .find(|entry| entry.tag == TAG_ORIENTATION && entry.kind == TYPE_SHORT)
+            .map(|entry| exif.order.u16(&entry.value))
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+// ----------------------------------------------------------------
+// Minimal EXIF (TIFF structure) reader/writer
+// ----------------------------------------------------------------
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+
+/// Nesting is IFD0 -> Exif IFD -> Interop IFD at most.
+const MAX_DEPTH: u8 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(b),
+            ByteOrder::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(b),
+            ByteOrder::Big => u32::from_be_bytes(b),
+        }
+    }
+
+    fn put_u16(self, v: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Little => v.to_le_bytes(),
+            ByteOrder::Big => v.to_be_bytes(),
+        }
+    }
+
+    fn put_u32(self, v: u32) -> [u8; 4] {
+        match self {
+            ByteOrder::Little => v.to_le_bytes(),
+            ByteOrder::Big => v.to_be_bytes(),
+        }
+    }
+}
+
+/// One IFD field. Values keep the byte order of the source, which the writer reuses.
+#[derive(Clone, Debug)]
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Vec<u8>,
+    /// Set for pointers to the Exif, GPS and Interop IFDs.
+    sub_ifd: Option<Vec<Entry>>,
+}
+
+#[derive(Debug)]
+struct Exif {
+    order: ByteOrder,
+    ifd0: Vec<Entry>,
+}
+
+fn type_size(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+impl Exif {
+    fn parse(data: &[u8]) -> Option<Exif> {
+        let data = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+        let order = match data.get(..4)? {
+            [b'I', b'I', 42, 0] => ByteOrder::Little,
+            [b'M', b'M', 0, 42] => ByteOrder::Big,
+            _ => return None,
+        };
+        let offset = order.u32(data.get(4..8)?) as usize;
+        let ifd0 = parse_ifd(data, order, offset, 0)?;
+        Some(Exif { order, ifd0 })
+    }
+
+    fn reset_orientation(&mut self) {
+        let order = self.order;
+        if let Some(entry) = self
+            .ifd0
+            .iter_mut()
+            .find(|entry| entry.tag == TAG_ORIENTATION && entry.kind == TYPE_SHORT)
+        {
+            entry.count = 1;
+            entry.value = order.put_u16(1).to_vec();
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = match self.order {
+            ByteOrder::Little => vec![b'I', b'I', 42, 0],
+            ByteOrder::Big => vec![b'M', b'M', 0, 42],
+        };
+        out.extend(self.order.put_u32(8));
+        write_ifd(&self.ifd0, self.order, &mut out);
+        out
+    }
+}
+
+fn parse_ifd(data: &[u8], order: ByteOrder, offset: usize, depth: u8) -> Option<Vec<Entry>> {
+    let count = order.u16(data.get(offset..offset + 2)?) as usize;
+    let mut entries = Vec::with_capacity(count);
+
+    for i in 0..count {
+        let start = offset + 2 + i * 12;
+        let field = data.get(start..start + 12)?;
+        let tag = order.u16(&field[0..2]);
+        let kind = order.u16(&field[2..4]);
+        let count = order.u32(&field[4..8]);
+
+        if tag == TAG_MAKER_NOTE {
+            continue;
+        }
+        // Unknown field types cannot be sized, so they cannot be copied either.
+        let Some(size) = type_size(kind).and_then(|s| s.checked_mul(count as usize)) else {
+            continue;
+        };
+
+        if matches!(tag, TAG_EXIF_IFD | TAG_GPS_IFD | TAG_INTEROP_IFD) {
+            if depth + 1 >= MAX_DEPTH {
+                continue;
+            }
+            let target = order.u32(&field[8..12]) as usize;
+            if let Some(sub) = parse_ifd(data, order, target, depth + 1) {
+                entries.push(Entry {
+                    tag,
+                    kind: TYPE_LONG,
+                    count: 1,
+                    value: Vec::new(),
+                    sub_ifd: Some(sub),
+                });
+            }
+            continue;
+        }
+
+        let value = if size <= 4 {
+            field[8..8 + size].to_vec()
+        } else {
+            let target = order.u32(&field[8..12]) as usize;
+            match data.get(target..target.checked_add(size)?) {
+                Some(value) => value.to_vec(),
+                None => continue,
+            }
+        };
+        entries.push(Entry { tag, kind, count, value, sub_ifd: None });
+    }
+
+    entries.sort_by_key(|entry| entry.tag);
+    Some(entries)
+}
+
+/// Appends an IFD (and everything it points to) to `out` and returns its offset.
+fn write_ifd(entries: &[Entry], order: ByteOrder, out: &mut Vec<u8>) -> u32 {
+    // IFDs and values start on word boundaries.
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+    let start = out.len();
+    out.extend(order.put_u16(entries.len() as u16));
+    // 12 bytes per field, then a zero "next IFD" link: IFD1 (the thumbnail) is never written.
+    out.resize(start + 2 + entries.len() * 12 + 4, 0);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let value_field = match &entry.sub_ifd {
+            Some(sub) => order.put_u32(write_ifd(sub, order, out)),
+            None if entry.value.len() <= 4 => {
+                let mut inline = [0u8; 4];
+                inline[..entry.value.len()].copy_from_slice(&entry.value);
+                inline
+            }
+            None => {
+                if out.len() % 2 == 1 {
+                    out.push(0);
+                }
+                let offset = out.len() as u32;
+                out.extend(&entry.value);
+                order.put_u32(offset)
+            }
+        };
+
+        let slot = start + 2 + i * 12;
+        out[slot..slot + 2].copy_from_slice(&order.put_u16(entry.tag));
+        out[slot + 2..slot + 4].copy_from_slice(&order.put_u16(entry.kind));
+        out[slot + 4..slot + 8].copy_from_slice(&order.put_u32(entry.count));
+        out[slot + 8..slot + 12].copy_from_slice(&value_field);
+    }
+
+    start as u32
+}
+
+// ----------------------------------------------------------------
+// Container specific embedding
+// ----------------------------------------------------------------
+
+/// Inserts iCCP, eXIf and an XMP iTXt chunk right after IHDR of an encoded PNG/APNG.
+pub fn embed_png(png: Vec<u8>, metadata: &Metadata) -> Vec<u8> {
+    // 8 byte signature + IHDR (length, type, 13 data bytes, CRC).
+    const AFTER_IHDR: usize = 8 + 4 + 4 + 13 + 4;
+    if metadata.is_empty() || png.len() < AFTER_IHDR {
+        return png;
+    }
+
+    let mut chunks = Vec::new();
+    if let Some(icc) = &metadata.icc {
+        // Profile name, NUL, compression method 0 (zlib).
+        let mut data = b"ICC Profile\0\0".to_vec();
+        data.extend(miniz_oxide::deflate::compress_to_vec_zlib(icc, 9));
+        write_png_chunk(&mut chunks, b"iCCP", &data);
+    }
+    if let Some(exif) = &metadata.exif {
+        write_png_chunk(&mut chunks, b"eXIf", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // Keyword, NUL, uncompressed, method 0, empty language tag and translated keyword.
+        let mut data = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+        data.extend(xmp);
+        write_png_chunk(&mut chunks, b"iTXt", &data);
+    }
+
+    let mut out = Vec::with_capacity(png.len() + chunks.len());
+    out.extend(&png[..AFTER_IHDR]);
+    out.extend(chunks);
+    out.extend(&png[AFTER_IHDR..]);
+    out
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(kind);
+    out.extend(data);
+    out.extend(hasher.finalize().to_be_bytes());
+}
+
+const VP8X_ICC: u8 = 0x20;
+pub const VP8X_ALPHA: u8 = 0x10;
+const VP8X_EXIF: u8 = 0x08;
+const VP8X_XMP: u8 = 0x04;
+
+/// Rewrites an encoded WebP as the extended format with ICCP, EXIF and XMP chunks.
+/// Simple (VP8/VP8L only) files get a VP8X header sized `width` x `height`.
+pub fn embed_webp(
+    webp: Vec<u8>,
+    metadata: &Metadata,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> Vec<u8> {
+    if metadata.is_empty() || webp.get(..4) != Some(b"RIFF") || webp.get(8..12) != Some(b"WEBP") {
+        return webp;
+    }
+
+    let mut vp8x: Option<Vec<u8>> = None;
+    let mut image_chunks: Vec<(&[u8], &[u8])> = Vec::new();
+    let mut pos = 12;
+    while let Some(header) = webp.get(pos..pos + 8) {
+        let kind = &header[..4];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let Some(data) = webp.get(pos + 8..pos + 8 + size) else {
+            break;
+        };
+        match kind {
+            b"VP8X" => vp8x = Some(data.to_vec()),
+            // Replaced below by whatever the policy kept.
+            b"ICCP" | b"EXIF" | b"XMP " => {}
+            _ => image_chunks.push((kind, data)),
+        }
+        pos += 8 + size + size % 2;
+    }
+
+    let mut header = vp8x.unwrap_or_else(|| {
+        let mut header = vec![if has_alpha { VP8X_ALPHA } else { 0 }, 0, 0, 0];
+        header.extend(&(width.saturating_sub(1)).to_le_bytes()[..3]);
+        header.extend(&(height.saturating_sub(1)).to_le_bytes()[..3]);
+        header
+    });
+    header[0] &= !(VP8X_ICC | VP8X_EXIF | VP8X_XMP);
+    if metadata.icc.is_some() {
+        header[0] |= VP8X_ICC;
+    }
+    if metadata.exif.is_some() {
+        header[0] |= VP8X_EXIF;
+    }
+    if metadata.xmp.is_some() {
+        header[0] |= VP8X_XMP;
+    }
+
+    // Chunk order is fixed by the spec: VP8X, ICCP, image data, EXIF, XMP.
+    let mut body = b"WEBP".to_vec();
+    write_riff_chunk(&mut body, b"VP8X", &header);
+    if let Some(icc) = &metadata.icc {
+        write_riff_chunk(&mut body, b"ICCP", icc);
+    }
+    for (kind, data) in image_chunks {
+        write_riff_chunk(&mut body, kind, data);
+    }
+    if let Some(exif) = &metadata.exif {
+        write_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        write_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(body);
+    out
+}
+
+pub fn write_riff_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    out.extend(kind);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IFD0: Orientation 6, Artist "Jo", Copyright "(c) Jo 2024", pointers to Exif and GPS IFDs.
+    fn sample_exif() -> Vec<u8> {
+        let mut exif = vec![b'I', b'I', 42, 0, 8, 0, 0, 0];
+        let ifd0 = 8;
+        let exif_ifd = ifd0 + 2 + 5 * 12 + 4;
+        let gps_ifd = exif_ifd + 2 + 12 + 4;
+        let copyright = gps_ifd + 2 + 12 + 4;
+
+        exif.extend(5u16.to_le_bytes());
+        let mut field = |tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+            exif.extend(tag.to_le_bytes());
+            exif.extend(kind.to_le_bytes());
+            exif.extend(count.to_le_bytes());
+            exif.extend(value);
+        };
+        field(TAG_ORIENTATION, TYPE_SHORT, 1, [6, 0, 0, 0]);
+        field(TAG_ARTIST, TYPE_ASCII, 3, *b"Jo\0\0");
+        field(TAG_COPYRIGHT, TYPE_ASCII, 12, (copyright as u32).to_le_bytes());
+        field(TAG_EXIF_IFD, TYPE_LONG, 1, (exif_ifd as u32).to_le_bytes());
+        field(TAG_GPS_IFD, TYPE_LONG, 1, (gps_ifd as u32).to_le_bytes());
+        exif.extend([0, 0, 0, 0]);
+
+        // Exif IFD: ISO 200.
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([0x27, 0x88, 3, 0, 1, 0, 0, 0, 200, 0, 0, 0, 0, 0, 0, 0]);
+        // GPS IFD: GPSVersionID 2.3.0.0.
+        exif.extend(1u16.to_le_bytes());
+        exif.extend([0, 0, 1, 0, 4, 0, 0, 0, 2, 3, 0, 0, 0, 0, 0, 0]);
+
+        exif.extend(b"(c) Jo 2024\0");
+        exif
+    }
+
+    fn tags(exif: &[u8]) -> Vec<u16> {
+        Exif::parse(exif).unwrap().ifd0.iter().map(|e| e.tag).collect()
+    }
+
+    fn source() -> Metadata {
+        Metadata {
+            exif: Some(sample_exif()),
+            xmp: Some(b"<x:xmpmeta exif:GPSLatitude=\"1\"/>".to_vec()),
+            icc: Some(vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn rebuilt_exif_keeps_every_tag() {
+        let kept = source().filter(MetadataPolicy::KeepAll, false);
+        let exif = kept.exif.clone().expect("EXIF should be kept");
+        assert_eq!(
+            tags(&exif),
+            vec![TAG_ORIENTATION, TAG_ARTIST, TAG_COPYRIGHT, TAG_EXIF_IFD, TAG_GPS_IFD]
+        );
+        assert_eq!(kept.exif_orientation(), Some(6));
+        assert!(kept.exif_text_tags().contains(&(TAG_COPYRIGHT, "(c) Jo 2024".to_string())));
+        assert_eq!(kept.icc, Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn oriented_pixels_reset_the_orientation_tag() {
+        let kept = source().filter(MetadataPolicy::KeepAll, true);
+        assert_eq!(kept.exif_orientation(), Some(1));
+    }
+
+    #[test]
+    fn policies_select_blocks_and_tags() {
+        assert!(source().filter(MetadataPolicy::Strip, false).is_empty());
+
+        let copyright = source().filter(MetadataPolicy::CopyrightAndIcc, false);
+        assert_eq!(tags(&copyright.exif.unwrap()), vec![TAG_ARTIST, TAG_COPYRIGHT]);
+        assert!(copyright.xmp.is_none());
+        assert!(copyright.icc.is_some());
+
+        let no_gps = source().filter(MetadataPolicy::StripGps, false);
+        assert!(!tags(no_gps.exif.as_ref().unwrap()).contains(&TAG_GPS_IFD));
+        assert!(tags(no_gps.exif.as_ref().unwrap()).contains(&TAG_EXIF_IFD));
+        assert!(no_gps.xmp.is_none());
+    }
+
+    #[test]
+    fn malformed_exif_is_dropped() {
+        let broken =
+            Metadata { exif: Some(b"II*\0\xff\xff\xff\xff".to_vec()), ..Metadata::default() };
+        assert!(broken.filter(MetadataPolicy::KeepAll, false).exif.is_none());
+    }
+}
@@ -0,0 +1,757 @@
+use image::imageops::FilterType;
+use image::{ImageResult, Limits, Rgba};
+use wasm_bindgen::prelude::wasm_bindgen;
+use crate::background::Background;
+use crate::error::{ConvertError, JsConvertError};
+use crate::quantize::QuantizeSettings;
+use crate::transform::Transform;
+
+// ----------------------------------------------------------------
+// Conversion Options (shared by the pure Rust API and the WASM bridge)
+// ----------------------------------------------------------------
+
+/// What happens to metadata (EXIF, XMP, ICC) carried by the source image.
+/// Only JPEG, PNG, WebP and TIFF outputs can hold metadata; TIFF keeps the ICC profile,
+/// XMP and the descriptive EXIF text tags (camera, date, artist, copyright).
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetadataPolicy {
+    /// Drop every metadata chunk. Re-encoding never copies anything across.
+    #[default]
+    Strip,
+    /// Copy EXIF, XMP and the ICC profile. The EXIF thumbnail and MakerNote are dropped.
+    KeepAll,
+    /// Keep only the EXIF Copyright and Artist tags and the ICC profile.
+    CopyrightAndIcc,
+    /// Keep everything except location: the EXIF GPS block and XMP packets with GPS data.
+    StripGps,
+}
+
+/// What happens to pixels tagged with an ICC profile (Display P3, Adobe RGB, CMYK, ...).
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IccHandling {
+    /// Transform the pixels to sRGB and write an untagged image, which every viewer shows
+    /// the same way. Handles RGB and grayscale profiles, and CMYK profiles in JPEG input.
+    #[default]
+    ConvertToSrgb,
+    /// Keep the pixel values and embed the profile in the output, whatever `metadata` says.
+    /// Falls back to converting for targets that cannot hold a profile and for non-RGB ones.
+    Embed,
+    /// Leave the pixels alone. The profile is only copied when `metadata` keeps it.
+    Ignore,
+}
+
+/// How `width` and `height` are combined when both are set. With only one of them the
+/// other follows from the aspect ratio and every mode gives the same result.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFit {
+    /// Scale to fit inside the box, then pad to exactly the box with transparency
+    /// (the background colour for targets without alpha).
+    Contain,
+    /// Scale to cover the box, then crop the overflow evenly from both sides.
+    Cover,
+    /// Stretch to exactly the box, ignoring the aspect ratio.
+    Fill,
+    /// Scale to fit inside the box. The output may be smaller than the box on one side.
+    #[default]
+    Inside,
+    /// Scale to cover the box. The output may be larger than the box on one side.
+    Outside,
+}
+
+/// Resampling filter used by the resize step, from fastest to sharpest.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    /// Blocky, but keeps hard pixel edges (pixel art, QR codes).
+    Nearest,
+    /// Bilinear.
+    Triangle,
+    CatmullRom,
+    /// Soft; hides noise at the cost of detail.
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Chroma subsampling used by the JPEG encoder.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// Full colour resolution. Best for text, line art and sharp edges.
+    Yuv444,
+    /// Half horizontal colour resolution.
+    Yuv422,
+    /// Half horizontal and vertical colour resolution. Smallest files.
+    Yuv420,
+}
+
+/// JPEG specific settings. The quality comes from `ConvertOptions::quality`.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct JpegOptions {
+    /// Write a progressive JPEG instead of a baseline one.
+    pub progressive: bool,
+    /// Compute Huffman tables for this image instead of using the standard ones.
+    /// Slightly smaller files at the cost of encoding speed.
+    pub optimize_huffman: bool,
+    /// `None` lets the encoder choose (4:2:0 below quality 90, 4:4:4 above).
+    pub subsampling: Option<ChromaSubsampling>,
+}
+
+#[wasm_bindgen]
+impl JpegOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> JpegOptions {
+        JpegOptions::default()
+    }
+}
+
+/// Row filter applied before deflate compression in PNG output.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PngFilter {
+    NoFilter,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Pick the best filter for each row.
+    #[default]
+    Adaptive,
+}
+
+/// PNG specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct PngOptions {
+    /// Deflate level from 0 (store) to 9 (smallest). `None` uses the encoder's fast default,
+    /// or 9 when `optimize` is set.
+    pub compression_level: Option<u8>,
+    pub filter: PngFilter,
+    /// Lossless optimization pass: reduces bit depth and colour type (palette, grayscale,
+    /// no alpha) where possible and tries filters, keeping the smallest result.
+    /// `filter` is ignored when this is on. Costs up to four full encodes at
+    /// `compression_level` (9 unless set), so it takes several times as long as a plain
+    /// encode; lower the level for large images.
+    pub optimize: bool,
+}
+
+#[wasm_bindgen]
+impl PngOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> PngOptions {
+        PngOptions::default()
+    }
+}
+
+/// WebP specific settings. The lossy quality comes from `ConvertOptions::quality`.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct WebpOptions {
+    /// Write lossless WebP instead of lossy.
+    pub lossless: bool,
+    /// Effort from 0 (fastest) to 6 (smallest file). Without libwebp, lossy output only
+    /// tells 0 (DC prediction only) apart from the rest.
+    pub method: u8,
+    /// Quality of the alpha plane (0-100) for lossy output.
+    pub alpha_quality: u8,
+    /// Near-lossless preprocessing strength (0-100, 100 = off). Only used with `lossless`.
+    pub near_lossless: Option<u8>,
+}
+
+#[wasm_bindgen]
+impl WebpOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WebpOptions {
+        WebpOptions::default()
+    }
+}
+
+impl Default for WebpOptions {
+    fn default() -> Self {
+        WebpOptions {
+            lossless: false,
+            method: 4,
+            alpha_quality: 100,
+            near_lossless: None,
+        }
+    }
+}
+
+/// AVIF specific settings (needs the `avif` feature). The colour quality comes from
+/// `ConvertOptions::quality`.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct AvifOptions {
+    /// Encoder effort from 1 (slowest, smallest file) to 10 (fastest). AV1 is slow to
+    /// encode, especially in WASM, so the default leans towards speed.
+    pub speed: u8,
+    /// Quality of the alpha plane (1-100).
+    pub alpha_quality: u8,
+}
+
+#[wasm_bindgen]
+impl AvifOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> AvifOptions {
+        AvifOptions::default()
+    }
+}
+
+impl Default for AvifOptions {
+    fn default() -> Self {
+        AvifOptions { speed: 6, alpha_quality: 100 }
+    }
+}
+
+/// How a palette is picked when an image has more colours than fit.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quantizer {
+    /// Neural network quantizer. Fast, good on photos; `sample_factor` trades speed for
+    /// quality. Palettes under 64 colours use `MedianCut` instead, reported as a
+    /// `quantizer_replaced` warning.
+    #[default]
+    NeuQuant,
+    /// Median cut refined with k-means (the libimagequant approach). Slower, but keeps
+    /// small palettes and flat artwork closer to the source.
+    MedianCut,
+}
+
+/// How colours between palette entries are approximated.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Nearest entry only. Smallest files, visible banding on gradients.
+    #[default]
+    None,
+    /// Error diffusion. Smoothest gradients, noisy look.
+    FloydSteinberg,
+    /// 4x4 Bayer pattern. Regular cross-hatch, compresses better than error diffusion.
+    Ordered,
+}
+
+/// GIF specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct GifOptions {
+    /// Images larger than this (in either dimension) are downscaled before encoding,
+    /// since quantizing big frames is slow. `None` keeps the full size.
+    pub max_dimension: Option<u32>,
+    /// Palette size per frame, 2-256, including the transparent entry.
+    pub colors: u16,
+    pub quantizer: Quantizer,
+    /// NeuQuant sampling factor from 1 (best, slowest) to 30 (fastest).
+    pub sample_factor: u8,
+    pub dither: Dither,
+    /// GIF transparency is on or off per pixel: pixels with a lower alpha become
+    /// transparent (using the last palette entry), the rest opaque. 0 keeps every pixel.
+    pub alpha_threshold: u8,
+}
+
+#[wasm_bindgen]
+impl GifOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> GifOptions {
+        GifOptions::default()
+    }
+}
+
+impl Default for GifOptions {
+    fn default() -> Self {
+        GifOptions {
+            max_dimension: Some(800),
+            colors: 256,
+            quantizer: Quantizer::default(),
+            sample_factor: 10,
+            dither: Dither::default(),
+            alpha_threshold: 128,
+        }
+    }
+}
+
+impl GifOptions {
+    pub fn quantize_settings(&self) -> QuantizeSettings {
+        QuantizeSettings {
+            colors: self.colors,
+            quantizer: self.quantizer,
+            sample_factor: self.sample_factor,
+            dither: self.dither,
+            alpha_threshold: self.alpha_threshold,
+        }
+    }
+}
+
+/// Entry sizes of a favicon that looks sharp everywhere from browser tabs to desktops.
+const FAVICON_SIZES: [u32; 7] = [16, 24, 32, 48, 64, 128, 256];
+
+/// ICO specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct IcoOptions {
+    /// ICO entries cannot exceed 256x256, so this is clamped to 256. Only used when `sizes`
+    /// is empty.
+    pub max_dimension: u32,
+    /// Square entry sizes (1-256) to store in one file, each resampled from the full
+    /// image and padded to a square. Empty writes a single entry capped at `max_dimension`.
+    /// From JS this is a copied array, so assign a new one rather than pushing to it.
+    #[wasm_bindgen(getter_with_clone)]
+    pub sizes: Vec<u32>,
+    /// Entries at least this large are stored as PNG, smaller ones as uncompressed BMP,
+    /// which old Windows versions need.
+    pub png_min_size: u32,
+}
+
+#[wasm_bindgen]
+impl IcoOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> IcoOptions {
+        IcoOptions::default()
+    }
+
+    /// The usual favicon set: 16, 24, 32, 48, 64, 128 and 256 pixels.
+    pub fn favicon() -> IcoOptions {
+        IcoOptions { sizes: FAVICON_SIZES.to_vec(), ..IcoOptions::default() }
+    }
+}
+
+impl Default for IcoOptions {
+    fn default() -> Self {
+        IcoOptions { max_dimension: 256, sizes: Vec::new(), png_min_size: 256 }
+    }
+}
+
+/// Compression of TIFF output. All of them are lossless.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TiffCompression {
+    /// Raw samples. Largest files, readable by everything.
+    #[default]
+    None,
+    /// LZW, the most widely supported compression.
+    Lzw,
+    /// Deflate (zlib). Usually the smallest files.
+    Deflate,
+    /// Run-length encoding. Fast and good on scanned text and line art, poor on photos.
+    PackBits,
+}
+
+/// TIFF specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct TiffOptions {
+    pub compression: TiffCompression,
+    /// Store differences between neighbouring samples instead of the samples, which makes
+    /// LZW and Deflate much better on photos. Ignored for float images and `None`.
+    pub predictor: bool,
+}
+
+#[wasm_bindgen]
+impl TiffOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TiffOptions {
+        TiffOptions::default()
+    }
+}
+
+/// Pixel layout of BMP output.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BmpFormat {
+    /// 24-bit RGB, or 32-bit RGBA when the image has transparency.
+    #[default]
+    Auto,
+    /// 2 colour palette (black and white displays).
+    Indexed1,
+    /// 16 colour palette.
+    Indexed4,
+    /// 256 colour palette.
+    Indexed8,
+    /// 16-bit 5-6-5 RGB, the native format of many small LCD controllers.
+    Rgb565,
+    Rgb24,
+    Rgba32,
+}
+
+impl BmpFormat {
+    /// Whether the layout stores alpha. Images written without it are flattened first.
+    pub fn has_alpha(self) -> bool {
+        matches!(self, BmpFormat::Auto | BmpFormat::Rgba32)
+    }
+}
+
+/// BMP specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct BmpOptions {
+    pub format: BmpFormat,
+    /// Store the top row first (negative height) instead of the usual bottom-up order.
+    pub top_down: bool,
+    /// How the paletted formats build their palette.
+    pub quantizer: Quantizer,
+    /// NeuQuant sampling factor from 1 (best, slowest) to 30 (fastest).
+    pub sample_factor: u8,
+    /// How the paletted formats approximate colours that are not in the palette.
+    pub dither: Dither,
+}
+
+#[wasm_bindgen]
+impl BmpOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> BmpOptions {
+        BmpOptions::default()
+    }
+}
+
+impl BmpOptions {
+    /// Palette settings for the paletted formats, `None` for the direct colour ones.
+    pub fn quantize_settings(&self) -> Option<QuantizeSettings> {
+        let bits = match self.format {
+            BmpFormat::Indexed1 => 1,
+            BmpFormat::Indexed4 => 4,
+            BmpFormat::Indexed8 => 8,
+            _ => return None,
+        };
+        Some(QuantizeSettings {
+            colors: 1 << bits,
+            quantizer: self.quantizer,
+            sample_factor: self.sample_factor,
+            dither: self.dither,
+            // BMP palettes have no transparent entry.
+            alpha_threshold: 0,
+        })
+    }
+}
+
+impl Default for BmpOptions {
+    fn default() -> Self {
+        BmpOptions {
+            format: BmpFormat::default(),
+            top_down: false,
+            quantizer: Quantizer::default(),
+            sample_factor: 10,
+            dither: Dither::default(),
+        }
+    }
+}
+
+/// Which Netpbm format PNM output uses.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PnmFormat {
+    /// PGM for gray and PPM for colour images, or PAM for ones with alpha or 16-bit samples.
+    #[default]
+    Auto,
+    /// PBM: black and white, thresholded at mid gray.
+    Pbm,
+    /// PGM: 8-bit gray.
+    Pgm,
+    /// PPM: 8-bit RGB.
+    Ppm,
+    /// PAM: keeps alpha and 16-bit samples. Some older tools cannot read it.
+    Pam,
+}
+
+impl PnmFormat {
+    /// Whether the format stores alpha. Images written without it are flattened first.
+    pub fn has_alpha(self) -> bool {
+        matches!(self, PnmFormat::Auto | PnmFormat::Pam)
+    }
+}
+
+/// PNM (PBM/PGM/PPM/PAM) specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct PnmOptions {
+    pub format: PnmFormat,
+    /// Write the samples as decimal text (P1/P2/P3) instead of binary. Ignored for PAM.
+    pub ascii: bool,
+}
+
+#[wasm_bindgen]
+impl PnmOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> PnmOptions {
+        PnmOptions::default()
+    }
+}
+
+/// TGA specific settings.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct TgaOptions {
+    /// Run-length encode the pixels. Much smaller for flat artwork; turn it off for old
+    /// tools that only read uncompressed TGA.
+    pub rle: bool,
+}
+
+#[wasm_bindgen]
+impl TgaOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TgaOptions {
+        TgaOptions::default()
+    }
+}
+
+impl Default for TgaOptions {
+    fn default() -> Self {
+        TgaOptions { rle: true }
+    }
+}
+
+/// Guards against decompression bombs: a small file can declare a huge canvas. Inputs over
+/// these limits fail with a `limit_exceeded` error before any pixel memory is allocated.
+/// Resizing, padding and rotating are held to the same limits, since their output size
+/// comes from the caller.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct DecodeLimits {
+    /// Widest accepted input in pixels. `None` disables the check.
+    pub max_width: Option<u32>,
+    /// Tallest accepted input in pixels. `None` disables the check.
+    pub max_height: Option<u32>,
+    /// Memory the decoded pixels may take, in MiB, counting every frame of an animation.
+    /// `None` disables the check, which risks aborting the WASM module on huge inputs.
+    pub max_memory_mb: Option<u32>,
+}
+
+#[wasm_bindgen]
+impl DecodeLimits {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> DecodeLimits {
+        DecodeLimits::default()
+    }
+}
+
+impl DecodeLimits {
+    /// The same limits in the form the `image` decoders take.
+    pub fn to_image_limits(&self) -> Limits {
+        let mut limits = Limits::no_limits();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_memory_mb.map(|mb| u64::from(mb) << 20);
+        limits
+    }
+
+    /// Fails with an `ImageError::Limits` if `frames` frames of `bytes_per_frame` each at
+    /// `width` x `height` would not fit. Meant to run on the headers, before decoding.
+    pub fn check(
+        &self,
+        (width, height): (u32, u32),
+        bytes_per_frame: u64,
+        frames: u32,
+    ) -> ImageResult<()> {
+        let mut limits = self.to_image_limits();
+        limits.check_dimensions(width, height)?;
+        limits.reserve(bytes_per_frame.saturating_mul(u64::from(frames)))
+    }
+
+    /// Fails with `LimitExceeded` if an RGBA canvas of `width` x `height` with
+    /// `bytes_per_channel` bytes per sample would not fit. Run before growing an image.
+    pub fn check_canvas(
+        &self,
+        (width, height): (u32, u32),
+        bytes_per_channel: u8,
+    ) -> Result<(), ConvertError> {
+        let bytes = u64::from(width)
+            .saturating_mul(u64::from(height))
+            .saturating_mul(4 * u64::from(bytes_per_channel));
+        self.check((width, height), bytes, 1).map_err(ConvertError::from_decode)
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        // 16384 is the largest side most browsers can draw; 512 MiB matches `image`.
+        DecodeLimits { max_width: Some(16384), max_height: Some(16384), max_memory_mb: Some(512) }
+    }
+}
+
+/// Everything that tunes a single conversion.
+///
+/// From JavaScript:
+/// ```js
+/// const options = new ConvertOptions();
+/// options.quality = 85;
+/// options.width = 1024;
+/// options.set_background(0, 0, 0);
+/// const bytes = convert_image(input, "jpeg", options);
+/// ```
+///
+/// The per-format settings (`jpeg`, `png`, ...) and `limits` are handed to JS as copies, so
+/// `options.jpeg.progressive = true` changes a temporary object and is lost. Take a copy,
+/// change it and assign it back:
+/// ```js
+/// const jpeg = options.jpeg;
+/// jpeg.progressive = true;
+/// options.jpeg = jpeg;
+/// ```
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    /// Quality for lossy encoders (1-100). `None` keeps each encoder's default.
+    pub quality: Option<u8>,
+    /// Output width. How it combines with `height` depends on `fit`.
+    pub width: Option<u32>,
+    /// Output height. How it combines with `width` depends on `fit`.
+    pub height: Option<u32>,
+    pub fit: ResizeFit,
+    /// Scale both sides by this percentage (50 halves the image). Only used when neither
+    /// `width` nor `height` is set.
+    pub scale_percent: Option<f32>,
+    /// Never make the image larger than the source; smaller targets still shrink it.
+    pub never_upscale: bool,
+    pub filter: ResizeFilter,
+    /// Resample and blend transparency in linear light (with premultiplied alpha) instead
+    /// of on the sRGB values. Avoids dark fringes and halos around anti-aliased edges, at
+    /// the cost of slower resizing.
+    pub linear_light: bool,
+    pub metadata: MetadataPolicy,
+    pub icc: IccHandling,
+    /// Rotate/flip the image according to its EXIF Orientation tag before anything else.
+    pub auto_orient: bool,
+    /// Keep every frame of animated GIF/APNG/WebP inputs when the target can animate.
+    /// When off (or when the target is a still format) only the first frame is converted.
+    pub keep_animation: bool,
+    /// Keep every page of multi-page TIFF inputs when the target is TIFF. When off (or for
+    /// other targets) only the first page is converted.
+    pub keep_pages: bool,
+    /// First frame (0-based) returned by `extract_frames`. `None` starts at the first frame.
+    pub frame_start: Option<u32>,
+    /// Last frame (inclusive) returned by `extract_frames`. `None` runs to the last frame.
+    pub frame_end: Option<u32>,
+    /// Composite transparent pixels onto the background for every target and write an
+    /// image without alpha. JPEG output is always flattened.
+    pub remove_alpha: bool,
+    /// JPEG settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub jpeg: JpegOptions,
+    /// PNG settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub png: PngOptions,
+    /// WebP settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub webp: WebpOptions,
+    /// AVIF settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub avif: AvifOptions,
+    /// GIF settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub gif: GifOptions,
+    /// ICO settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub ico: IcoOptions,
+    /// TIFF settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub tiff: TiffOptions,
+    /// BMP settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub bmp: BmpOptions,
+    /// TGA settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub tga: TgaOptions,
+    /// PNM settings.
+    #[wasm_bindgen(getter_with_clone)]
+    pub pnm: PnmOptions,
+    /// Decode limits.
+    #[wasm_bindgen(getter_with_clone)]
+    pub limits: DecodeLimits,
+    background: Background,
+    transforms: Vec<Transform>,
+}
+
+#[wasm_bindgen]
+impl ConvertOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> ConvertOptions {
+        ConvertOptions::default()
+    }
+
+    /// Colour used when transparency has to be flattened (e.g. for JPEG).
+    pub fn set_background(&mut self, r: u8, g: u8, b: u8) {
+        self.background = Background::Color(Rgba([r, g, b, 255]));
+    }
+
+    /// Same as `set_background`, from "#rrggbb", "#rgb", "black" or "white".
+    pub fn set_background_color(&mut self, value: &str) -> Result<(), JsConvertError> {
+        self.background = Background::parse_color(value)?;
+        Ok(())
+    }
+
+    /// Flattens transparency onto a light/dark checkerboard of `size` pixel squares
+    /// instead of a colour, to show where the image was transparent in previews.
+    pub fn set_background_checkerboard(&mut self, size: u32) {
+        self.background = Background::Checkerboard { size: size.max(1) };
+    }
+
+    /// Appends a crop, rotate or flip step. Steps run in the order they were added, before
+    /// the resize.
+    pub fn add_transform(&mut self, transform: &Transform) {
+        self.transforms.push(*transform);
+    }
+
+    pub fn clear_transforms(&mut self) {
+        self.transforms.clear();
+    }
+}
+
+impl ConvertOptions {
+    pub fn background(&self) -> &Background {
+        &self.background
+    }
+
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            quality: None,
+            width: None,
+            height: None,
+            fit: ResizeFit::default(),
+            scale_percent: None,
+            never_upscale: false,
+            filter: ResizeFilter::default(),
+            linear_light: false,
+            metadata: MetadataPolicy::default(),
+            icc: IccHandling::default(),
+            auto_orient: true,
+            keep_animation: true,
+            keep_pages: true,
+            frame_start: None,
+            frame_end: None,
+            remove_alpha: false,
+            jpeg: JpegOptions::default(),
+            png: PngOptions::default(),
+            webp: WebpOptions::default(),
+            avif: AvifOptions::default(),
+            gif: GifOptions::default(),
+            ico: IcoOptions::default(),
+            tiff: TiffOptions::default(),
+            bmp: BmpOptions::default(),
+            tga: TgaOptions::default(),
+            pnm: PnmOptions::default(),
+            limits: DecodeLimits::default(),
+            background: Background::default(),
+            transforms: Vec::new(),
+        }
+    }
+}
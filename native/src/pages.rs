@@ -0,0 +1,293 @@
+use image::metadata::Orientation;
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageFormat, Luma, Rgb, RgbImage};
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Seek};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+use crate::error::ConvertError;
+use crate::options::DecodeLimits;
+
+// ----------------------------------------------------------------
+// Multi-page TIFF (scanned documents, faxes)
+// ----------------------------------------------------------------
+//
+// The `image` TIFF decoder only reads the first image directory, so the pages of a
+// multi-page file are read with the `tiff` crate directly. Pages can differ in size and
+// colour type; each one becomes its own `DynamicImage`.
+
+/// Pages past this are ignored. Each directory is parsed to find the next one, so a crafted
+/// chain of tiny directories would otherwise make even probing cost grow with the file.
+pub const MAX_TIFF_PAGES: u32 = 1024;
+
+/// Number of pages (image directories) in a TIFF, read without decoding any pixels. Stops
+/// at `MAX_TIFF_PAGES` and at a directory that was already visited.
+pub fn count_tiff_pages(input_data: &[u8]) -> u32 {
+    let Ok(mut decoder) = Decoder::new(Cursor::new(input_data)) else {
+        return 1;
+    };
+    let mut pages = 1;
+    let mut seen = HashSet::new();
+    while pages < MAX_TIFF_PAGES && next_page(&mut decoder, &mut seen) {
+        pages += 1;
+    }
+    pages
+}
+
+/// Moves to the next directory. False at the end of the chain, on a broken directory, and
+/// on one that points back into the chain.
+fn next_page<R: Read + Seek>(decoder: &mut Decoder<R>, seen: &mut HashSet<u64>) -> bool {
+    if let Some(ifd) = decoder.ifd_pointer() {
+        seen.insert(ifd.0);
+    }
+    decoder.more_images()
+        && decoder.next_image().is_ok()
+        && decoder.ifd_pointer().is_some_and(|ifd| seen.insert(ifd.0))
+}
+
+/// Decodes every page of a TIFF, applying each page's own Orientation tag when `auto_orient`
+/// is set. Returns `None` for files with a single page, so callers take the still image
+/// path. Fails with `LimitExceeded` before a page is decoded if it, together with the pages
+/// before it, would break `limits`.
+pub fn decode_tiff_pages(
+    input_data: &[u8],
+    limits: &DecodeLimits,
+    auto_orient: bool,
+) -> Result<Option<Vec<DynamicImage>>, ConvertError> {
+    if count_tiff_pages(input_data) < 2 {
+        return Ok(None);
+    }
+
+    let to_error = |e| ConvertError::decoder(ImageFormat::Tiff, e);
+    let mut decoder = Decoder::new(Cursor::new(input_data)).map_err(to_error)?;
+    let mut pages = Vec::new();
+    let mut total_bytes = 0u64;
+    loop {
+        let (width, height) = decoder.dimensions().map_err(to_error)?;
+        let color = decoder.colortype().map_err(to_error)?;
+        let page_bytes = u64::from(width) * u64::from(height) * bytes_per_pixel(color);
+        total_bytes = total_bytes.saturating_add(page_bytes);
+        limits.check((width, height), total_bytes, 1).map_err(ConvertError::from_decode)?;
+
+        let orientation = match auto_orient {
+            true => decoder.find_tag_unsigned::<u8>(Tag::Orientation).ok().flatten(),
+            false => None,
+        };
+        let mut page = decode_page(&mut decoder, width, height, color)?;
+        if let Some(orientation) = orientation.and_then(Orientation::from_exif) {
+            page.apply_orientation(orientation);
+        }
+        pages.push(page);
+        if pages.len() as u32 >= MAX_TIFF_PAGES || !decoder.more_images() {
+            break;
+        }
+        decoder.next_image().map_err(to_error)?;
+    }
+
+    Ok(Some(pages))
+}
+
+/// Decoded size of one pixel of `color`, as `decode_page` stores it.
+fn bytes_per_pixel(color: ColorType) -> u64 {
+    match color {
+        ColorType::Gray(1 | 8) => 1,
+        ColorType::Gray(16) | ColorType::GrayA(8) => 2,
+        ColorType::RGB(8) | ColorType::CMYK(8) => 3,
+        ColorType::RGB(16) | ColorType::CMYK(16) => 6,
+        ColorType::RGBA(16) => 8,
+        ColorType::RGB(32) => 12,
+        ColorType::RGBA(32) => 16,
+        _ => 4,
+    }
+}
+
+/// Decodes the page the decoder is on, with the same colour mapping as the `image` crate
+/// (bilevel expanded to 8-bit gray, CMYK converted to RGB).
+fn decode_page<R: Read + Seek>(
+    decoder: &mut Decoder<R>,
+    width: u32,
+    height: u32,
+    color: ColorType,
+) -> Result<DynamicImage, ConvertError> {
+    let data = decoder
+        .read_image()
+        .map_err(|e| ConvertError::decoder(ImageFormat::Tiff, e))?;
+
+    let img = match (color, data) {
+        (ColorType::Gray(1), DecodingResult::U8(bits)) => {
+            // Rows are packed most significant bit first and padded to whole bytes.
+            let row_bytes = width.div_ceil(8) as usize;
+            (bits.len() >= row_bytes * height as usize).then(|| {
+                DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+                    let byte = bits[y as usize * row_bytes + x as usize / 8];
+                    Luma([if byte & (0x80 >> (x % 8)) != 0 { 255 } else { 0 }])
+                }))
+            })
+        }
+        (ColorType::Gray(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayA(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::RGB(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::RGBA(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Gray(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayA(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::RGB(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::RGBA(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba16)
+        }
+        (ColorType::RGB(32), DecodingResult::F32(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb32F)
+        }
+        (ColorType::RGBA(32), DecodingResult::F32(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba32F)
+        }
+        (ColorType::CMYK(8), DecodingResult::U8(v)) => {
+            let rgb: Vec<u8> = v.chunks_exact(4).flat_map(|cmyk| cmyk_to_rgb(cmyk).0).collect();
+            RgbImage::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::CMYK(16), DecodingResult::U16(v)) => {
+            let rgb: Vec<u16> = v.chunks_exact(4).flat_map(|cmyk| cmyk16_to_rgb(cmyk).0).collect();
+            ImageBuffer::from_raw(width, height, rgb).map(DynamicImage::ImageRgb16)
+        }
+        (color, _) => {
+            return Err(ConvertError::decoder(
+                ImageFormat::Tiff,
+                format!("pages with colour type {:?} are not supported", color),
+            ));
+        }
+    };
+
+    img.ok_or_else(|| ConvertError::decoder(ImageFormat::Tiff, "page data is truncated"))
+}
+
+/// Naive CMYK to RGB, matching the `image` crate's TIFF decoder.
+fn cmyk_to_rgb(cmyk: &[u8]) -> Rgb<u8> {
+    let black = 1.0 - f32::from(cmyk[3]) / 255.0;
+    Rgb([0, 1, 2].map(|i| ((255.0 - f32::from(cmyk[i])) * black) as u8))
+}
+
+/// The same for 16-bit samples.
+fn cmyk16_to_rgb(cmyk: &[u16]) -> Rgb<u16> {
+    let black = 1.0 - f32::from(cmyk[3]) / 65535.0;
+    Rgb([0, 1, 2].map(|i| ((65535.0 - f32::from(cmyk[i])) * black) as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    /// A two page TIFF: a 4x2 RGB page and a 3x3 gray one.
+    fn two_pages() -> Vec<u8> {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        encoder.write_image::<colortype::RGB8>(4, 2, &[200; 4 * 2 * 3]).unwrap();
+        encoder.write_image::<colortype::Gray8>(3, 3, &[7; 9]).unwrap();
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn every_page_is_decoded() {
+        let data = two_pages();
+        assert_eq!(count_tiff_pages(&data), 2);
+
+        let pages = decode_tiff_pages(&data, &DecodeLimits::default(), true).unwrap().unwrap();
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].to_rgb8().get_pixel(3, 1).0, [200, 200, 200]);
+        let DynamicImage::ImageLuma8(gray) = &pages[1] else { panic!("expected gray") };
+        assert_eq!((gray.dimensions(), gray.get_pixel(2, 2).0), ((3, 3), [7]));
+    }
+
+    #[test]
+    fn single_pages_and_limits() {
+        let mut buffer = Cursor::new(Vec::new());
+        TiffEncoder::new(&mut buffer)
+            .unwrap()
+            .write_image::<colortype::Gray8>(2, 2, &[0; 4])
+            .unwrap();
+        let single = buffer.into_inner();
+        assert_eq!(count_tiff_pages(&single), 1);
+        assert!(decode_tiff_pages(&single, &DecodeLimits::default(), true).unwrap().is_none());
+
+        // The 4 column first page is already too wide.
+        let limits = DecodeLimits { max_width: Some(3), ..DecodeLimits::default() };
+        let result = decode_tiff_pages(&two_pages(), &limits, true);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn cmyk_pages_become_rgb() {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        // Pure cyan, then 50% black.
+        encoder.write_image::<colortype::CMYK8>(2, 1, &[255, 0, 0, 0, 0, 0, 0, 255]).unwrap();
+        encoder.write_image::<colortype::CMYK16>(1, 1, &[0, 0, 0, 32768]).unwrap();
+        let data = buffer.into_inner();
+
+        let pages = decode_tiff_pages(&data, &DecodeLimits::default(), true).unwrap().unwrap();
+        let DynamicImage::ImageRgb8(cyan) = &pages[0] else { panic!("expected Rgb8") };
+        assert_eq!((cyan.get_pixel(0, 0).0, cyan.get_pixel(1, 0).0), ([0, 255, 255], [0; 3]));
+        let DynamicImage::ImageRgb16(gray) = &pages[1] else { panic!("expected Rgb16") };
+        assert_eq!(gray.get_pixel(0, 0).0, [32767; 3]);
+    }
+
+    #[test]
+    fn page_walk_is_bounded() {
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        for _ in 0..MAX_TIFF_PAGES + 5 {
+            encoder.write_image::<colortype::Gray8>(1, 1, &[0]).unwrap();
+        }
+        let data = buffer.into_inner();
+        assert_eq!(count_tiff_pages(&data), MAX_TIFF_PAGES);
+        let pages = decode_tiff_pages(&data, &DecodeLimits::default(), true).unwrap().unwrap();
+        assert_eq!(pages.len() as u32, MAX_TIFF_PAGES);
+
+        // Point the second directory's "next" offset back at the first: a cycle.
+        let mut data = two_pages();
+        let mut decoder = Decoder::new(Cursor::new(&data)).unwrap();
+        let first = decoder.ifd_pointer().unwrap().0 as usize;
+        decoder.next_image().unwrap();
+        let second = decoder.ifd_pointer().unwrap().0 as usize;
+        let entries = usize::from(u16::from_le_bytes([data[second], data[second + 1]]));
+        let next = second + 2 + entries * 12;
+        data[next..next + 4].copy_from_slice(&(first as u32).to_le_bytes());
+        // The walk ends instead of going round (where the second page is dropped depends on
+        // whether `tiff` or our own check sees the cycle first).
+        assert!(count_tiff_pages(&data) <= 2);
+    }
+
+    #[test]
+    fn pages_follow_their_own_orientation() {
+        // Page 1 is rotated 90° clockwise (6), page 2 has no tag.
+        let mut buffer = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut buffer).unwrap();
+        let mut page = encoder.new_image::<colortype::Gray8>(4, 2).unwrap();
+        page.encoder().write_tag(Tag::Orientation, 6u16).unwrap();
+        page.write_data(&[0, 0, 0, 9, 0, 0, 0, 0]).unwrap();
+        encoder.write_image::<colortype::Gray8>(4, 2, &[0; 8]).unwrap();
+        let data = buffer.into_inner();
+
+        let pages = decode_tiff_pages(&data, &DecodeLimits::default(), true).unwrap().unwrap();
+        assert_eq!((pages[0].width(), pages[0].height()), (2, 4));
+        // The top right pixel ends up bottom right.
+        assert_eq!(pages[0].to_luma8().get_pixel(1, 3).0, [9]);
+        assert_eq!((pages[1].width(), pages[1].height()), (4, 2));
+
+        let pages = decode_tiff_pages(&data, &DecodeLimits::default(), false).unwrap().unwrap();
+        assert_eq!((pages[0].width(), pages[0].height()), (4, 2));
+    }
+}
@@ -0,0 +1,307 @@
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use png::{BitDepth, ColorType, DeflateCompression, Filter};
+use std::collections::{HashMap, HashSet};
+use crate::error::ConvertError;
+
+// ----------------------------------------------------------------
+// Lossless PNG optimization
+// ----------------------------------------------------------------
+//
+// Same idea as oxipng, on a smaller scale:
+// 1. Reduce the pixel format without losing information
+//    (16 -> 8 bit, drop an all-opaque alpha, RGB -> gray, palette for <= 256 colours).
+// 2. Pick the best single row filter for each candidate on a sample of its rows, then
+//    encode the candidate with that filter and with adaptive filtering, keeping the
+//    smallest file. That is at most four full deflate runs instead of one per filter.
+// Only IHDR, PLTE, tRNS, IDAT and IEND are written, so ancillary chunks of the
+// source never make it into the output.
+
+const SINGLE_FILTERS: [Filter; 5] =
+    [Filter::NoFilter, Filter::Sub, Filter::Up, Filter::Avg, Filter::Paeth];
+
+/// The sample is this many bands of `BAND_ROWS` rows, spread evenly over the image.
+const SAMPLE_BANDS: usize = 4;
+const BAND_ROWS: usize = 8;
+
+/// Raw image data in a layout the `png` crate can write directly.
+struct Candidate {
+    color: ColorType,
+    depth: BitDepth,
+    data: Vec<u8>,
+    palette: Option<Vec<u8>>,
+    trns: Option<Vec<u8>>,
+}
+
+pub fn optimize_png(img: &DynamicImage, level: u8) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = (img.width(), img.height());
+
+    let mut candidates = Vec::new();
+    let rgba16 = img.to_rgba16();
+    if is_16_bit(img) && !rgba16.as_raw().iter().all(|&v| v % 257 == 0) {
+        // Real 16-bit data; only the channel reductions are lossless.
+        candidates.push(truecolor_16(&rgba16.into_raw()));
+    } else {
+        let rgba = img.to_rgba8();
+        candidates.push(truecolor_8(rgba.as_raw()));
+        if let Some(indexed) = palette(&rgba) {
+            candidates.push(indexed);
+        }
+    }
+
+    let to_error = |e| ConvertError::encoder(ImageFormat::Png, e);
+    let mut best: Option<Vec<u8>> = None;
+    for candidate in &candidates {
+        let single = best_single_filter(candidate, width, height, level).map_err(to_error)?;
+        for filter in [single, Filter::Adaptive] {
+            let encoded =
+                encode_candidate(candidate, width, height, filter, level).map_err(to_error)?;
+            if best.as_ref().is_none_or(|b| encoded.len() < b.len()) {
+                best = Some(encoded);
+            }
+        }
+    }
+
+    // `candidates` always holds at least the truecolour version.
+    Ok(best.unwrap_or_default())
+}
+
+fn is_16_bit(img: &DynamicImage) -> bool {
+    !matches!(
+        img,
+        DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+    )
+}
+
+fn truecolor_8(rgba: &[u8]) -> Candidate {
+    let opaque = rgba.chunks_exact(4).all(|p| p[3] == 255);
+    let gray = rgba.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2]);
+
+    let (color, data) = match (gray, opaque) {
+        (true, true) => (ColorType::Grayscale, rgba.chunks_exact(4).map(|p| p[0]).collect()),
+        (true, false) => (
+            ColorType::GrayscaleAlpha,
+            rgba.chunks_exact(4).flat_map(|p| [p[0], p[3]]).collect(),
+        ),
+        (false, true) => (
+            ColorType::Rgb,
+            rgba.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect(),
+        ),
+        (false, false) => (ColorType::Rgba, rgba.to_vec()),
+    };
+
+    Candidate { color, depth: BitDepth::Eight, data, palette: None, trns: None }
+}
+
+fn truecolor_16(rgba: &[u16]) -> Candidate {
+    let opaque = rgba.chunks_exact(4).all(|p| p[3] == u16::MAX);
+    let gray = rgba.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2]);
+
+    let (color, channels): (ColorType, &[usize]) = match (gray, opaque) {
+        (true, true) => (ColorType::Grayscale, &[0]),
+        (true, false) => (ColorType::GrayscaleAlpha, &[0, 3]),
+        (false, true) => (ColorType::Rgb, &[0, 1, 2]),
+        (false, false) => (ColorType::Rgba, &[0, 1, 2, 3]),
+    };
+
+    // PNG stores 16-bit samples big-endian.
+    let data = rgba
+        .chunks_exact(4)
+        .flat_map(|p| channels.iter().flat_map(move |&c| p[c].to_be_bytes()))
+        .collect();
+
+    Candidate { color, depth: BitDepth::Sixteen, data, palette: None, trns: None }
+}
+
+/// Builds an indexed candidate, or `None` when the image has more than 256 colours.
+fn palette(rgba: &RgbaImage) -> Option<Candidate> {
+    let mut colors: Vec<[u8; 4]> = Vec::new();
+    let mut seen: HashSet<[u8; 4]> = HashSet::new();
+    for pixel in rgba.pixels() {
+        if seen.insert(pixel.0) {
+            if colors.len() == 256 {
+                return None;
+            }
+            colors.push(pixel.0);
+        }
+    }
+
+    // Translucent entries first, so tRNS can stop at the last one of them.
+    colors.sort_by_key(|c| c[3] == 255);
+    let index: HashMap<[u8; 4], u8> =
+        colors.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+
+    let depth = match colors.len() {
+        0..=2 => BitDepth::One,
+        3..=4 => BitDepth::Two,
+        5..=16 => BitDepth::Four,
+        _ => BitDepth::Eight,
+    };
+    let bits = depth as usize;
+
+    // Pack the indices MSB first; every row starts on a byte boundary.
+    let row_bytes = (rgba.width() as usize * bits).div_ceil(8);
+    let mut data = vec![0u8; row_bytes * rgba.height() as usize];
+    for (y, row) in rgba.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            let bit = x * bits;
+            let shift = 8 - bits - (bit % 8);
+            data[y * row_bytes + bit / 8] |= index[&pixel.0] << shift;
+        }
+    }
+
+    let plte = colors.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let translucent = colors.iter().take_while(|c| c[3] != 255).count();
+    let trns = (translucent > 0).then(|| colors[..translucent].iter().map(|c| c[3]).collect());
+
+    Some(Candidate { color: ColorType::Indexed, depth, data, palette: Some(plte), trns })
+}
+
+/// The single filter that compresses a sample of `candidate`'s rows best.
+fn best_single_filter(
+    candidate: &Candidate,
+    width: u32,
+    height: u32,
+    level: u8,
+) -> Result<Filter, png::EncodingError> {
+    let bits = candidate.color.samples() * candidate.depth as usize;
+    let row_bytes = (width as usize * bits).div_ceil(8);
+    let rows = height as usize;
+
+    let sample = if rows <= SAMPLE_BANDS * BAND_ROWS {
+        None
+    } else {
+        let step = rows / SAMPLE_BANDS;
+        let data = (0..SAMPLE_BANDS)
+            .flat_map(|band| {
+                let start = band * step * row_bytes;
+                &candidate.data[start..start + BAND_ROWS * row_bytes]
+            })
+            .copied()
+            .collect();
+        Some(Candidate {
+            color: candidate.color,
+            depth: candidate.depth,
+            data,
+            palette: candidate.palette.clone(),
+            trns: candidate.trns.clone(),
+        })
+    };
+    let (sample, sample_height) = match &sample {
+        Some(sample) => (sample, (SAMPLE_BANDS * BAND_ROWS) as u32),
+        None => (candidate, height),
+    };
+
+    let mut best = (Filter::NoFilter, usize::MAX);
+    for filter in SINGLE_FILTERS {
+        let len = encode_candidate(sample, width, sample_height, filter, level)?.len();
+        if len < best.1 {
+            best = (filter, len);
+        }
+    }
+    Ok(best.0)
+}
+
+fn encode_candidate(
+    candidate: &Candidate,
+    width: u32,
+    height: u32,
+    filter: Filter,
+    level: u8,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let mut output = Vec::new();
+
+    let mut encoder = png::Encoder::new(&mut output, width, height);
+    encoder.set_color(candidate.color);
+    encoder.set_depth(candidate.depth);
+    if let Some(palette) = &candidate.palette {
+        encoder.set_palette(palette.as_slice());
+    }
+    if let Some(trns) = &candidate.trns {
+        encoder.set_trns(trns.as_slice());
+    }
+    encoder.set_deflate_compression(match level {
+        0 => DeflateCompression::NoCompression,
+        n => DeflateCompression::Level(n.min(9)),
+    });
+    encoder.set_filter(filter);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&candidate.data)?;
+    writer.finish()?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgba};
+
+    fn assert_lossless(original: &DynamicImage, optimized: &[u8]) {
+        let reloaded = image::load_from_memory_with_format(optimized, ImageFormat::Png)
+            .expect("Failed to reload optimized PNG");
+        assert_eq!(reloaded.to_rgba16(), original.to_rgba16());
+    }
+
+    #[test]
+    fn few_colours_become_low_depth_palette() {
+        let pixels = RgbaImage::from_fn(200, 150, |x, y| match (x * x * 31 + x * y + y * 17) % 3 {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([0, 0, 255, 128]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        let img = DynamicImage::ImageRgba8(pixels);
+        let optimized = optimize_png(&img, 9).expect("Optimization failed");
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(&optimized));
+        let reader = decoder.read_info().expect("Failed to read PNG header");
+        assert_eq!(reader.info().color_type, ColorType::Indexed);
+        assert_eq!(reader.info().bit_depth, BitDepth::Two);
+
+        assert_lossless(&img, &optimized);
+    }
+
+    #[test]
+    fn widened_8_bit_data_is_reduced() {
+        // Farbfeld inputs are always 16-bit even when the source was 8-bit.
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_fn(300, 2, |x, y| {
+            let v = ((x * 7 + y) % 256) as u16 * 257;
+            Rgba([v, v, v, u16::MAX])
+        }));
+        let optimized = optimize_png(&img, 6).expect("Optimization failed");
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(&optimized));
+        let reader = decoder.read_info().expect("Failed to read PNG header");
+        assert_eq!(reader.info().color_type, ColorType::Grayscale);
+        assert_eq!(reader.info().bit_depth, BitDepth::Eight);
+
+        assert_lossless(&img, &optimized);
+    }
+
+    #[test]
+    fn tall_images_are_filtered_from_a_sample() {
+        // A smooth gradient, where any prediction beats no filter at all.
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 200, |x, y| {
+            image::Rgb([(x * 3 + y) as u8, (x + y * 2) as u8, (x * y / 64) as u8])
+        }));
+        let optimized = optimize_png(&img, 6).expect("Optimization failed");
+        assert_lossless(&img, &optimized);
+
+        let rgb = truecolor_8(img.to_rgba8().as_raw());
+        assert_ne!(best_single_filter(&rgb, 64, 200, 6).unwrap(), Filter::NoFilter);
+        let plain = encode_candidate(&rgb, 64, 200, Filter::NoFilter, 6).unwrap();
+        assert!(optimized.len() < plain.len());
+    }
+
+    #[test]
+    fn real_16_bit_data_is_kept() {
+        let img = DynamicImage::ImageRgba16(ImageBuffer::from_fn(20, 20, |x, y| {
+            Rgba([(x * 1000 + 1) as u16, (y * 999) as u16, 7, 40000])
+        }));
+        let optimized = optimize_png(&img, 9).expect("Optimization failed");
+        assert_lossless(&img, &optimized);
+    }
+}
@@ -0,0 +1,344 @@
+use color_quant::NeuQuant;
+use image::RgbaImage;
+use std::collections::HashMap;
+use crate::options::{Dither, Quantizer};
+
+// ----------------------------------------------------------------
+// Palette quantization and dithering (GIF and other paletted outputs)
+// ----------------------------------------------------------------
+
+/// NeuQuant is unreliable with small networks; smaller palettes always use median cut.
+const NEUQUANT_MIN_COLORS: u16 = 64;
+
+/// Refinement passes run after median cut, moving each entry to the mean of its pixels.
+const KMEANS_PASSES: usize = 2;
+
+/// 4x4 Bayer matrix for ordered dithering.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// How an RGBA image is reduced to a palette.
+#[derive(Clone, Copy, Debug)]
+pub struct QuantizeSettings {
+    /// Palette size including the transparent entry, clamped to 2-256.
+    pub colors: u16,
+    pub quantizer: Quantizer,
+    /// NeuQuant sampling factor, 1 (best) to 30 (fastest).
+    pub sample_factor: u8,
+    pub dither: Dither,
+    /// Pixels with a lower alpha become transparent, the rest opaque.
+    pub alpha_threshold: u8,
+}
+
+impl QuantizeSettings {
+    /// Whether NeuQuant was asked for but the palette is too small for it, so median cut
+    /// builds it instead.
+    pub fn replaces_neuquant(&self) -> bool {
+        self.quantizer == Quantizer::NeuQuant && self.colors.clamp(2, 256) < NEUQUANT_MIN_COLORS
+    }
+}
+
+/// An image as palette indices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Indexed {
+    pub palette: Vec<[u8; 3]>,
+    /// One palette index per pixel, row by row.
+    pub indices: Vec<u8>,
+    /// The palette entry reserved for transparent pixels, when there are any. Always the
+    /// last one.
+    pub transparent: Option<u8>,
+}
+
+/// Reduces `img` to at most `settings.colors` entries. Images with few enough colours get
+/// an exact palette and are never dithered.
+pub fn quantize(img: &RgbaImage, settings: &QuantizeSettings) -> Indexed {
+    let colors = usize::from(settings.colors.clamp(2, 256));
+    let is_opaque = |alpha: u8| alpha >= settings.alpha_threshold;
+    let has_transparency = img.pixels().any(|p| !is_opaque(p[3]));
+    let slots = if has_transparency { colors - 1 } else { colors };
+
+    let mut histogram: HashMap<[u8; 3], u32> = HashMap::new();
+    for p in img.pixels().filter(|p| is_opaque(p[3])) {
+        *histogram.entry([p[0], p[1], p[2]]).or_default() += 1;
+    }
+
+    let exact = histogram.len() <= slots;
+    let mut nearest = if exact {
+        let mut palette: Vec<_> = histogram.keys().copied().collect();
+        palette.sort_unstable();
+        Nearest::new(palette, None)
+    } else if settings.quantizer == Quantizer::NeuQuant && !settings.replaces_neuquant() {
+        let pixels: Vec<u8> = img
+            .pixels()
+            .filter(|p| is_opaque(p[3]))
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect();
+        let sample_factor = i32::from(settings.sample_factor.clamp(1, 30));
+        let neuquant = NeuQuant::new(sample_factor, slots, &pixels);
+        let palette =
+            neuquant.color_map_rgb().chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        Nearest::new(palette, Some(neuquant))
+    } else {
+        Nearest::new(median_cut(&histogram, slots), None)
+    };
+
+    let transparent = has_transparency.then_some(nearest.palette.len() as u8);
+    let dither = if exact { Dither::None } else { settings.dither };
+    let indices = map_pixels(img, &mut nearest, dither, slots, |alpha| {
+        if is_opaque(alpha) { None } else { transparent }
+    });
+
+    let mut palette = nearest.palette;
+    if has_transparency {
+        palette.push([0, 0, 0]);
+    }
+    Indexed { palette, indices, transparent }
+}
+
+/// Finds the closest palette entry, remembering earlier answers.
+struct Nearest {
+    palette: Vec<[u8; 3]>,
+    neuquant: Option<NeuQuant>,
+    cache: HashMap<[u8; 3], u8>,
+}
+
+impl Nearest {
+    fn new(palette: Vec<[u8; 3]>, neuquant: Option<NeuQuant>) -> Nearest {
+        let cache = palette.iter().zip(0..=255u8).map(|(&c, i)| (c, i)).collect();
+        Nearest { palette, neuquant, cache }
+    }
+
+    fn index_of(&mut self, color: [u8; 3]) -> u8 {
+        if let Some(&index) = self.cache.get(&color) {
+            return index;
+        }
+        let index = match &self.neuquant {
+            Some(neuquant) => neuquant.index_of(&[color[0], color[1], color[2], 255]) as u8,
+            None => closest(&self.palette, color),
+        };
+        self.cache.insert(color, index);
+        index
+    }
+}
+
+fn closest(palette: &[[u8; 3]], color: [u8; 3]) -> u8 {
+    (0..=255u8)
+        .zip(palette)
+        .min_by_key(|(_, entry)| distance(**entry, color))
+        .map_or(0, |(index, _)| index)
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> u32 {
+    a.iter().zip(b).map(|(&x, y)| (i32::from(x) - i32::from(y)).pow(2) as u32).sum()
+}
+
+/// Maps every pixel to a palette index. `transparent` returns the index for pixels that
+/// are not opaque, which are skipped by the error diffusion.
+fn map_pixels(
+    img: &RgbaImage,
+    nearest: &mut Nearest,
+    dither: Dither,
+    slots: usize,
+    transparent: impl Fn(u8) -> Option<u8>,
+) -> Vec<u8> {
+    let width = img.width() as usize;
+    let mut indices = Vec::with_capacity(width * img.height() as usize);
+    // Floyd-Steinberg error carried into the current and the next row, one slot of padding
+    // on each side.
+    let mut errors = vec![[0f32; 3]; width + 2];
+    let mut next_errors = vec![[0f32; 3]; width + 2];
+    // Ordered dithering spreads by about one palette step per channel.
+    let spread = 255.0 / (slots as f32).cbrt();
+
+    for (y, row) in img.rows().enumerate() {
+        for (x, pixel) in row.enumerate() {
+            if let Some(index) = transparent(pixel[3]) {
+                indices.push(index);
+                continue;
+            }
+            let mut wanted = [f32::from(pixel[0]), f32::from(pixel[1]), f32::from(pixel[2])];
+            match dither {
+                Dither::None => {}
+                Dither::FloydSteinberg => {
+                    for (c, e) in wanted.iter_mut().zip(errors[x + 1]) {
+                        *c += e;
+                    }
+                }
+                Dither::Ordered => {
+                    let threshold = f32::from(BAYER_4X4[y % 4][x % 4]) + 0.5;
+                    for c in wanted.iter_mut() {
+                        *c += (threshold / 16.0 - 0.5) * spread;
+                    }
+                }
+            }
+            let wanted = wanted.map(|c| c.clamp(0.0, 255.0));
+            let color = wanted.map(|c| c.round() as u8);
+            let index = nearest.index_of(color);
+            indices.push(index);
+
+            if dither == Dither::FloydSteinberg {
+                let chosen = nearest.palette[usize::from(index)];
+                for channel in 0..3 {
+                    let error = wanted[channel] - f32::from(chosen[channel]);
+                    errors[x + 2][channel] += error * 7.0 / 16.0;
+                    next_errors[x][channel] += error * 3.0 / 16.0;
+                    next_errors[x + 1][channel] += error * 5.0 / 16.0;
+                    next_errors[x + 2][channel] += error / 16.0;
+                }
+            }
+        }
+        std::mem::swap(&mut errors, &mut next_errors);
+        next_errors.fill([0.0; 3]);
+    }
+    indices
+}
+
+/// Median cut (split the box with the widest, busiest colour range at its weighted median)
+/// followed by a few k-means passes, in the spirit of libimagequant.
+fn median_cut(histogram: &HashMap<[u8; 3], u32>, slots: usize) -> Vec<[u8; 3]> {
+    let mut boxes: Vec<Vec<([u8; 3], u32)>> =
+        vec![histogram.iter().map(|(&c, &n)| (c, n)).collect()];
+    while boxes.len() < slots {
+        let candidate = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .map(|(i, colors)| {
+                let (channel, range) = widest_channel(colors);
+                let population: u64 = colors.iter().map(|&(_, n)| u64::from(n)).sum();
+                (u64::from(range) * population, i, channel)
+            })
+            .max();
+        let Some((_, i, channel)) = candidate else { break };
+
+        let mut colors = boxes.swap_remove(i);
+        colors.sort_unstable_by_key(|(c, _)| c[channel]);
+        let total: u64 = colors.iter().map(|&(_, n)| u64::from(n)).sum();
+        let mut seen = 0;
+        let split = colors
+            .iter()
+            .position(|&(_, n)| {
+                seen += u64::from(n);
+                seen * 2 >= total
+            })
+            .map_or(1, |k| k + 1)
+            .clamp(1, colors.len() - 1);
+        let rest = colors.split_off(split);
+        boxes.push(colors);
+        boxes.push(rest);
+    }
+
+    let mut palette: Vec<[u8; 3]> = boxes
+        .iter()
+        .map(|colors| mean(colors.iter().copied()).unwrap_or([0, 0, 0]))
+        .collect();
+    for _ in 0..KMEANS_PASSES {
+        let mut members: Vec<Vec<([u8; 3], u32)>> = vec![Vec::new(); palette.len()];
+        for (&color, &count) in histogram {
+            members[usize::from(closest(&palette, color))].push((color, count));
+        }
+        for (entry, colors) in palette.iter_mut().zip(members) {
+            if let Some(center) = mean(colors.into_iter()) {
+                *entry = center;
+            }
+        }
+    }
+    palette
+}
+
+/// The channel with the largest spread in `colors`, and that spread.
+fn widest_channel(colors: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = colors.iter().map(|(c, _)| c[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+/// Population weighted mean colour, or `None` for no colours.
+fn mean(colors: impl Iterator<Item = ([u8; 3], u32)>) -> Option<[u8; 3]> {
+    let mut sums = [0u64; 3];
+    let mut total = 0u64;
+    for (color, count) in colors {
+        for (sum, c) in sums.iter_mut().zip(color) {
+            *sum += u64::from(c) * u64::from(count);
+        }
+        total += u64::from(count);
+    }
+    (total > 0).then(|| sums.map(|sum| ((sum + total / 2) / total) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn settings(colors: u16, quantizer: Quantizer, dither: Dither) -> QuantizeSettings {
+        QuantizeSettings { colors, quantizer, sample_factor: 10, dither, alpha_threshold: 128 }
+    }
+
+    /// A smooth 64x64 gradient with far more than 256 colours.
+    fn gradient() -> RgbaImage {
+        RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255]))
+    }
+
+    #[test]
+    fn few_colours_get_an_exact_palette_and_a_transparent_slot() {
+        let img = RgbaImage::from_fn(4, 1, |x, _| match x {
+            0 => Rgba([255, 0, 0, 255]),
+            1 => Rgba([0, 255, 0, 255]),
+            2 => Rgba([0, 0, 0, 20]),
+            _ => Rgba([255, 0, 0, 200]),
+        });
+        let indexed = quantize(&img, &settings(256, Quantizer::NeuQuant, Dither::FloydSteinberg));
+        assert_eq!(indexed.palette, vec![[0, 255, 0], [255, 0, 0], [0, 0, 0]]);
+        assert_eq!(indexed.indices, vec![1, 0, 2, 1]);
+        assert_eq!(indexed.transparent, Some(2));
+    }
+
+    #[test]
+    fn palette_respects_the_colour_count() {
+        for quantizer in [Quantizer::NeuQuant, Quantizer::MedianCut] {
+            for colors in [2, 16, 64, 256] {
+                let indexed = quantize(&gradient(), &settings(colors, quantizer, Dither::None));
+                assert!(indexed.palette.len() <= usize::from(colors));
+                assert_eq!(indexed.transparent, None);
+                assert_eq!(indexed.indices.len(), 64 * 64);
+            }
+        }
+    }
+
+    #[test]
+    fn median_cut_follows_the_colours_present() {
+        // Two clusters: the two entries should land on them.
+        let img = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 { Rgba([10, 10, 10, 255]) } else { Rgba([240, 200, 0, 255]) }
+        });
+        let mut img = img;
+        img.put_pixel(0, 0, Rgba([12, 10, 10, 255]));
+        img.put_pixel(7, 7, Rgba([240, 202, 0, 255]));
+        let indexed = quantize(&img, &settings(2, Quantizer::MedianCut, Dither::None));
+        let mut palette = indexed.palette.clone();
+        palette.sort_unstable();
+        assert_eq!(palette, vec![[10, 10, 10], [240, 200, 0]]);
+    }
+
+    #[test]
+    fn dithering_mixes_entries_to_match_the_average() {
+        // Flat mid grey against a black and white palette.
+        let img = RgbaImage::from_pixel(16, 16, Rgba([128, 128, 128, 255]));
+        let average = |dither| {
+            let mut nearest = Nearest::new(vec![[0, 0, 0], [255, 255, 255]], None);
+            let indices = map_pixels(&img, &mut nearest, dither, 2, |_| None);
+            indices.iter().map(|&i| u32::from(i) * 255).sum::<u32>() / (16 * 16)
+        };
+
+        assert_eq!(average(Dither::None), 255);
+        for dither in [Dither::FloydSteinberg, Dither::Ordered] {
+            let average = average(dither);
+            assert!((100..=156).contains(&average), "{:?} averaged {}", dither, average);
+        }
+    }
+}
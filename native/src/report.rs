@@ -0,0 +1,173 @@
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// ----------------------------------------------------------------
+// Conversion result and warnings
+// ----------------------------------------------------------------
+
+/// Something the conversion had to give up on without failing outright.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConvertWarning {
+    /// The input was animated but the target cannot hold an animation,
+    /// so only the first of `frames` frames was written.
+    AnimationFlattened { frames: usize },
+    /// The input had several pages but the target holds one image,
+    /// so only the first of `pages` pages was written.
+    PagesFlattened { pages: usize },
+    /// NeuQuant was asked for but the `colors` entry palette is too small for it,
+    /// so median cut built it instead.
+    QuantizerReplaced { colors: u16 },
+}
+
+impl ConvertWarning {
+    /// Stable machine readable identifier, like `ConvertError::code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ConvertWarning::AnimationFlattened { .. } => "animation_flattened",
+            ConvertWarning::PagesFlattened { .. } => "pages_flattened",
+            ConvertWarning::QuantizerReplaced { .. } => "quantizer_replaced",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ConvertWarning::AnimationFlattened { frames } => format!(
+                "Output format cannot store animations; kept the first of {} frames",
+                frames
+            ),
+            ConvertWarning::PagesFlattened { pages } => format!(
+                "Output format holds a single image; kept the first of {} pages",
+                pages
+            ),
+            ConvertWarning::QuantizerReplaced { colors } => format!(
+                "NeuQuant needs at least 64 colours; built the {} colour palette with median cut",
+                colors
+            ),
+        }
+    }
+}
+
+/// Encoded output plus what happened along the way.
+#[derive(Debug)]
+pub struct ConvertReport {
+    pub data: Vec<u8>,
+    /// Number of frames written (1 for still images).
+    pub frames: usize,
+    pub warnings: Vec<ConvertWarning>,
+}
+
+// ----------------------------------------------------------------
+// JS facing result object
+// ----------------------------------------------------------------
+
+#[wasm_bindgen]
+pub struct ConvertResult {
+    data: Vec<u8>,
+    frames: usize,
+    warning_codes: Vec<String>,
+    warning_messages: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl ConvertResult {
+    /// The encoded image bytes.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Warning codes, e.g. "animation_flattened".
+    #[wasm_bindgen(getter)]
+    pub fn warnings(&self) -> Vec<String> {
+        self.warning_codes.clone()
+    }
+
+    /// Human readable text for each entry of `warnings`, in the same order.
+    #[wasm_bindgen(getter)]
+    pub fn warning_messages(&self) -> Vec<String> {
+        self.warning_messages.clone()
+    }
+}
+
+impl From<ConvertReport> for ConvertResult {
+    fn from(report: ConvertReport) -> Self {
+        ConvertResult {
+            warning_codes: report.warnings.iter().map(|w| w.code().to_string()).collect(),
+            warning_messages: report.warnings.iter().map(|w| w.message()).collect(),
+            data: report.data,
+            frames: report.frames,
+        }
+    }
+}
+
+/// One frame pulled out of an animation by `extract_frames`, encoded on its own.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct ExtractedFrame {
+    index: u32,
+    delay_ms: u32,
+    timestamp_ms: u32,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl ExtractedFrame {
+    /// Position of the frame in the source animation (0-based).
+    #[wasm_bindgen(getter)]
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// How long the frame is shown.
+    #[wasm_bindgen(getter)]
+    pub fn delay_ms(&self) -> u32 {
+        self.delay_ms
+    }
+
+    /// When the frame starts, counted from the beginning of the animation.
+    #[wasm_bindgen(getter)]
+    pub fn timestamp_ms(&self) -> u32 {
+        self.timestamp_ms
+    }
+
+    /// The encoded image bytes.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+impl ExtractedFrame {
+    pub fn new(index: u32, delay_ms: u32, timestamp_ms: u32, data: Vec<u8>) -> Self {
+        ExtractedFrame { index, delay_ms, timestamp_ms, data }
+    }
+}
+
+/// What `probe_image` found in the input's headers. Nothing is decoded to get this.
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// Detected input format, spelled like the `convert_image` targets ("png", "jpeg", ...).
+    pub format: String,
+    /// How sure the format detection is, 0-100. Always 100 for formats with a magic number;
+    /// TGA has none and is scored from how plausible its header is.
+    pub confidence: u8,
+    pub width: u32,
+    pub height: u32,
+    /// Colour model as stored in the file: "gray", "gray_alpha", "rgb", "rgba", "cmyk",
+    /// or "indexed" for palette data the decoder does not expand up front.
+    pub color_type: String,
+    /// Bits per channel (per pixel for indexed data).
+    pub bit_depth: u8,
+    /// 1 for still images.
+    pub frames: u32,
+    pub has_alpha: bool,
+    pub has_icc: bool,
+    pub has_exif: bool,
+    /// EXIF orientation (1-8). 1 when the file has none.
+    pub orientation: u8,
+}
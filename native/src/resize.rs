@@ -0,0 +1,258 @@
+use image::{imageops, DynamicImage, ImageBuffer, Pixel};
+use crate::error::ConvertError;
+use crate::linear;
+use crate::options::{ConvertOptions, ResizeFit};
+
+// ----------------------------------------------------------------
+// User requested resize (runs before any format specific adjustment)
+// ----------------------------------------------------------------
+
+/// Applies `width`/`height` (or `scale_percent`), `fit`, `never_upscale` and `filter` from
+/// `options`. The image is returned untouched when no size is requested. Fails with
+/// `LimitExceeded` when the result would break `options.limits`.
+pub fn resize(img: DynamicImage, options: &ConvertOptions) -> Result<DynamicImage, ConvertError> {
+    resize_to(img, options.width, options.height, options)
+}
+
+/// Like `resize`, but with the box passed in instead of read from `options`.
+pub fn resize_to(
+    img: DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    options: &ConvertOptions,
+) -> Result<DynamicImage, ConvertError> {
+    let (src_width, src_height) = (img.width(), img.height());
+    if src_width == 0 || src_height == 0 {
+        return Ok(img);
+    }
+
+    let x_ratio = width.map(|w| f64::from(w) / f64::from(src_width));
+    let y_ratio = height.map(|h| f64::from(h) / f64::from(src_height));
+    let (mut x_ratio, mut y_ratio) = match (x_ratio, y_ratio) {
+        (Some(x), Some(y)) => match options.fit {
+            ResizeFit::Fill => (x, y),
+            ResizeFit::Inside | ResizeFit::Contain => (x.min(y), x.min(y)),
+            ResizeFit::Cover | ResizeFit::Outside => (x.max(y), x.max(y)),
+        },
+        // A single side keeps the aspect ratio whatever the fit.
+        (Some(x), None) => (x, x),
+        (None, Some(y)) => (y, y),
+        (None, None) => match options.scale_percent {
+            Some(percent) if percent.is_finite() && percent > 0.0 => {
+                let ratio = f64::from(percent) / 100.0;
+                (ratio, ratio)
+            }
+            _ => return Ok(img),
+        },
+    };
+    if options.never_upscale {
+        x_ratio = x_ratio.min(1.0);
+        y_ratio = y_ratio.min(1.0);
+    }
+
+    // Same rounding as `DynamicImage::resize`, so `Inside` matches it pixel for pixel.
+    let scaled_width = scale(src_width, x_ratio);
+    let scaled_height = scale(src_height, y_ratio);
+    let box_width = width.unwrap_or(scaled_width);
+    let box_height = height.unwrap_or(scaled_height);
+
+    // The sizes come from the caller: refuse ones that cannot be allocated before trying.
+    let linear_light = options.linear_light || linear::is_float(img.color());
+    let color = img.color();
+    let bytes_per_channel = match linear_light {
+        true => 4,
+        false => color.bytes_per_pixel() / color.channel_count(),
+    };
+    let (canvas_width, canvas_height) = match options.fit {
+        ResizeFit::Contain => (scaled_width.max(box_width), scaled_height.max(box_height)),
+        _ => (scaled_width, scaled_height),
+    };
+    options.limits.check_canvas((canvas_width, canvas_height), bytes_per_channel)?;
+
+    let filter = options.filter.filter_type();
+    let img = if (scaled_width, scaled_height) == (src_width, src_height) {
+        img
+    } else if linear_light {
+        // Float pixels are linear light already, and the plain resize would clip them at 1.0.
+        linear::resize_exact(&img, scaled_width, scaled_height, filter)
+    } else {
+        img.resize_exact(scaled_width, scaled_height, filter)
+    };
+
+    Ok(match options.fit {
+        ResizeFit::Cover => crop_center(img, box_width, box_height),
+        ResizeFit::Contain => pad_center(img, box_width, box_height),
+        _ => img,
+    })
+}
+
+fn scale(size: u32, ratio: f64) -> u32 {
+    ((f64::from(size) * ratio).round() as u32).max(1)
+}
+
+/// Cuts the overflow past `width` x `height` evenly from both sides.
+fn crop_center(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let width = width.min(img.width());
+    let height = height.min(img.height());
+    if (width, height) == (img.width(), img.height()) {
+        return img;
+    }
+    let x = (img.width() - width) / 2;
+    let y = (img.height() - height) / 2;
+    img.crop_imm(x, y, width, height)
+}
+
+/// Centres the image on a transparent `width` x `height` canvas.
+fn pad_center(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let width = width.max(img.width());
+    let height = height.max(img.height());
+    if (width, height) == (img.width(), img.height()) {
+        return img;
+    }
+    let x = i64::from((width - img.width()) / 2);
+    let y = i64::from((height - img.height()) / 2);
+
+    let color = img.color();
+    match color.bytes_per_pixel() / color.channel_count() {
+        1 => DynamicImage::ImageRgba8(pad(&img.to_rgba8(), width, height, x, y)),
+        2 => DynamicImage::ImageRgba16(pad(&img.to_rgba16(), width, height, x, y)),
+        _ => DynamicImage::ImageRgba32F(pad(&img.to_rgba32f(), width, height, x, y)),
+    }
+}
+
+fn pad<P: Pixel>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+    x: i64,
+    y: i64,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    // A new buffer is all zeroes: fully transparent.
+    let mut canvas = ImageBuffer::new(width, height);
+    imageops::replace(&mut canvas, img, x, y);
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::ResizeFilter;
+    use image::{Rgb, RgbImage};
+
+    /// 400x200, left half red and right half blue.
+    fn sample() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(400, 200, |x, _| {
+            if x < 200 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) }
+        }))
+    }
+
+    fn resized(fit: ResizeFit, width: Option<u32>, height: Option<u32>) -> DynamicImage {
+        let mut options = ConvertOptions::default();
+        options.fit = fit;
+        resize_to(sample(), width, height, &options).unwrap()
+    }
+
+    fn size(img: &DynamicImage) -> (u32, u32) {
+        (img.width(), img.height())
+    }
+
+    #[test]
+    fn fit_modes_shape_the_output() {
+        let (w, h) = (Some(100), Some(100));
+        assert_eq!(size(&resized(ResizeFit::Inside, w, h)), (100, 50));
+        assert_eq!(size(&resized(ResizeFit::Outside, w, h)), (200, 100));
+        assert_eq!(size(&resized(ResizeFit::Fill, w, h)), (100, 100));
+        assert_eq!(size(&resized(ResizeFit::Cover, w, h)), (100, 100));
+        assert_eq!(size(&resized(ResizeFit::Contain, w, h)), (100, 100));
+    }
+
+    #[test]
+    fn cover_crops_and_contain_pads_evenly() {
+        // Cover keeps the middle, where red meets blue.
+        let cover = resized(ResizeFit::Cover, Some(100), Some(100)).to_rgb8();
+        assert_eq!(cover.get_pixel(10, 50).0, [255, 0, 0]);
+        assert_eq!(cover.get_pixel(90, 50).0, [0, 0, 255]);
+
+        // Contain adds transparent bands above and below.
+        let contain = resized(ResizeFit::Contain, Some(100), Some(100)).to_rgba8();
+        assert_eq!(contain.get_pixel(50, 10)[3], 0);
+        assert_eq!(contain.get_pixel(10, 50).0, [255, 0, 0, 255]);
+        assert_eq!(contain.get_pixel(50, 90)[3], 0);
+    }
+
+    #[test]
+    fn single_side_keeps_the_aspect_ratio() {
+        for fit in [ResizeFit::Inside, ResizeFit::Cover, ResizeFit::Fill, ResizeFit::Contain] {
+            assert_eq!(size(&resized(fit, Some(100), None)), (100, 50));
+            assert_eq!(size(&resized(fit, None, Some(100))), (200, 100));
+        }
+    }
+
+    #[test]
+    fn percentage_and_never_upscale() {
+        let mut options = ConvertOptions::default();
+        options.scale_percent = Some(25.0);
+        assert_eq!(size(&resize(sample(), &options).unwrap()), (100, 50));
+
+        options.scale_percent = Some(200.0);
+        options.never_upscale = true;
+        assert_eq!(size(&resize(sample(), &options).unwrap()), (400, 200));
+
+        // Shrinking still happens.
+        options.width = Some(800);
+        options.height = Some(100);
+        assert_eq!(size(&resize(sample(), &options).unwrap()), (200, 100));
+    }
+
+    #[test]
+    fn sizes_are_held_to_the_limits() {
+        let mut options = ConvertOptions::default();
+        // 400x200 scaled 100000 times would need terabytes.
+        options.scale_percent = Some(1e7);
+        let result = resize(sample(), &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+
+        // Padding grows the canvas past the scaled image too.
+        options.scale_percent = None;
+        options.fit = ResizeFit::Contain;
+        options.width = Some(100);
+        options.height = Some(u32::MAX);
+        let result = resize(sample(), &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+
+        options.limits.max_height = None;
+        options.limits.max_memory_mb = Some(1);
+        options.height = Some(5000);
+        let result = resize(sample(), &options);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn nearest_filter_keeps_hard_edges() {
+        let mut options = ConvertOptions::default();
+        options.filter = ResizeFilter::Nearest;
+        options.width = Some(40);
+
+        let img = resize(sample(), &options).unwrap().to_rgb8();
+        // Lanczos would blend red into blue around the middle column.
+        assert_eq!(img.get_pixel(19, 0).0, [255, 0, 0]);
+        assert_eq!(img.get_pixel(20, 0).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn linear_light_averages_colours_by_their_light() {
+        let mut options = ConvertOptions::default();
+        options.filter = ResizeFilter::Triangle;
+        options.width = Some(1);
+
+        // Half red, half blue: averaging the sRGB values gives a dim purple.
+        let srgb = resize(sample(), &options).unwrap().to_rgb8();
+        assert!(srgb.get_pixel(0, 0)[0] < 140);
+
+        options.linear_light = true;
+        let linear = resize(sample(), &options).unwrap();
+        assert_eq!(linear.color(), sample().color());
+        let [r, g, b] = linear.to_rgb8().get_pixel(0, 0).0;
+        assert!((186..=190).contains(&r) && g == 0 && (186..=190).contains(&b));
+    }
+}
@@ -0,0 +1,146 @@
+// ----------------------------------------------------------------
+// TGA detection (the format has no magic number)
+// ----------------------------------------------------------------
+//
+// TGA files start with an 18 byte header and, since TGA 2.0, may end with a 26 byte footer
+// carrying a signature. Older files have nothing to recognize them by, so the header is
+// checked field by field and scored instead of accepted on the first parse that succeeds.
+
+/// Scores at or above this are treated as TGA by format detection.
+pub const MIN_TGA_CONFIDENCE: u8 = 50;
+
+const HEADER_LEN: usize = 18;
+const FOOTER_LEN: usize = 26;
+const FOOTER_SIGNATURE: &[u8] = b"TRUEVISION-XFILE.\0";
+
+/// How sure we are that `input_data` is a TGA file, from 0 (certainly not) to 100 (it has
+/// a TGA 2.0 footer and a valid header). Headers that break the spec, such as unknown image
+/// types, impossible pixel depths, or pixel data shorter than the header promises, score 0.
+pub fn tga_confidence(input_data: &[u8]) -> u8 {
+    let Some(header) = input_data.get(..HEADER_LEN) else {
+        return 0;
+    };
+    let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+    let id_len = usize::from(header[0]);
+    let color_map_type = header[1];
+    let image_type = header[2];
+    let (color_map_len, color_map_depth) = (u16_at(5), header[7]);
+    let (width, height) = (u16_at(12), u16_at(14));
+    let pixel_depth = header[16];
+    let descriptor = header[17];
+
+    let depth_ok = match image_type {
+        // Colour mapped: 8-bit indices into a colour map that must be present.
+        1 | 9 => {
+            color_map_type == 1
+                && color_map_len > 0
+                && matches!(color_map_depth, 15 | 16 | 24 | 32)
+                && matches!(pixel_depth, 8 | 16)
+        }
+        2 | 10 => matches!(pixel_depth, 15 | 16 | 24 | 32),
+        3 | 11 => matches!(pixel_depth, 8 | 16),
+        // 0 means "no image data", everything else is undefined.
+        _ => false,
+    };
+    // Bits 6 and 7 of the descriptor select interleaving, which was never used in practice.
+    if !depth_ok || color_map_type > 1 || width == 0 || height == 0 || descriptor & 0xC0 != 0 {
+        return 0;
+    }
+
+    // The colour map (if any) follows the image ID; the pixels follow the colour map.
+    let color_map_bytes = if color_map_type == 1 {
+        usize::from(color_map_len) * usize::from(color_map_depth).div_ceil(8)
+    } else {
+        0
+    };
+    let data_start = HEADER_LEN + id_len + color_map_bytes;
+    let rle = image_type >= 9;
+    let pixel_bytes =
+        usize::from(width) * usize::from(height) * usize::from(pixel_depth).div_ceil(8);
+    // RLE data can be far smaller than the image, but there has to be some.
+    let min_len = data_start + if rle { 1 } else { pixel_bytes };
+    if input_data.len() < min_len {
+        return 0;
+    }
+
+    if input_data.len() >= data_start + FOOTER_LEN
+        && input_data.ends_with(FOOTER_SIGNATURE)
+    {
+        return 100;
+    }
+
+    let mut confidence: u8 = 60;
+    // Files without a colour map should leave its fields zeroed.
+    if color_map_type == 0 && header[3..8].iter().any(|&b| b != 0) {
+        confidence -= 20;
+    }
+    // The low descriptor bits count alpha bits, which only 16 and 32-bit pixels have room for.
+    let alpha_bits = descriptor & 0x0F;
+    let alpha_ok = match pixel_depth {
+        32 => matches!(alpha_bits, 0 | 8),
+        16 => alpha_bits <= 1,
+        _ => alpha_bits == 0,
+    };
+    if !alpha_ok {
+        confidence -= 20;
+    }
+    // Uncompressed pixel data that ends exactly at the end of the file is a strong hint.
+    if !rle && input_data.len() == min_len {
+        confidence += 20;
+    }
+    confidence
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x2 uncompressed 24-bit TGA 1.0 file.
+    fn truecolor() -> Vec<u8> {
+        let mut data = vec![0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 2, 0, 24, 0];
+        data.extend([0x80; 2 * 2 * 3]);
+        data
+    }
+
+    #[test]
+    fn scores_valid_headers() {
+        let mut data = truecolor();
+        assert_eq!(tga_confidence(&data), 80);
+
+        // Trailing bytes make the exact size hint go away.
+        data.push(0);
+        assert_eq!(tga_confidence(&data), 60);
+
+        // A TGA 2.0 footer settles it.
+        data.extend([0; 8]);
+        data.extend(FOOTER_SIGNATURE);
+        assert_eq!(tga_confidence(&data), 100);
+
+        // Inconsistent fields lower the score below the detection threshold.
+        let mut data = truecolor();
+        data.push(0);
+        data[5] = 1;
+        data[17] = 4;
+        assert!(tga_confidence(&data) < MIN_TGA_CONFIDENCE);
+    }
+
+    #[test]
+    fn rejects_impossible_headers() {
+        let with = |index: usize, value: u8| {
+            let mut data = truecolor();
+            data[index] = value;
+            tga_confidence(&data)
+        };
+        assert_eq!(with(2, 0), 0, "no image data");
+        assert_eq!(with(2, 5), 0, "unknown image type");
+        assert_eq!(with(1, 2), 0, "unknown colour map type");
+        assert_eq!(with(2, 1), 0, "colour mapped without a colour map");
+        assert_eq!(with(16, 12), 0, "pixel depth");
+        assert_eq!(with(12, 0), 0, "zero width");
+        assert_eq!(with(17, 0x40), 0, "interleaved");
+        assert_eq!(with(13, 1), 0, "truncated pixel data");
+
+        assert_eq!(tga_confidence(b"This is not an image!"), 0);
+        assert_eq!(tga_confidence(&[0; 10]), 0);
+    }
+}
@@ -0,0 +1,338 @@
+use image::imageops::interpolate_bilinear;
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba};
+use wasm_bindgen::prelude::wasm_bindgen;
+use crate::error::ConvertError;
+use crate::options::DecodeLimits;
+
+// ----------------------------------------------------------------
+// Crop / rotate / flip steps (run in the order they were added)
+// ----------------------------------------------------------------
+
+/// One geometric step of the conversion pipeline. Steps run in the order they are added
+/// with `ConvertOptions.add_transform`, after auto-orientation and before the resize.
+///
+/// From JavaScript:
+/// ```js
+/// const options = new ConvertOptions();
+/// options.add_transform(Transform.rotate(-2.5));
+/// options.add_transform(Transform.crop_to_aspect(16, 9));
+/// options.add_transform(Transform.flip_horizontal());
+/// ```
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    kind: TransformKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TransformKind {
+    Crop { x: u32, y: u32, width: u32, height: u32 },
+    CropToAspect { width: f32, height: f32 },
+    /// Clockwise, in degrees.
+    Rotate { degrees: f32, fill: Rgba<u8> },
+    FlipHorizontal,
+    FlipVertical,
+}
+
+#[wasm_bindgen]
+impl Transform {
+    /// Keeps the `width` x `height` rectangle whose top left corner is at (`x`, `y`).
+    /// A rectangle running past the edge is cut short there.
+    pub fn crop(x: u32, y: u32, width: u32, height: u32) -> Transform {
+        Transform { kind: TransformKind::Crop { x, y, width, height } }
+    }
+
+    /// Keeps the largest centred rectangle with a `width`:`height` aspect ratio
+    /// (e.g. 16, 9 or 1, 1).
+    pub fn crop_to_aspect(width: f32, height: f32) -> Transform {
+        Transform { kind: TransformKind::CropToAspect { width, height } }
+    }
+
+    /// Rotates clockwise by `degrees` (negative turns counter-clockwise). Multiples of 90
+    /// are exact; other angles grow the canvas to fit and leave the corners transparent.
+    pub fn rotate(degrees: f32) -> Transform {
+        Transform::rotate_with_fill(degrees, 0, 0, 0, 0)
+    }
+
+    /// Like `rotate`, but fills the uncovered corners with the given colour.
+    pub fn rotate_with_fill(degrees: f32, r: u8, g: u8, b: u8, a: u8) -> Transform {
+        Transform { kind: TransformKind::Rotate { degrees, fill: Rgba([r, g, b, a]) } }
+    }
+
+    /// Mirrors left to right.
+    pub fn flip_horizontal() -> Transform {
+        Transform { kind: TransformKind::FlipHorizontal }
+    }
+
+    /// Mirrors top to bottom.
+    pub fn flip_vertical() -> Transform {
+        Transform { kind: TransformKind::FlipVertical }
+    }
+}
+
+impl Transform {
+    /// Runs this step. Fails with `InvalidOption` when it cannot apply to an image of
+    /// this size (a crop outside the image, a zero aspect ratio, a non finite angle).
+    pub fn apply(&self, img: DynamicImage) -> Result<DynamicImage, ConvertError> {
+        match self.kind {
+            TransformKind::Crop { x, y, width, height } => crop(img, x, y, width, height),
+            TransformKind::CropToAspect { width, height } => crop_to_aspect(img, width, height),
+            TransformKind::Rotate { degrees, fill } => rotate(img, degrees, fill),
+            TransformKind::FlipHorizontal => Ok(img.fliph()),
+            TransformKind::FlipVertical => Ok(img.flipv()),
+        }
+    }
+}
+
+/// Runs every step of `transforms` in order. Fails with `LimitExceeded` before a rotation
+/// whose canvas would break `limits`.
+pub fn apply_transforms(
+    mut img: DynamicImage,
+    transforms: &[Transform],
+    limits: &DecodeLimits,
+) -> Result<DynamicImage, ConvertError> {
+    for transform in transforms {
+        // Crops and flips never grow the image; rotations can, up to 1.41 times a side.
+        if let TransformKind::Rotate { degrees, .. } = transform.kind
+            && degrees.is_finite()
+        {
+            let radians = f64::from(degrees).to_radians();
+            let size = rotated_size(f64::from(img.width()), f64::from(img.height()), radians);
+            let color = img.color();
+            limits.check_canvas(size, color.bytes_per_pixel() / color.channel_count())?;
+        }
+        img = transform.apply(img)?;
+    }
+    Ok(img)
+}
+
+fn crop(
+    img: DynamicImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+) -> Result<DynamicImage, ConvertError> {
+    if width == 0 || height == 0 || x >= img.width() || y >= img.height() {
+        return Err(ConvertError::InvalidOption(format!(
+            "crop of {}x{} at ({}, {}) is empty for a {}x{} image",
+            width,
+            height,
+            x,
+            y,
+            img.width(),
+            img.height()
+        )));
+    }
+    let width = width.min(img.width() - x);
+    let height = height.min(img.height() - y);
+    Ok(img.crop_imm(x, y, width, height))
+}
+
+fn crop_to_aspect(
+    img: DynamicImage,
+    width: f32,
+    height: f32,
+) -> Result<DynamicImage, ConvertError> {
+    let ratio = f64::from(width) / f64::from(height);
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return Err(ConvertError::InvalidOption(format!(
+            "crop aspect ratio {}:{} must be positive",
+            width, height
+        )));
+    }
+
+    let (src_width, src_height) = (img.width(), img.height());
+    let (new_width, new_height) = if f64::from(src_width) / f64::from(src_height) > ratio {
+        let new_width = (f64::from(src_height) * ratio).round() as u32;
+        (new_width.clamp(1, src_width), src_height)
+    } else {
+        let new_height = (f64::from(src_width) / ratio).round() as u32;
+        (src_width, new_height.clamp(1, src_height))
+    };
+    let x = (src_width - new_width) / 2;
+    let y = (src_height - new_height) / 2;
+    Ok(img.crop_imm(x, y, new_width, new_height))
+}
+
+fn rotate(
+    img: DynamicImage,
+    degrees: f32,
+    fill: Rgba<u8>,
+) -> Result<DynamicImage, ConvertError> {
+    if !degrees.is_finite() {
+        return Err(ConvertError::InvalidOption(format!("rotation of {} degrees", degrees)));
+    }
+
+    let degrees = f64::from(degrees).rem_euclid(360.0);
+    let is = |angle: f64| (degrees - angle).abs() < 1e-3;
+    if is(0.0) || is(360.0) {
+        return Ok(img);
+    }
+    if is(90.0) {
+        return Ok(img.rotate90());
+    }
+    if is(180.0) {
+        return Ok(img.rotate180());
+    }
+    if is(270.0) {
+        return Ok(img.rotate270());
+    }
+
+    let radians = degrees.to_radians();
+    let color = img.color();
+    Ok(match color.bytes_per_pixel() / color.channel_count() {
+        1 => DynamicImage::ImageRgba8(rotate_buffer(&img.to_rgba8(), radians, fill)),
+        2 => {
+            let fill = Rgba(fill.0.map(|c| u16::from(c) * 257));
+            DynamicImage::ImageRgba16(rotate_buffer(&img.to_rgba16(), radians, fill))
+        }
+        _ => {
+            let fill = Rgba(fill.0.map(|c| f32::from(c) / 255.0));
+            DynamicImage::ImageRgba32F(rotate_buffer(&img.to_rgba32f(), radians, fill))
+        }
+    })
+}
+
+/// Rotates clockwise by `radians` onto a canvas just big enough for the result, sampling
+/// the source bilinearly. Output pixels that map outside the source get `fill`.
+fn rotate_buffer<P: Pixel>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    radians: f64,
+    fill: P,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (sin, cos) = radians.sin_cos();
+    let (src_width, src_height) = (f64::from(img.width()), f64::from(img.height()));
+    let (out_width, out_height) = rotated_size(src_width, src_height, radians);
+
+    let (src_cx, src_cy) = (src_width / 2.0, src_height / 2.0);
+    let (out_cx, out_cy) = (f64::from(out_width) / 2.0, f64::from(out_height) / 2.0);
+    ImageBuffer::from_fn(out_width, out_height, |x, y| {
+        // Map the centre of the output pixel back into the source (y points down, so this
+        // is the inverse of a clockwise turn).
+        let dx = f64::from(x) + 0.5 - out_cx;
+        let dy = f64::from(y) + 0.5 - out_cy;
+        let sx = cos * dx + sin * dy + src_cx;
+        let sy = -sin * dx + cos * dy + src_cy;
+        if !(0.0..=src_width).contains(&sx) || !(0.0..=src_height).contains(&sy) {
+            return fill;
+        }
+        // Pixel centres sit at +0.5; the outer half pixel repeats the edge.
+        let sx = (sx - 0.5).clamp(0.0, src_width - 1.0) as f32;
+        let sy = (sy - 0.5).clamp(0.0, src_height - 1.0) as f32;
+        interpolate_bilinear(img, sx, sy).unwrap_or(fill)
+    })
+}
+
+/// Size of the canvas that just fits a `width` x `height` image turned by `radians`.
+fn rotated_size(width: f64, height: f64, radians: f64) -> (u32, u32) {
+    let (sin, cos) = radians.sin_cos();
+    // The small slack keeps float noise from adding a row or column.
+    let out_width = (width * cos.abs() + height * sin.abs() - 1e-6).ceil().max(1.0);
+    let out_height = (width * sin.abs() + height * cos.abs() - 1e-6).ceil().max(1.0);
+    (out_width as u32, out_height as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// 4x2: top row red, green, blue, white; bottom row black.
+    fn sample() -> DynamicImage {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        DynamicImage::ImageRgb8(RgbImage::from_fn(4, 2, |x, y| {
+            if y == 0 { Rgb(colors[x as usize]) } else { Rgb([0, 0, 0]) }
+        }))
+    }
+
+    fn pixel(img: &DynamicImage, x: u32, y: u32) -> [u8; 4] {
+        img.to_rgba8().get_pixel(x, y).0
+    }
+
+    #[test]
+    fn crop_keeps_the_rectangle_and_stops_at_the_edge() {
+        let img = Transform::crop(1, 0, 2, 1).apply(sample()).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 1));
+        assert_eq!(pixel(&img, 0, 0), [0, 255, 0, 255]);
+
+        let img = Transform::crop(2, 1, 100, 100).apply(sample()).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 1));
+
+        let result = Transform::crop(4, 0, 1, 1).apply(sample());
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn crop_to_aspect_is_centred() {
+        let img = Transform::crop_to_aspect(1.0, 1.0).apply(sample()).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 2));
+        assert_eq!(pixel(&img, 0, 0), [0, 255, 0, 255]);
+
+        let img = Transform::crop_to_aspect(4.0, 1.0).apply(sample()).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 1));
+
+        let result = Transform::crop_to_aspect(0.0, 1.0).apply(sample());
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn right_angles_are_exact() {
+        let img = Transform::rotate(90.0).apply(sample()).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 4));
+        // Clockwise: the top row ends up in the right column, red on top.
+        assert_eq!(pixel(&img, 1, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(&img, 1, 3), [255, 255, 255, 255]);
+
+        let turned = Transform::rotate(-270.0).apply(sample()).unwrap();
+        assert_eq!(turned, img);
+        let turned = Transform::rotate(450.0).apply(sample()).unwrap();
+        assert_eq!(turned, img);
+
+        let img = Transform::rotate(180.0).apply(sample()).unwrap();
+        assert_eq!(pixel(&img, 0, 1), [255, 255, 255, 255]);
+        assert_eq!(Transform::rotate(360.0).apply(sample()).unwrap(), sample());
+    }
+
+    #[test]
+    fn arbitrary_angles_grow_the_canvas_and_fill_the_corners() {
+        let square = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, Rgb([0, 0, 255])));
+
+        let img = Transform::rotate(45.0).apply(square.clone()).unwrap();
+        assert_eq!((img.width(), img.height()), (15, 15));
+        assert_eq!(pixel(&img, 0, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&img, 7, 7), [0, 0, 255, 255]);
+
+        let img = Transform::rotate_with_fill(30.0, 255, 255, 0, 255).apply(square).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [255, 255, 0, 255]);
+
+        let result = Transform::rotate(f32::NAN).apply(sample());
+        assert!(matches!(result, Err(ConvertError::InvalidOption(_))));
+    }
+
+    #[test]
+    fn flips_and_order() {
+        let img = Transform::flip_horizontal().apply(sample()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [255, 255, 255, 255]);
+        let img = Transform::flip_vertical().apply(sample()).unwrap();
+        assert_eq!(pixel(&img, 0, 0), [0, 0, 0, 255]);
+
+        // Cropping the left column then turning differs from turning then cropping.
+        let crop = Transform::crop(0, 0, 1, 2);
+        let turn = Transform::rotate(90.0);
+        let img = apply_transforms(sample(), &[crop, turn], &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 1));
+        let img = apply_transforms(sample(), &[turn, crop], &DecodeLimits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (1, 2));
+    }
+
+    #[test]
+    fn rotations_are_held_to_the_limits() {
+        // 4x2 turned 90° is 2x4; at 45° the canvas grows to 5x5.
+        let limits = DecodeLimits { max_width: Some(4), max_height: Some(4), ..Default::default() };
+        let img = apply_transforms(sample(), &[Transform::rotate(90.0)], &limits).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 4));
+        let result = apply_transforms(sample(), &[Transform::rotate(45.0)], &limits);
+        assert!(matches!(result, Err(ConvertError::LimitExceeded(_))));
+    }
+}
@@ -0,0 +1,1399 @@
+use image::{DynamicImage, GenericImageView, ImageFormat, RgbaImage};
+use crate::error::ConvertError;
+use crate::metadata::{write_riff_chunk, VP8X_ALPHA};
+use crate::options::WebpOptions;
+
+// ----------------------------------------------------------------
+// Lossy WebP (VP8 key frames) in pure Rust
+// ----------------------------------------------------------------
+//
+// libwebp cannot be built for plain `wasm32-unknown-unknown`, so lossy WebP is written
+// here unless the `webp-lossy` feature brings libwebp in:
+// 1. RGB becomes limited range YUV 4:2:0, padded to whole 16x16 macroblocks by repeating
+//    the right and bottom edges.
+// 2. Each macroblock keeps the 16x16 luma and 8x8 chroma prediction (DC, V, H or TM) that
+//    is closest to the source. The 4x4 luma modes (B_PRED) are not used.
+// 3. The residue is transformed and quantized, then rebuilt exactly like the decoder will,
+//    so the following macroblocks predict from the same pixels.
+// 4. The tokens are counted before they are written, and every token probability that
+//    saves more than its update costs is sent in the frame header.
+// Alpha goes into an ALPH chunk, compressed with the lossless encoder of `image-webp`.
+// The tables come from RFC 6386, the VP8 specification.
+
+/// Largest width or height of a VP8 frame.
+const MAX_DIMENSION: u32 = 16383;
+/// Largest quantized coefficient the DCT_CAT6 token can carry.
+const MAX_LEVEL: i32 = 2048;
+/// The first partition size is stored in 19 bits of the frame tag.
+const MAX_FIRST_PARTITION: usize = (1 << 19) - 1;
+
+/// Writes `img` as a lossy WebP file at `quality` (1-100).
+pub fn encode_lossy_webp(
+    img: &DynamicImage,
+    quality: u8,
+    options: &WebpOptions,
+) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(ConvertError::encoder(
+            ImageFormat::WebP,
+            format!("lossy WebP is limited to {MAX_DIMENSION}x{MAX_DIMENSION} pixels"),
+        ));
+    }
+    let rgba = img.to_rgba8();
+    let frame = encode_frame(&rgba, quantizer_index(quality), options.method)?;
+
+    let mut body = b"WEBP".to_vec();
+    if img.color().has_alpha() {
+        let mut header = vec![VP8X_ALPHA, 0, 0, 0];
+        header.extend(&(width - 1).to_le_bytes()[..3]);
+        header.extend(&(height - 1).to_le_bytes()[..3]);
+        write_riff_chunk(&mut body, b"VP8X", &header);
+        write_riff_chunk(&mut body, b"ALPH", &encode_alpha(&rgba, options.alpha_quality)?);
+    }
+    write_riff_chunk(&mut body, b"VP8 ", &frame);
+
+    let mut riff = b"RIFF".to_vec();
+    riff.extend((body.len() as u32).to_le_bytes());
+    riff.extend(body);
+    Ok(riff)
+}
+
+/// Quantizer index (0-127) for a quality of 1-100, on the curve libwebp uses.
+fn quantizer_index(quality: u8) -> usize {
+    let quality = f64::from(quality.clamp(1, 100)) / 100.0;
+    let linear = if quality < 0.75 { quality * 2.0 / 3.0 } else { 2.0 * quality - 1.0 };
+    (127.0 * (1.0 - linear.cbrt())).round() as usize
+}
+
+/// The alpha plane, losslessly compressed after `quality` below 100 reduced its levels.
+fn encode_alpha(rgba: &RgbaImage, quality: u8) -> Result<Vec<u8>, ConvertError> {
+    let quality = u32::from(quality.min(100));
+    // libwebp keeps this many alpha levels for a given alpha quality.
+    let levels = if quality <= 70 { 2 + quality / 5 } else { 16 + (quality - 70) * 8 }.min(256);
+    let alpha: Vec<u8> = rgba
+        .pixels()
+        .map(|p| {
+            let steps = levels - 1;
+            let level = (u32::from(p[3]) * steps + 127) / 255;
+            ((level * 255 + steps / 2) / steps) as u8
+        })
+        .collect();
+
+    let mut webp = Vec::new();
+    image_webp::WebPEncoder::new(&mut webp)
+        .encode(&alpha, rgba.width(), rgba.height(), image_webp::ColorType::L8)
+        .map_err(|e| ConvertError::encoder(ImageFormat::WebP, e))?;
+    // A simple lossless file: RIFF header (12), VP8L chunk header (8), then a 5 byte
+    // bitstream header with the size, which ALPH leaves out.
+    let size = u32::from_le_bytes([webp[16], webp[17], webp[18], webp[19]]) as usize;
+    let stream = webp.get(25..20 + size).ok_or_else(|| {
+        ConvertError::encoder(ImageFormat::WebP, "unexpected lossless WebP layout")
+    })?;
+
+    // Compression 1 (lossless), no filtering, level reduction as the preprocessing.
+    let mut chunk = vec![1 | if levels < 256 { 1 << 4 } else { 0 }];
+    chunk.extend(stream);
+    Ok(chunk)
+}
+
+// ----------------------------------------------------------------
+// Frame
+// ----------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Dc,
+    V,
+    H,
+    Tm,
+}
+
+const MODES: [Mode; 4] = [Mode::Dc, Mode::V, Mode::H, Mode::Tm];
+
+/// Quantized coefficients of one macroblock, each block in raster order.
+struct Macroblock {
+    luma: Mode,
+    chroma: Mode,
+    y2: [i16; 16],
+    y: [[i16; 16]; 16],
+    /// Four U blocks, then four V blocks.
+    uv: [[i16; 16]; 8],
+}
+
+impl Macroblock {
+    fn is_empty(&self) -> bool {
+        let zero = |block: &[i16; 16]| block.iter().all(|&c| c == 0);
+        zero(&self.y2) && self.y.iter().all(zero) && self.uv.iter().all(zero)
+    }
+}
+
+fn encode_frame(rgba: &RgbaImage, q_index: usize, method: u8) -> Result<Vec<u8>, ConvertError> {
+    let (width, height) = rgba.dimensions();
+    let mb_width = width.div_ceil(16) as usize;
+    let mb_height = height.div_ceil(16) as usize;
+    let source = YuvPlanes::from_rgba(rgba, mb_width, mb_height);
+    let mut recon = YuvPlanes::new(mb_width, mb_height);
+    let quant = Quantizers::new(q_index);
+    // Method 0 is the fastest: DC prediction only.
+    let modes: &[Mode] = if method == 0 { &MODES[..1] } else { &MODES };
+
+    let mut macroblocks = Vec::with_capacity(mb_width * mb_height);
+    for mby in 0..mb_height {
+        for mbx in 0..mb_width {
+            macroblocks.push(encode_macroblock(&source, &mut recon, mbx, mby, modes, &quant));
+        }
+    }
+
+    // Token statistics first, to choose the probabilities.
+    let mut counts = TokenCounts::new();
+    tokenize(&macroblocks, mb_width, &mut counts);
+    let mut probs = COEFF_PROBS;
+
+    let mut header = BoolEncoder::new();
+    header.put_literal(0, 1); // colour space
+    header.put_literal(0, 1); // clamping required
+    header.put_literal(0, 1); // no segmentation
+    header.put_literal(0, 1); // normal loop filter
+    header.put_literal(filter_level(q_index), 6);
+    header.put_literal(0, 3); // sharpness
+    header.put_literal(0, 1); // no loop filter deltas
+    header.put_literal(0, 2); // one token partition
+    header.put_literal(q_index as u32, 7);
+    for _ in 0..5 {
+        header.put_literal(0, 1); // no quantizer deltas
+    }
+    header.put_literal(0, 1); // refresh entropy probabilities
+    for (i, plane) in COEFF_UPDATE_PROBS.iter().enumerate() {
+        for (j, band) in plane.iter().enumerate() {
+            for (k, context) in band.iter().enumerate() {
+                for (t, &update_prob) in context.iter().enumerate() {
+                    let [zeros, ones] = counts.0[i][j][k][t];
+                    let old = probs[i][j][k][t];
+                    let new = branch_prob(zeros, ones);
+                    let savings = branch_cost(zeros, ones, old)
+                        - branch_cost(zeros, ones, new)
+                        - (bit_cost(update_prob, true) + 8.0 - bit_cost(update_prob, false));
+                    let update = savings > 0.0;
+                    header.put_bool(update_prob, update);
+                    if update {
+                        header.put_literal(u32::from(new), 8);
+                        probs[i][j][k][t] = new;
+                    }
+                }
+            }
+        }
+    }
+    let skipped = macroblocks.iter().filter(|mb| mb.is_empty()).count() as u32;
+    let skip_prob = branch_prob(macroblocks.len() as u32 - skipped, skipped);
+    header.put_literal(1, 1); // macroblocks may skip their coefficients
+    header.put_literal(u32::from(skip_prob), 8);
+
+    for mb in &macroblocks {
+        header.put_bool(skip_prob, mb.is_empty());
+        // Key frame luma tree, without its first branch (B_PRED).
+        header.put_bool(145, true);
+        match mb.luma {
+            Mode::Dc | Mode::V => {
+                header.put_bool(156, false);
+                header.put_bool(163, mb.luma == Mode::V);
+            }
+            Mode::H | Mode::Tm => {
+                header.put_bool(156, true);
+                header.put_bool(128, mb.luma == Mode::Tm);
+            }
+        }
+        header.put_bool(142, mb.chroma != Mode::Dc);
+        if mb.chroma != Mode::Dc {
+            header.put_bool(114, mb.chroma != Mode::V);
+            if mb.chroma != Mode::V {
+                header.put_bool(183, mb.chroma == Mode::Tm);
+            }
+        }
+    }
+    let first_partition = header.finish();
+    if first_partition.len() > MAX_FIRST_PARTITION {
+        return Err(ConvertError::encoder(ImageFormat::WebP, "VP8 mode partition too large"));
+    }
+
+    let mut writer = TokenWriter { encoder: BoolEncoder::new(), probs: &probs };
+    tokenize(&macroblocks, mb_width, &mut writer);
+    let tokens = writer.encoder.finish();
+
+    // Key frame, version 0, shown, then the first partition size.
+    let tag = (first_partition.len() as u32) << 5 | 1 << 4;
+    let mut frame = Vec::with_capacity(10 + first_partition.len() + tokens.len());
+    frame.extend(&tag.to_le_bytes()[..3]);
+    frame.extend([0x9d, 0x01, 0x2a]);
+    frame.extend((width as u16).to_le_bytes());
+    frame.extend((height as u16).to_le_bytes());
+    frame.extend(first_partition);
+    frame.extend(tokens);
+    Ok(frame)
+}
+
+/// Loop filter strength for a quantizer: stronger as the blocks get coarser.
+fn filter_level(q_index: usize) -> u32 {
+    (AC_QUANT[q_index] as u32 * 5 / 16).min(63)
+}
+
+// ----------------------------------------------------------------
+// Prediction, transform and quantization
+// ----------------------------------------------------------------
+
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Plane {
+        Plane { data: vec![0; width * height], stride: width }
+    }
+
+    fn at(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.stride + x]
+    }
+}
+
+struct YuvPlanes {
+    y: Plane,
+    u: Plane,
+    v: Plane,
+}
+
+impl YuvPlanes {
+    fn new(mb_width: usize, mb_height: usize) -> YuvPlanes {
+        YuvPlanes {
+            y: Plane::new(mb_width * 16, mb_height * 16),
+            u: Plane::new(mb_width * 8, mb_height * 8),
+            v: Plane::new(mb_width * 8, mb_height * 8),
+        }
+    }
+
+    /// BT.601 limited range, with the integer coefficients libwebp uses.
+    fn from_rgba(rgba: &RgbaImage, mb_width: usize, mb_height: usize) -> YuvPlanes {
+        let mut planes = YuvPlanes::new(mb_width, mb_height);
+        let (width, height) = rgba.dimensions();
+        let pixel = |x: usize, y: usize| {
+            let p = rgba.get_pixel((x as u32).min(width - 1), (y as u32).min(height - 1));
+            [i32::from(p[0]), i32::from(p[1]), i32::from(p[2])]
+        };
+
+        for y in 0..mb_height * 16 {
+            for x in 0..mb_width * 16 {
+                let [r, g, b] = pixel(x, y);
+                let luma = 16839 * r + 33059 * g + 6420 * b + (1 << 15) + (16 << 16);
+                planes.y.data[y * planes.y.stride + x] = (luma >> 16) as u8;
+            }
+        }
+        for y in 0..mb_height * 8 {
+            for x in 0..mb_width * 8 {
+                // Sums over the 2x2 pixels each chroma sample covers.
+                let [mut r, mut g, mut b] = [0; 3];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let p = pixel(2 * x + dx, 2 * y + dy);
+                    (r, g, b) = (r + p[0], g + p[1], b + p[2]);
+                }
+                let chroma = |c: i32| ((c + (1 << 17) + (128 << 18)) >> 18).clamp(0, 255) as u8;
+                let i = y * planes.u.stride + x;
+                planes.u.data[i] = chroma(-9719 * r - 19081 * g + 28800 * b);
+                planes.v.data[i] = chroma(28800 * r - 24116 * g - 4684 * b);
+            }
+        }
+        planes
+    }
+}
+
+/// Predicts the `size` x `size` block at (`x0`, `y0`) of `recon` like the decoder does.
+/// Edges outside the frame read as 127 above and 129 to the left.
+fn predict(recon: &Plane, x0: usize, y0: usize, size: usize, mode: Mode) -> [u8; 256] {
+    let (has_above, has_left) = (y0 > 0, x0 > 0);
+    let above = |i: usize| if has_above { recon.at(x0 + i, y0 - 1) } else { 127 };
+    let left = |i: usize| if has_left { recon.at(x0 - 1, y0 + i) } else { 129 };
+    let corner = match (has_above, has_left) {
+        (false, _) => 127,
+        (true, false) => 129,
+        (true, true) => recon.at(x0 - 1, y0 - 1),
+    };
+
+    let mut pred = [0u8; 256];
+    let dc = {
+        let mut sum = 0u32;
+        let mut count = 0u32;
+        if has_above {
+            sum += (0..size).map(|i| u32::from(above(i))).sum::<u32>();
+            count += size as u32;
+        }
+        if has_left {
+            sum += (0..size).map(|i| u32::from(left(i))).sum::<u32>();
+            count += size as u32;
+        }
+        (sum + count / 2).checked_div(count).map_or(128, |dc| dc as u8)
+    };
+    for y in 0..size {
+        for x in 0..size {
+            pred[y * size + x] = match mode {
+                Mode::Dc => dc,
+                Mode::V => above(x),
+                Mode::H => left(y),
+                Mode::Tm => {
+                    (i32::from(left(y)) + i32::from(above(x)) - i32::from(corner)).clamp(0, 255)
+                        as u8
+                }
+            };
+        }
+    }
+    pred
+}
+
+/// The mode in `modes` whose prediction is closest to `source`, and that prediction.
+/// Chroma compares U and V together.
+fn best_prediction(
+    sources: &[&Plane],
+    recons: &[&Plane],
+    x0: usize,
+    y0: usize,
+    size: usize,
+    modes: &[Mode],
+) -> (Mode, Vec<[u8; 256]>) {
+    let mut best: Option<(u64, Mode, Vec<[u8; 256]>)> = None;
+    for &mode in modes {
+        let preds: Vec<[u8; 256]> =
+            recons.iter().map(|recon| predict(recon, x0, y0, size, mode)).collect();
+        let mut error = 0u64;
+        for (source, pred) in sources.iter().zip(&preds) {
+            for y in 0..size {
+                for x in 0..size {
+                    let diff = i32::from(source.at(x0 + x, y0 + y)) - i32::from(pred[y * size + x]);
+                    error += u64::from(diff.unsigned_abs().pow(2));
+                }
+            }
+        }
+        if best.as_ref().is_none_or(|(best_error, ..)| error < *best_error) {
+            best = Some((error, mode, preds));
+        }
+    }
+    let (_, mode, preds) = best.expect("at least one prediction mode");
+    (mode, preds)
+}
+
+/// Where a 4x4 block sits: block (`bx`, `by`) of the `size` x `size` prediction at
+/// (`x0`, `y0`).
+#[derive(Clone, Copy)]
+struct BlockAt {
+    x0: usize,
+    y0: usize,
+    size: usize,
+    bx: usize,
+    by: usize,
+}
+
+/// The residue of `source` against `pred` for one 4x4 block.
+fn residue(source: &Plane, pred: &[u8; 256], at: BlockAt) -> [i32; 16] {
+    let BlockAt { x0, y0, size, bx, by } = at;
+    let mut block = [0; 16];
+    for y in 0..4 {
+        for x in 0..4 {
+            let (px, py) = (bx * 4 + x, by * 4 + y);
+            block[y * 4 + x] =
+                i32::from(source.at(x0 + px, y0 + py)) - i32::from(pred[py * size + px]);
+        }
+    }
+    block
+}
+
+/// Adds the dequantized, inverse transformed `block` to `pred` and stores it in `recon`.
+fn reconstruct(recon: &mut Plane, pred: &[u8; 256], at: BlockAt, block: &mut [i32; 16]) {
+    let BlockAt { x0, y0, size, bx, by } = at;
+    idct4x4(block);
+    for y in 0..4 {
+        for x in 0..4 {
+            let (px, py) = (bx * 4 + x, by * 4 + y);
+            let value = i32::from(pred[py * size + px]) + block[y * 4 + x];
+            recon.data[(y0 + py) * recon.stride + x0 + px] = value.clamp(0, 255) as u8;
+        }
+    }
+}
+
+fn encode_macroblock(
+    source: &YuvPlanes,
+    recon: &mut YuvPlanes,
+    mbx: usize,
+    mby: usize,
+    modes: &[Mode],
+    quant: &Quantizers,
+) -> Macroblock {
+    let (x0, y0) = (mbx * 16, mby * 16);
+    let (luma, preds) = best_prediction(&[&source.y], &[&recon.y], x0, y0, 16, modes);
+    let pred = &preds[0];
+
+    // Luma: the DC of every block goes through the second order (Y2) block.
+    let at = |i: usize| BlockAt { x0, y0, size: 16, bx: i % 4, by: i / 4 };
+    let mut y = [[0; 16]; 16];
+    let mut dc = [0; 16];
+    for (i, levels) in y.iter_mut().enumerate() {
+        let coeffs = fdct4x4(&residue(&source.y, pred, at(i)));
+        dc[i] = coeffs[0];
+        *levels = std::array::from_fn(|k| if k == 0 { 0 } else { quant.y.quantize(coeffs[k], k) });
+    }
+    let walsh = fwht4x4(&dc);
+    let y2 = std::array::from_fn(|k| quant.y2.quantize(walsh[k], k));
+
+    let mut dc = std::array::from_fn(|k| quant.y2.dequantize(y2[k], k));
+    iwht4x4(&mut dc);
+    for (i, levels) in y.iter().enumerate() {
+        let mut block = std::array::from_fn(|k| quant.y.dequantize(levels[k], k));
+        block[0] = dc[i];
+        reconstruct(&mut recon.y, pred, at(i), &mut block);
+    }
+
+    // Chroma: U and V share the mode, every block keeps its own DC.
+    let (x0, y0) = (mbx * 8, mby * 8);
+    let (chroma, preds) =
+        best_prediction(&[&source.u, &source.v], &[&recon.u, &recon.v], x0, y0, 8, modes);
+    let mut uv = [[0; 16]; 8];
+    for (plane, (source, recon)) in
+        [(&source.u, &mut recon.u), (&source.v, &mut recon.v)].into_iter().enumerate()
+    {
+        for i in 0..4 {
+            let at = BlockAt { x0, y0, size: 8, bx: i % 2, by: i / 2 };
+            let coeffs = fdct4x4(&residue(source, &preds[plane], at));
+            let levels = &mut uv[plane * 4 + i];
+            *levels = std::array::from_fn(|k| quant.uv.quantize(coeffs[k], k));
+            let mut block = std::array::from_fn(|k| quant.uv.dequantize(levels[k], k));
+            reconstruct(recon, &preds[plane], at, &mut block);
+        }
+    }
+
+    Macroblock { luma, chroma, y2, y, uv }
+}
+
+#[derive(Clone, Copy)]
+struct Quantizer {
+    dc: i32,
+    ac: i32,
+}
+
+impl Quantizer {
+    /// Coefficient `index` (in raster order) of a block divided by its step. AC rounds
+    /// down a little more, which drops noise that would cost more than it shows.
+    fn quantize(self, coeff: i32, index: usize) -> i16 {
+        let (step, bias) =
+            if index == 0 { (self.dc, self.dc / 2) } else { (self.ac, self.ac * 3 / 8) };
+        let level = ((coeff.abs() + bias) / step).min(MAX_LEVEL) as i16;
+        if coeff < 0 { -level } else { level }
+    }
+
+    fn dequantize(self, level: i16, index: usize) -> i32 {
+        i32::from(level) * if index == 0 { self.dc } else { self.ac }
+    }
+}
+
+struct Quantizers {
+    y: Quantizer,
+    y2: Quantizer,
+    uv: Quantizer,
+}
+
+impl Quantizers {
+    /// The steps a decoder derives from `q_index` when no deltas are sent.
+    fn new(q_index: usize) -> Quantizers {
+        let (dc, ac) = (i32::from(DC_QUANT[q_index]), i32::from(AC_QUANT[q_index]));
+        Quantizers {
+            y: Quantizer { dc, ac },
+            y2: Quantizer { dc: dc * 2, ac: (ac * 155 / 100).max(8) },
+            uv: Quantizer { dc: dc.min(132), ac },
+        }
+    }
+}
+
+/// Forward DCT, the counterpart of `idct4x4` (as in libvpx).
+fn fdct4x4(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for (row, out) in input.chunks_exact(4).zip(tmp.chunks_exact_mut(4)) {
+        let a1 = (row[0] + row[3]) * 8;
+        let b1 = (row[1] + row[2]) * 8;
+        let c1 = (row[1] - row[2]) * 8;
+        let d1 = (row[0] - row[3]) * 8;
+        out[0] = a1 + b1;
+        out[2] = a1 - b1;
+        out[1] = (c1 * 2217 + d1 * 5352 + 14500) >> 12;
+        out[3] = (d1 * 2217 - c1 * 5352 + 7500) >> 12;
+    }
+    let mut output = [0; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[12 + i];
+        let b1 = tmp[4 + i] + tmp[8 + i];
+        let c1 = tmp[4 + i] - tmp[8 + i];
+        let d1 = tmp[i] - tmp[12 + i];
+        output[i] = (a1 + b1 + 7) >> 4;
+        output[8 + i] = (a1 - b1 + 7) >> 4;
+        output[4 + i] = ((c1 * 2217 + d1 * 5352 + 12000) >> 16) + i32::from(d1 != 0);
+        output[12 + i] = (d1 * 2217 - c1 * 5352 + 51000) >> 16;
+    }
+    output
+}
+
+/// Inverse DCT, bit exact with the decoder.
+fn idct4x4(block: &mut [i32; 16]) {
+    const C1: i64 = 20091;
+    const C2: i64 = 35468;
+    let mut tmp = [0i64; 16];
+    for i in 0..4 {
+        let [b0, b4, b8, b12] = [0, 4, 8, 12].map(|j| i64::from(block[j + i]));
+        let a1 = b0 + b8;
+        let b1 = b0 - b8;
+        let c1 = ((b4 * C2) >> 16) - (b12 + ((b12 * C1) >> 16));
+        let d1 = (b4 + ((b4 * C1) >> 16)) + ((b12 * C2) >> 16);
+        tmp[i] = a1 + d1;
+        tmp[4 + i] = b1 + c1;
+        tmp[8 + i] = b1 - c1;
+        tmp[12 + i] = a1 - d1;
+    }
+    for i in 0..4 {
+        let [t0, t1, t2, t3] = [0, 1, 2, 3].map(|j| tmp[4 * i + j]);
+        let a1 = t0 + t2;
+        let b1 = t0 - t2;
+        let c1 = ((t1 * C2) >> 16) - (t3 + ((t3 * C1) >> 16));
+        let d1 = (t1 + ((t1 * C1) >> 16)) + ((t3 * C2) >> 16);
+        block[4 * i] = ((a1 + d1 + 4) >> 3) as i32;
+        block[4 * i + 1] = ((b1 + c1 + 4) >> 3) as i32;
+        block[4 * i + 2] = ((b1 - c1 + 4) >> 3) as i32;
+        block[4 * i + 3] = ((a1 - d1 + 4) >> 3) as i32;
+    }
+}
+
+/// Forward Walsh-Hadamard transform of the 16 luma DCs (as in libvpx).
+fn fwht4x4(input: &[i32; 16]) -> [i32; 16] {
+    let mut tmp = [0; 16];
+    for (row, out) in input.chunks_exact(4).zip(tmp.chunks_exact_mut(4)) {
+        let a1 = (row[0] + row[2]) * 4;
+        let d1 = (row[1] + row[3]) * 4;
+        let c1 = (row[1] - row[3]) * 4;
+        let b1 = (row[0] - row[2]) * 4;
+        out[0] = a1 + d1 + i32::from(a1 != 0);
+        out[1] = b1 + c1;
+        out[2] = b1 - c1;
+        out[3] = a1 - d1;
+    }
+    let mut output = [0; 16];
+    for i in 0..4 {
+        let a1 = tmp[i] + tmp[8 + i];
+        let d1 = tmp[4 + i] + tmp[12 + i];
+        let c1 = tmp[4 + i] - tmp[12 + i];
+        let b1 = tmp[i] - tmp[8 + i];
+        for (j, v) in [a1 + d1, b1 + c1, b1 - c1, a1 - d1].into_iter().enumerate() {
+            output[4 * j + i] = (v + i32::from(v < 0) + 3) >> 3;
+        }
+    }
+    output
+}
+
+/// Inverse Walsh-Hadamard transform, bit exact with the decoder.
+fn iwht4x4(block: &mut [i32; 16]) {
+    for i in 0..4 {
+        let a1 = block[i] + block[12 + i];
+        let b1 = block[4 + i] + block[8 + i];
+        let c1 = block[4 + i] - block[8 + i];
+        let d1 = block[i] - block[12 + i];
+        block[i] = a1 + b1;
+        block[4 + i] = c1 + d1;
+        block[8 + i] = a1 - b1;
+        block[12 + i] = d1 - c1;
+    }
+    for row in block.chunks_exact_mut(4) {
+        let a1 = row[0] + row[3];
+        let b1 = row[1] + row[2];
+        let c1 = row[1] - row[2];
+        let d1 = row[0] - row[3];
+        row[0] = (a1 + b1 + 3) >> 3;
+        row[1] = (c1 + d1 + 3) >> 3;
+        row[2] = (a1 - b1 + 3) >> 3;
+        row[3] = (d1 - c1 + 3) >> 3;
+    }
+}
+
+// ----------------------------------------------------------------
+// Tokens and the boolean entropy coder
+// ----------------------------------------------------------------
+
+/// Token probabilities by plane type, band and context.
+type TokenProbs = [[[[u8; 11]; 3]; 8]; 4];
+/// Zeros and ones seen at each token probability.
+type BranchCounts = [[[[[u32; 2]; 11]; 3]; 8]; 4];
+
+/// Receives the branches of the token tree; `fixed` bits have a probability of their own.
+trait TokenSink {
+    fn branch(&mut self, plane: usize, band: usize, context: usize, node: usize, bit: bool);
+    fn fixed(&mut self, prob: u8, bit: bool);
+}
+
+/// How often each token tree branch went each way.
+struct TokenCounts(Box<BranchCounts>);
+
+impl TokenCounts {
+    fn new() -> TokenCounts {
+        TokenCounts(Box::new([[[[[0; 2]; 11]; 3]; 8]; 4]))
+    }
+}
+
+impl TokenSink for TokenCounts {
+    fn branch(&mut self, plane: usize, band: usize, context: usize, node: usize, bit: bool) {
+        self.0[plane][band][context][node][usize::from(bit)] += 1;
+    }
+
+    fn fixed(&mut self, _prob: u8, _bit: bool) {}
+}
+
+struct TokenWriter<'a> {
+    encoder: BoolEncoder,
+    probs: &'a TokenProbs,
+}
+
+impl TokenSink for TokenWriter<'_> {
+    fn branch(&mut self, plane: usize, band: usize, context: usize, node: usize, bit: bool) {
+        self.encoder.put_bool(self.probs[plane][band][context][node], bit);
+    }
+
+    fn fixed(&mut self, prob: u8, bit: bool) {
+        self.encoder.put_bool(prob, bit);
+    }
+}
+
+/// Feeds the tokens of every macroblock to `sink`, tracking which neighbouring blocks
+/// had coefficients (the context of each block's first token).
+fn tokenize(macroblocks: &[Macroblock], mb_width: usize, sink: &mut impl TokenSink) {
+    // Per column above and for the macroblock to the left: Y2, four Y, two U, two V.
+    let mut above = vec![[false; 9]; mb_width];
+    let mut left = [false; 9];
+    for (i, mb) in macroblocks.iter().enumerate() {
+        let top = &mut above[i % mb_width];
+        if i % mb_width == 0 {
+            left = [false; 9];
+        }
+        if mb.is_empty() {
+            *top = [false; 9];
+            left = [false; 9];
+            continue;
+        }
+
+        let mut code = |levels: &[i16; 16], plane: usize, first: usize, t: usize, l: usize| {
+            let context = usize::from(top[t]) + usize::from(left[l]);
+            let nonzero = code_block(levels, plane, first, context, sink);
+            (top[t], left[l]) = (nonzero, nonzero);
+        };
+        // Plane types: 0 is Y after Y2, 1 is Y2, 2 is chroma.
+        code(&mb.y2, 1, 0, 0, 0);
+        for (b, levels) in mb.y.iter().enumerate() {
+            code(levels, 0, 1, 1 + b % 4, 1 + b / 4);
+        }
+        for (b, levels) in mb.uv.iter().enumerate() {
+            let offset = if b < 4 { 5 } else { 7 };
+            code(levels, 2, 0, offset + b % 2, offset + b % 4 / 2);
+        }
+    }
+}
+
+/// Codes the coefficients of one block from position `first` in zigzag order.
+/// Returns whether any were non-zero.
+fn code_block(
+    levels: &[i16; 16],
+    plane: usize,
+    first: usize,
+    mut context: usize,
+    sink: &mut impl TokenSink,
+) -> bool {
+    let Some(last) = (first..16).rev().find(|&i| levels[usize::from(ZIGZAG[i])] != 0) else {
+        sink.branch(plane, usize::from(COEFF_BANDS[first]), context, 0, false);
+        return false;
+    };
+
+    let mut after_zero = false;
+    for i in first..=last {
+        let band = usize::from(COEFF_BANDS[i]);
+        let level = levels[usize::from(ZIGZAG[i])];
+        let value = u32::from(level.unsigned_abs());
+        let mut branch = |node: usize, bit: bool| sink.branch(plane, band, context, node, bit);
+
+        // No end of block can follow a zero, so that branch is skipped.
+        if !after_zero {
+            branch(0, true);
+        }
+        branch(1, value != 0);
+        if value == 0 {
+            after_zero = true;
+            context = 0;
+            continue;
+        }
+        branch(2, value != 1);
+        if value > 1 {
+            branch(3, value > 4);
+            if value <= 4 {
+                branch(4, value != 2);
+                if value != 2 {
+                    branch(5, value == 4);
+                }
+            } else {
+                let category = DCT_CAT_BASE.iter().rposition(|&base| value >= u32::from(base));
+                let category = category.expect("values above 4 have a category");
+                branch(6, category >= 2);
+                match category {
+                    0 | 1 => branch(7, category == 1),
+                    _ => {
+                        branch(8, category >= 4);
+                        if category < 4 {
+                            branch(9, category == 3);
+                        } else {
+                            branch(10, category == 5);
+                        }
+                    }
+                }
+                let extra = value - u32::from(DCT_CAT_BASE[category]);
+                let probs = PROB_DCT_CAT[category].iter().take_while(|&&p| p != 0);
+                let bits = probs.clone().count();
+                for (n, &prob) in probs.enumerate() {
+                    sink.fixed(prob, extra >> (bits - 1 - n) & 1 == 1);
+                }
+            }
+        }
+        sink.fixed(128, level < 0);
+        after_zero = false;
+        context = if value == 1 { 1 } else { 2 };
+    }
+    if last < 15 {
+        sink.branch(plane, usize::from(COEFF_BANDS[last + 1]), context, 0, false);
+    }
+    true
+}
+
+/// Probability (of a 0) that fits `zeros` and `ones` best.
+fn branch_prob(zeros: u32, ones: u32) -> u8 {
+    let total = u64::from(zeros) + u64::from(ones);
+    if total == 0 {
+        return 255;
+    }
+    ((u64::from(zeros) * 256 + total / 2) / total).clamp(1, 255) as u8
+}
+
+/// Bits spent on `bit` at probability `prob` (of a 0).
+fn bit_cost(prob: u8, bit: bool) -> f64 {
+    let p = if bit { 256 - u32::from(prob) } else { u32::from(prob) };
+    -(f64::from(p) / 256.0).log2()
+}
+
+fn branch_cost(zeros: u32, ones: u32, prob: u8) -> f64 {
+    f64::from(zeros) * bit_cost(prob, false) + f64::from(ones) * bit_cost(prob, true)
+}
+
+/// The boolean entropy encoder of RFC 6386, section 7.
+struct BoolEncoder {
+    output: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: i32,
+}
+
+impl BoolEncoder {
+    fn new() -> BoolEncoder {
+        BoolEncoder { output: Vec::new(), range: 255, bottom: 0, bit_count: 24 }
+    }
+
+    /// Propagates a carry into the bytes already written.
+    fn add_one_to_output(&mut self) {
+        for byte in self.output.iter_mut().rev() {
+            if *byte == 255 {
+                *byte = 0;
+            } else {
+                *byte += 1;
+                break;
+            }
+        }
+    }
+
+    /// Writes `bit`, which is 0 with probability `prob` / 256.
+    fn put_bool(&mut self, prob: u8, bit: bool) {
+        let split = 1 + (((self.range - 1) * u32::from(prob)) >> 8);
+        if bit {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+        while self.range < 128 {
+            self.range <<= 1;
+            if self.bottom & (1 << 31) != 0 {
+                self.add_one_to_output();
+            }
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.output.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    /// Writes the low `bits` of `value`, most significant first, at even odds.
+    fn put_literal(&mut self, value: u32, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.put_bool(128, value >> bit & 1 == 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let count = self.bit_count;
+        let mut value = self.bottom;
+        if value & (1 << (32 - count)) != 0 {
+            self.add_one_to_output();
+        }
+        value <<= count & 7;
+        let count = count >> 3;
+        for _ in 0..count {
+            value <<= 8;
+        }
+        for _ in 0..4 {
+            self.output.push((value >> 24) as u8);
+            value <<= 8;
+        }
+        self.output
+    }
+}
+
+// ----------------------------------------------------------------
+// Tables (RFC 6386)
+// ----------------------------------------------------------------
+
+/// Probability that each token probability is updated in the frame header (13.4).
+const COEFF_UPDATE_PROBS: TokenProbs = [
+    [
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [176, 246, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [223, 241, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 244, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [234, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 246, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [239, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 253, 255, 254, 255, 255, 255, 255, 255, 255],
+            [250, 255, 254, 255, 254, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [217, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [225, 252, 241, 253, 255, 255, 254, 255, 255, 255, 255],
+            [234, 250, 241, 250, 253, 255, 253, 254, 255, 255, 255],
+        ],
+        [
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [223, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [238, 253, 254, 254, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [247, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [186, 251, 250, 255, 255, 255, 255, 255, 255, 255, 255],
+            [234, 251, 244, 254, 255, 255, 255, 255, 255, 255, 255],
+            [251, 251, 243, 253, 254, 255, 254, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [236, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [251, 253, 253, 254, 254, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+    [
+        [
+            [248, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 254, 252, 254, 255, 255, 255, 255, 255, 255, 255],
+            [248, 254, 249, 253, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [246, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 254, 251, 254, 254, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 254, 252, 255, 255, 255, 255, 255, 255, 255, 255],
+            [248, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 255, 254, 254, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [245, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [253, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 251, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [252, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 252, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [249, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 253, 255, 255, 255, 255, 255, 255, 255, 255],
+            [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+        [
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+            [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255],
+        ],
+    ],
+];
+
+/// Token probabilities a frame starts from (13.5).
+const COEFF_PROBS: TokenProbs = [
+    [
+        [
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [253, 136, 254, 255, 228, 219, 128, 128, 128, 128, 128],
+            [189, 129, 242, 255, 227, 213, 255, 219, 128, 128, 128],
+            [106, 126, 227, 252, 214, 209, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 98, 248, 255, 236, 226, 255, 255, 128, 128, 128],
+            [181, 133, 238, 254, 221, 234, 255, 154, 128, 128, 128],
+            [78, 134, 202, 247, 198, 180, 255, 219, 128, 128, 128],
+        ],
+        [
+            [1, 185, 249, 255, 243, 255, 128, 128, 128, 128, 128],
+            [184, 150, 247, 255, 236, 224, 128, 128, 128, 128, 128],
+            [77, 110, 216, 255, 236, 230, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 101, 251, 255, 241, 255, 128, 128, 128, 128, 128],
+            [170, 139, 241, 252, 236, 209, 255, 255, 128, 128, 128],
+            [37, 116, 196, 243, 228, 255, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 204, 254, 255, 245, 255, 128, 128, 128, 128, 128],
+            [207, 160, 250, 255, 238, 128, 128, 128, 128, 128, 128],
+            [102, 103, 231, 255, 211, 171, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 152, 252, 255, 240, 255, 128, 128, 128, 128, 128],
+            [177, 135, 243, 255, 234, 225, 128, 128, 128, 128, 128],
+            [80, 129, 211, 255, 194, 224, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [246, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [255, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [198, 35, 237, 223, 193, 187, 162, 160, 145, 155, 62],
+            [131, 45, 198, 221, 172, 176, 220, 157, 252, 221, 1],
+            [68, 47, 146, 208, 149, 167, 221, 162, 255, 223, 128],
+        ],
+        [
+            [1, 149, 241, 255, 221, 224, 255, 255, 128, 128, 128],
+            [184, 141, 234, 253, 222, 220, 255, 199, 128, 128, 128],
+            [81, 99, 181, 242, 176, 190, 249, 202, 255, 255, 128],
+        ],
+        [
+            [1, 129, 232, 253, 214, 197, 242, 196, 255, 255, 128],
+            [99, 121, 210, 250, 201, 198, 255, 202, 128, 128, 128],
+            [23, 91, 163, 242, 170, 187, 247, 210, 255, 255, 128],
+        ],
+        [
+            [1, 200, 246, 255, 234, 255, 128, 128, 128, 128, 128],
+            [109, 178, 241, 255, 231, 245, 255, 255, 128, 128, 128],
+            [44, 130, 201, 253, 205, 192, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 132, 239, 251, 219, 209, 255, 165, 128, 128, 128],
+            [94, 136, 225, 251, 218, 190, 255, 255, 128, 128, 128],
+            [22, 100, 174, 245, 186, 161, 255, 199, 128, 128, 128],
+        ],
+        [
+            [1, 182, 249, 255, 232, 235, 128, 128, 128, 128, 128],
+            [124, 143, 241, 255, 227, 234, 128, 128, 128, 128, 128],
+            [35, 77, 181, 251, 193, 211, 255, 205, 128, 128, 128],
+        ],
+        [
+            [1, 157, 247, 255, 236, 231, 255, 255, 128, 128, 128],
+            [121, 141, 235, 255, 225, 227, 255, 255, 128, 128, 128],
+            [45, 99, 188, 251, 195, 217, 255, 224, 128, 128, 128],
+        ],
+        [
+            [1, 1, 251, 255, 213, 255, 128, 128, 128, 128, 128],
+            [203, 1, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+            [137, 1, 177, 255, 224, 255, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [253, 9, 248, 251, 207, 208, 255, 192, 128, 128, 128],
+            [175, 13, 224, 243, 193, 185, 249, 198, 255, 255, 128],
+            [73, 17, 171, 221, 161, 179, 236, 167, 255, 234, 128],
+        ],
+        [
+            [1, 95, 247, 253, 212, 183, 255, 255, 128, 128, 128],
+            [239, 90, 244, 250, 211, 209, 255, 255, 128, 128, 128],
+            [155, 77, 195, 248, 188, 195, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 24, 239, 251, 218, 219, 255, 205, 128, 128, 128],
+            [201, 51, 219, 255, 196, 186, 128, 128, 128, 128, 128],
+            [69, 46, 190, 239, 201, 218, 255, 228, 128, 128, 128],
+        ],
+        [
+            [1, 191, 251, 255, 255, 128, 128, 128, 128, 128, 128],
+            [223, 165, 249, 255, 213, 255, 128, 128, 128, 128, 128],
+            [141, 124, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 16, 248, 255, 255, 128, 128, 128, 128, 128, 128],
+            [190, 36, 230, 255, 236, 255, 128, 128, 128, 128, 128],
+            [149, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 226, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [247, 192, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [240, 128, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [1, 134, 252, 255, 255, 128, 128, 128, 128, 128, 128],
+            [213, 62, 250, 255, 255, 128, 128, 128, 128, 128, 128],
+            [55, 93, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+        [
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+            [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+    [
+        [
+            [202, 24, 213, 235, 186, 191, 220, 160, 240, 175, 255],
+            [126, 38, 182, 232, 169, 184, 228, 174, 255, 187, 128],
+            [61, 46, 138, 219, 151, 178, 240, 170, 255, 216, 128],
+        ],
+        [
+            [1, 112, 230, 250, 199, 191, 247, 159, 255, 255, 128],
+            [166, 109, 228, 252, 211, 215, 255, 174, 128, 128, 128],
+            [39, 77, 162, 232, 172, 180, 245, 178, 255, 255, 128],
+        ],
+        [
+            [1, 52, 220, 246, 198, 199, 249, 220, 255, 255, 128],
+            [124, 74, 191, 243, 183, 193, 250, 221, 255, 255, 128],
+            [24, 71, 130, 219, 154, 170, 243, 182, 255, 255, 128],
+        ],
+        [
+            [1, 182, 225, 249, 219, 240, 255, 224, 128, 128, 128],
+            [149, 150, 226, 252, 216, 205, 255, 171, 128, 128, 128],
+            [28, 108, 170, 242, 183, 194, 254, 223, 255, 255, 128],
+        ],
+        [
+            [1, 81, 230, 252, 204, 203, 255, 192, 128, 128, 128],
+            [123, 102, 209, 247, 188, 196, 255, 233, 128, 128, 128],
+            [20, 95, 153, 243, 164, 173, 255, 203, 128, 128, 128],
+        ],
+        [
+            [1, 222, 248, 255, 216, 213, 128, 128, 128, 128, 128],
+            [168, 175, 246, 252, 235, 205, 255, 255, 128, 128, 128],
+            [47, 116, 215, 255, 211, 212, 255, 255, 128, 128, 128],
+        ],
+        [
+            [1, 121, 236, 253, 212, 214, 255, 255, 128, 128, 128],
+            [141, 84, 213, 252, 201, 202, 255, 219, 128, 128, 128],
+            [42, 80, 160, 240, 162, 185, 255, 205, 128, 128, 128],
+        ],
+        [
+            [1, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [244, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+            [238, 1, 255, 128, 128, 128, 128, 128, 128, 128, 128],
+        ],
+    ],
+];
+
+/// Probabilities of the extra bits of DCT_CAT1 to DCT_CAT6, ending at a 0.
+const PROB_DCT_CAT: [[u8; 12]; 6] = [
+    [159, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [165, 145, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [173, 148, 140, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [176, 155, 140, 135, 0, 0, 0, 0, 0, 0, 0, 0],
+    [180, 157, 141, 134, 130, 0, 0, 0, 0, 0, 0, 0],
+    [254, 254, 243, 230, 196, 177, 153, 140, 133, 130, 129, 0],
+];
+
+/// Smallest value of each category.
+const DCT_CAT_BASE: [u8; 6] = [5, 7, 11, 19, 35, 67];
+/// Band of each zigzag position, which picks its token probabilities.
+const COEFF_BANDS: [u8; 16] = [0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7];
+
+#[rustfmt::skip]
+const DC_QUANT: [i16; 128] = [
+      4,   5,   6,   7,   8,   9,  10,  10,
+     11,  12,  13,  14,  15,  16,  17,  17,
+     18,  19,  20,  20,  21,  21,  22,  22,
+     23,  23,  24,  25,  25,  26,  27,  28,
+     29,  30,  31,  32,  33,  34,  35,  36,
+     37,  37,  38,  39,  40,  41,  42,  43,
+     44,  45,  46,  46,  47,  48,  49,  50,
+     51,  52,  53,  54,  55,  56,  57,  58,
+     59,  60,  61,  62,  63,  64,  65,  66,
+     67,  68,  69,  70,  71,  72,  73,  74,
+     75,  76,  76,  77,  78,  79,  80,  81,
+     82,  83,  84,  85,  86,  87,  88,  89,
+     91,  93,  95,  96,  98, 100, 101, 102,
+    104, 106, 108, 110, 112, 114, 116, 118,
+    122, 124, 126, 128, 130, 132, 134, 136,
+    138, 140, 143, 145, 148, 151, 154, 157,
+];
+
+#[rustfmt::skip]
+const AC_QUANT: [i16; 128] = [
+      4,   5,   6,   7,   8,    9,  10,  11,
+      12,  13,  14,  15,  16,  17,  18,  19,
+      20,  21,  22,  23,  24,  25,  26,  27,
+      28,  29,  30,  31,  32,  33,  34,  35,
+      36,  37,  38,  39,  40,  41,  42,  43,
+      44,  45,  46,  47,  48,  49,  50,  51,
+      52,  53,  54,  55,  56,  57,  58,  60,
+      62,  64,  66,  68,  70,  72,  74,  76,
+      78,  80,  82,  84,  86,  88,  90,  92,
+      94,  96,  98, 100, 102, 104, 106, 108,
+     110, 112, 114, 116, 119, 122, 125, 128,
+     131, 134, 137, 140, 143, 146, 149, 152,
+     155, 158, 161, 164, 167, 170, 173, 177,
+     181, 185, 189, 193, 197, 201, 205, 209,
+     213, 217, 221, 225, 229, 234, 239, 245,
+     249, 254, 259, 264, 269, 274, 279, 284,
+];
+
+/// Raster position of each coefficient in coding order.
+const ZIGZAG: [u8; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba};
+
+    /// Smooth gradients with a sharp edge and some texture, in a size that does not fill
+    /// whole macroblocks.
+    fn sample() -> RgbImage {
+        RgbImage::from_fn(100, 70, |x, y| {
+            let edge = if x > 60 && y > 20 { 120 } else { 0 };
+            let texture = ((x * 7 + y * 13) % 17) as u8;
+            Rgb([(x * 2) as u8 + texture, (y * 3) as u8, 255 - edge - texture])
+        })
+    }
+
+    fn psnr(a: &RgbImage, b: &RgbImage) -> f64 {
+        let sse: f64 = a
+            .as_raw()
+            .iter()
+            .zip(b.as_raw())
+            .map(|(&x, &y)| (f64::from(x) - f64::from(y)).powi(2))
+            .sum();
+        10.0 * (255.0f64.powi(2) / (sse / a.as_raw().len() as f64)).log10()
+    }
+
+    fn decode(webp: &[u8]) -> DynamicImage {
+        image::load_from_memory_with_format(webp, ImageFormat::WebP).expect("Failed to decode")
+    }
+
+    #[test]
+    fn transforms_invert_like_the_decoder() {
+        // A simple LCG covers the whole residue range.
+        let mut seed = 12345u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as i32 % 511 - 255
+        };
+        for _ in 0..200 {
+            let input: [i32; 16] = std::array::from_fn(|_| next());
+            let mut block = fdct4x4(&input);
+            idct4x4(&mut block);
+            for (a, b) in input.iter().zip(&block) {
+                assert!((a - b).abs() <= 1, "{input:?} came back as {block:?}");
+            }
+
+            let dc = input.map(|v| v * 16);
+            let mut walsh = fwht4x4(&dc);
+            iwht4x4(&mut walsh);
+            for (a, b) in dc.iter().zip(&walsh) {
+                assert!((a - b).abs() <= 1, "{dc:?} came back as {walsh:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn lossy_round_trip() {
+        let img = DynamicImage::ImageRgb8(sample());
+        let options = WebpOptions::default();
+        let high = encode_lossy_webp(&img, 90, &options).unwrap();
+        let low = encode_lossy_webp(&img, 10, &options).unwrap();
+        assert_eq!(&high[12..16], b"VP8 ");
+        assert!(low.len() < high.len());
+
+        let decoded = decode(&high);
+        assert_eq!(decoded.color(), image::ColorType::Rgb8);
+        assert_eq!((decoded.width(), decoded.height()), (100, 70));
+        assert!(psnr(&decoded.to_rgb8(), &sample()) > 32.0);
+        assert!(psnr(&decode(&low).to_rgb8(), &sample()) > 22.0);
+
+        // The fastest method only predicts DC, which still decodes.
+        let fast = WebpOptions { method: 0, ..WebpOptions::default() };
+        let decoded = decode(&encode_lossy_webp(&img, 90, &fast).unwrap());
+        assert!(psnr(&decoded.to_rgb8(), &sample()) > 30.0);
+    }
+
+    #[cfg(feature = "webp-lossy")]
+    #[test]
+    fn libwebp_decodes_the_same() {
+        let rgba = RgbaImage::from_fn(100, 70, |x, y| {
+            let Rgb([r, g, b]) = *sample().get_pixel(x, y);
+            Rgba([r, g, b, (y * 3) as u8])
+        });
+        let webp =
+            encode_lossy_webp(&DynamicImage::ImageRgba8(rgba), 90, &WebpOptions::default())
+                .unwrap();
+        let decoded = webp::Decoder::new(&webp).decode().expect("libwebp rejected the file");
+        let ours = decode(&webp).to_rgba8();
+        assert_eq!((decoded.width(), decoded.height()), (100, 70));
+        // Both upsample chroma the same way, so only rounding may differ.
+        assert!(decoded.iter().zip(ours.as_raw()).all(|(&a, &b)| a.abs_diff(b) <= 1));
+    }
+
+    #[test]
+    fn flat_and_tiny_images() {
+        // Flat macroblocks skip all their coefficients.
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, Rgb([200, 40, 90])));
+        let decoded = decode(&encode_lossy_webp(&flat, 75, &WebpOptions::default()).unwrap());
+        for p in decoded.to_rgb8().pixels() {
+            assert!(p.0.iter().zip([200, 40, 90]).all(|(&a, b)| a.abs_diff(b) <= 3), "{p:?}");
+        }
+
+        let dot = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([10, 20, 30])));
+        let decoded = decode(&encode_lossy_webp(&dot, 75, &WebpOptions::default()).unwrap());
+        assert_eq!((decoded.width(), decoded.height()), (1, 1));
+
+        let huge = DynamicImage::ImageLuma8(image::GrayImage::new(MAX_DIMENSION + 1, 1));
+        assert!(encode_lossy_webp(&huge, 75, &WebpOptions::default()).is_err());
+    }
+
+    #[test]
+    fn alpha_goes_into_an_alph_chunk() {
+        let rgba = RgbaImage::from_fn(40, 30, |x, y| {
+            let Rgb([r, g, b]) = *sample().get_pixel(x, y);
+            Rgba([r, g, b, (x * 6) as u8])
+        });
+        let img = DynamicImage::ImageRgba8(rgba.clone());
+        let webp = encode_lossy_webp(&img, 80, &WebpOptions::default()).unwrap();
+        assert_eq!(&webp[12..16], b"VP8X");
+        assert!(webp.windows(4).any(|w| w == b"ALPH"));
+
+        // Alpha is lossless at the default alpha quality of 100.
+        let decoded = decode(&webp).to_rgba8();
+        assert!(decoded.pixels().zip(rgba.pixels()).all(|(a, b)| a[3] == b[3]));
+
+        // At 0 only two levels are left.
+        let coarse = WebpOptions { alpha_quality: 0, ..WebpOptions::default() };
+        let decoded = decode(&encode_lossy_webp(&img, 80, &coarse).unwrap()).to_rgba8();
+        assert!(decoded.pixels().all(|p| p[3] == 0 || p[3] == 255));
+    }
+}
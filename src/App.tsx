@@ -1,6 +1,6 @@
 import React, {useState, useRef, useEffect} from 'react';
 import './App.css';
-import {convert_image} from '../public/wasm/native';
+import {convert_image, ConvertError} from '../public/wasm/native';
 import init from '../public/wasm/native.js';
 import {SUPPORTED_FORMATS, type SupportedFormat, normalizeFormat} from './formats';
 
@@ -160,7 +160,12 @@ function App({initialTargetFormat}: AppProps) {
 
         } catch (err) {
             console.error('Conversion error:', err);
-            setError(err instanceof Error ? err.message : 'Failed to convert image');
+            if (err instanceof ConvertError) {
+                // err.code / err.stage are stable identifiers if we ever need to branch on them
+                setError(err.message);
+            } else {
+                setError(err instanceof Error ? err.message : 'Failed to convert image');
+            }
         } finally {
             // dummy await 500ms to show converting state
             await new Promise(res => setTimeout(res, 500));